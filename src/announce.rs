@@ -0,0 +1,35 @@
+/// Keeping ourselves listed as a peer for an infohash.
+
+use mio::Timeout;
+
+use messages::NodeId;
+
+/// Identifies an `Announce` in the server.
+pub type AnnounceId = usize;
+
+/// A standing announce, renewed every `Config::reannounce_interval_ms` until cancelled.
+///
+/// Each round is a `get_peers` lookup for the infohash followed by an `announce_peer` to
+/// every close node that handed us a token. Other nodes forget announces after half an hour,
+/// so a long-lived seeder has to keep doing this.
+pub struct Announce {
+    pub info_hash: NodeId,
+    /// Port our peer (not DHT) traffic is on.
+    pub port: u16,
+    /// We have the whole torrent: announce with the `seed` flag, and only look for
+    /// peers that don't.
+    pub seed: bool,
+    /// The timer for the next round, once this one's lookup has finished.
+    pub next: Option<Timeout>,
+}
+
+impl Announce {
+    pub fn new(info_hash: NodeId, port: u16, seed: bool) -> Self {
+        Announce {
+            info_hash: info_hash,
+            port: port,
+            seed: seed,
+            next: None,
+        }
+    }
+}
@@ -0,0 +1,99 @@
+/// Addresses we refuse to deal with for the rest of the session.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Most bans we hold; the oldest is lifted to make room for a new one.
+const MAX_BANS: usize = 256;
+
+/// Most sources we count strikes against at once, so a spoofing flood can't grow the map.
+const MAX_TRACKED: usize = 1024;
+
+/// Banned IPv4 subnets, plus strike counts toward banning misbehaving sources.
+pub struct Blacklist {
+    /// Banned subnets as network and prefix length, oldest first.
+    bans: VecDeque<(Ipv4Addr, u8)>,
+    /// Protocol errors from each source IP not (yet) banned.
+    strikes: HashMap<Ipv4Addr, u32>,
+    /// Strikes that earn a source a ban; zero never bans.
+    max_strikes: u32,
+}
+
+impl Blacklist {
+    pub fn new(max_strikes: u32) -> Self {
+        Blacklist {
+            bans: VecDeque::new(),
+            strikes: HashMap::new(),
+            max_strikes: max_strikes,
+        }
+    }
+
+    /// Bans every address sharing `prefix_len` leading bits with `ip`.
+    ///
+    /// Returns false if that subnet was already banned.
+    pub fn ban(&mut self, ip: Ipv4Addr, prefix_len: u8) -> bool {
+        let subnet = (network(ip, prefix_len), prefix_len);
+        if self.bans.iter().any(|banned| banned == &subnet) {
+            return false
+        }
+        if self.bans.len() == MAX_BANS {
+            self.bans.pop_front();
+        }
+        self.bans.push_back(subnet);
+        true
+    }
+
+    /// Whether `ip` falls in a banned subnet. IPv6 is never banned.
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        match *ip {
+            IpAddr::V4(ip) => self.bans.iter().any(|&(net, len)| network(ip, len) == net),
+            IpAddr::V6(_) => false,
+        }
+    }
+
+    /// Counts a protocol error against `ip`. Returns true once it has earned a ban.
+    pub fn strike(&mut self, ip: &IpAddr) -> bool {
+        let ip = match *ip {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => return false,
+        };
+        if self.max_strikes == 0 {
+            return false
+        }
+        if !self.strikes.contains_key(&ip) && self.strikes.len() >= MAX_TRACKED {
+            // forget everyone rather than pick favourites; persistent offenders soon return
+            self.strikes.clear();
+        }
+        let earned = {
+            let count = self.strikes.entry(ip).or_insert(0);
+            *count += 1;
+            *count >= self.max_strikes
+        };
+        if earned {
+            self.strikes.remove(&ip);
+        }
+        earned
+    }
+
+    pub fn len(&self) -> usize {
+        self.bans.len()
+    }
+}
+
+/// Parses `IP` or `IP/BITS`; a bare IP bans just that address.
+pub fn parse_subnet(s: &str) -> Option<(Ipv4Addr, u8)> {
+    let (ip, len) = match s.find('/') {
+        Some(i) => (&s[..i], s[i + 1..].parse().ok()),
+        None => (s, Some(32)),
+    };
+    match (ip.parse(), len) {
+        (Ok(ip), Some(len)) if len <= 32 => Some((ip, len)),
+        _ => None,
+    }
+}
+
+/// `ip` with all but its first `prefix_len` bits cleared.
+fn network(ip: Ipv4Addr, prefix_len: u8) -> Ipv4Addr {
+    let mask = if prefix_len == 0 { 0 } else { !0u32 << (32 - prefix_len) };
+    Ipv4Addr::from(u32::from(ip) & mask)
+}
@@ -0,0 +1,355 @@
+/// Settings for running a node.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use lookup;
+use messages::{self, NodeId, Query};
+use table::{self, Eviction, Split};
+
+/// Everything a `ServerHandler` needs to know up front.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Local address for our UDP socket.
+    pub bind: SocketAddr,
+    /// Our node ID; `None` picks a random one each run. A fixed one, with a fixed `bind`,
+    /// makes a set of nodes started together behave the same every time.
+    pub node_id: Option<NodeId>,
+    /// Source IP for everything we send, overriding `bind`'s IP but keeping its port.
+    ///
+    /// A socket bound to 0.0.0.0 sends from whichever interface the OS routes through, which
+    /// on a host with several public IPs may not be the one other nodes know us by.
+    pub source: Option<IpAddr>,
+    /// Listen on whatever free port the OS picks instead of `bind`'s, a different one each
+    /// run, so we're harder to recognize across restarts.
+    pub random_port: bool,
+    /// `SO_RCVBUF` to ask for on our socket; `None` leaves the OS default. Busy nodes may
+    /// need more to avoid dropping datagrams in bursts.
+    pub recv_buffer: Option<usize>,
+    /// `SO_SNDBUF` to ask for on our socket; `None` leaves the OS default.
+    pub send_buffer: Option<usize>,
+    /// Largest datagram we'll send in reply to a query. Replies that would be bigger carry
+    /// fewer nodes (then peers) until they fit, so they don't risk IP fragmentation. What we
+    /// receive isn't held to it: any datagram up to 64KiB is read whole.
+    pub max_datagram: usize,
+    /// `host:port` names of well-known nodes to bootstrap from.
+    pub bootstrap: Vec<String>,
+    /// Contacts saved from an earlier run. If any are given we ping them first and only fall
+    /// back to `bootstrap` if fewer than `warm_start_min` answer.
+    pub contacts: Vec<SocketAddr>,
+    /// Good contacts a warm start needs to skip DNS bootstrap.
+    pub warm_start_min: usize,
+    /// If set, look up the nodes closest to this ID, print them, and exit.
+    pub find: Option<NodeId>,
+    /// Infohashes to keep announcing ourselves for, each with the port our peer is on and
+    /// whether we're seeding it.
+    pub announce: Vec<(NodeId, u16, bool)>,
+    /// How often a standing announce is renewed; must stay under other nodes' peer TTL.
+    pub reannounce_interval_ms: u64,
+    /// How long a lookup may run before it settles for the nodes found so far.
+    pub lookup_timeout_ms: u64,
+    /// How long a lookup waits on one query before also trying the next candidate.
+    pub lookup_stall_ms: u64,
+    /// Queries in flight at once for the self-lookup that fills our table at startup.
+    pub bootstrap_alpha: usize,
+    /// Queries in flight at once for `get_peers` lookups, our own or for an announce.
+    pub get_peers_alpha: usize,
+    /// Queries in flight at once for other `find_node` lookups: bucket refreshes and `--find`.
+    pub find_node_alpha: usize,
+    /// How many times a lookup may find a new closest node before it stops where it is.
+    pub max_lookup_iterations: usize,
+    /// How many queries a lookup may send in all, answered or not, before it settles for
+    /// what it has.
+    pub max_lookup_queries: usize,
+    /// Where to keep the peers announced to us across restarts: loaded at startup if it
+    /// exists, and rewritten every refresh tick.
+    pub peer_store_file: Option<String>,
+    /// Where to write our good nodes every refresh tick, one `ID ADDR:PORT` line each, for
+    /// `--contacts` to start from next time or on another machine.
+    pub contacts_file: Option<String>,
+    /// How many infohashes' worth of peers from our own `get_peers` lookups to remember;
+    /// zero disables the cache.
+    pub peer_cache_size: usize,
+    /// How long cached lookup peers are handed out before we ask the network again.
+    pub peer_cache_ttl_ms: u64,
+    /// Interval between refresh ticks; each tick refreshes at most one stale bucket.
+    pub refresh_tick_ms: u64,
+    /// Interval between lookups for our own ID, which keep our closest neighbours current
+    /// whatever the buckets' refresh state; zero disables them.
+    pub self_refresh_ms: u64,
+    /// Interval between one-line table summaries in the log (see `stats::Summary`); zero
+    /// disables them.
+    pub summary_interval_ms: u64,
+    /// Identifier of a private DHT. When set, every message we send carries it and any
+    /// message without it is dropped, so we never merge with mainline. `None` is mainline.
+    pub network: Option<Vec<u8>>,
+    /// Emit line-delimited JSON lifecycle events on stdout (see `events`).
+    pub json_events: bool,
+    /// Print the infohashes other nodes `get_peers` or announce to us (see `observe`), at most
+    /// this many a second; `None` doesn't watch.
+    pub observe_queries: Option<usize>,
+    /// Loopback address to take `control::Command`s on, if any.
+    pub control: Option<SocketAddr>,
+    /// Log every query sent, message received and transaction timed out. Off by default, as
+    /// it's one line per packet; failures are logged either way.
+    pub trace_txs: bool,
+    /// What to do with nodes we learn of secondhand, from `find_node` and the like.
+    pub ping_policy: PingPolicy,
+    /// Most pings to secondhand nodes in flight at once; the rest wait their turn, so a burst
+    /// of `find_node` replies doesn't become a burst of pings.
+    pub max_discovery_pings: usize,
+    /// Whether a node that queries us gets a discovery ping, and a place in our table if it
+    /// answers. Its query shows it's alive, but not that its ID is really its own.
+    pub learn_from_queriers: bool,
+    /// How many answered transactions to remember, so a repeated reply is logged as a
+    /// duplicate rather than as a reply to a transaction we never sent.
+    pub recent_txs: usize,
+    /// Most announce rounds whose `get_peers` lookups run at once; the rest queue, so
+    /// announcing many infohashes doesn't flood the network or our transaction table.
+    pub max_concurrent_announces: usize,
+    /// Let a full home bucket evict its worst node for a newcomer (see `Table::set_home_eviction`).
+    pub home_eviction: bool,
+    /// Which node that eviction picks (see `table::Eviction`).
+    pub eviction: Eviction,
+    /// When the deepest bucket splits (see `table::Split`).
+    pub split: Split,
+    /// Deepest the routing table may grow (see `Table::set_max_buckets`); fewer buckets use
+    /// less memory but know our neighbourhood less well.
+    pub max_buckets: usize,
+    /// Unanswered queries in a row after which a node is bad and may be evicted (see
+    /// `Table::set_bad_after`).
+    pub bad_after_failures: u32,
+    /// Evict nodes whose IDs break BEP 42 for their address before any others; see
+    /// `Table::set_prefer_bep42`.
+    pub prefer_bep42: bool,
+    /// Infohashes we won't serve: `get_peers` and `announce_peer` for them get error 201.
+    pub refused: Vec<NodeId>,
+    /// What to do with queries that come without a valid sender ID.
+    pub idless_policy: IdlessPolicy,
+    /// Whether a node that answers `ServerHandler::reverify` from a new address has moved.
+    pub address_changes: AddressChangePolicy,
+    /// Fraction of incoming datagrams to drop on purpose, for testing how we cope with a
+    /// lossy network; see `impair`.
+    pub impair_loss: f64,
+    /// Delay to add to every incoming datagram, likewise.
+    pub impair_latency_ms: u64,
+    /// Seeds the choice of datagrams to drop, so a test run can be repeated.
+    pub impair_seed: u32,
+    /// Most nodes we take from each of a reply's `nodes` and `nodes6`; the rest are ignored
+    /// without being decoded.
+    pub max_reply_nodes: usize,
+    /// Accept non-global addresses in replies' node and peer lists, for a private DHT on a
+    /// LAN; mainline nodes should never hand them out.
+    pub accept_private_addrs: bool,
+    /// Good nodes we need before answering `find_node` or `get_peers`; until then they get
+    /// error 202, rather than the empty node list a fresh table would give. Zero serves at once.
+    pub serve_min_good: usize,
+    /// How much of the DHT we serve to others.
+    pub serve_mode: ServeMode,
+    /// Whether a node we queried by ID may answer under a different one.
+    pub id_changes: IdChangePolicy,
+    /// Tell queriers their address as we see it, in the BEP 42 `ip` key of our replies.
+    pub echo_ip: bool,
+    /// Subnets (network and prefix length) whose packets and nodes we ignore from the start.
+    pub bans: Vec<(Ipv4Addr, u8)>,
+    /// File to append each new ban to, so a later run can load them back with `--ban-file`.
+    pub ban_file: Option<String>,
+    /// Undecodable messages from one IP before we ban it; zero never bans automatically.
+    pub max_strikes: u32,
+}
+
+/// How eagerly we verify nodes other nodes tell us about.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PingPolicy {
+    /// File every new node and ping it straight away.
+    Eager,
+    /// File new nodes unverified, without pinging; they become good once they answer us.
+    /// Saves a burst of pings during bootstrap, at the cost of a staler table.
+    Lazy,
+    /// Ping a new node only if its bucket already has room, so hearsay never spills buckets.
+    BucketNotFull,
+}
+
+impl PingPolicy {
+    /// Parses the `--ping-policy` spelling.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "eager" => Some(PingPolicy::Eager),
+            "lazy" => Some(PingPolicy::Lazy),
+            "bucket-not-full" => Some(PingPolicy::BucketNotFull),
+            _ => None,
+        }
+    }
+}
+
+/// How we treat a query whose sender ID is missing or malformed.
+///
+/// Either way the sender never enters our table; there's no ID to file it under.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IdlessPolicy {
+    /// Refuse with error 203, as BEP 5 has it for malformed packets.
+    Reject,
+    /// Answer anyway. Lenient towards buggy clients.
+    Serve,
+}
+
+impl IdlessPolicy {
+    /// Parses the `--idless-queries` spelling.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "reject" => Some(IdlessPolicy::Reject),
+            "serve" => Some(IdlessPolicy::Serve),
+            _ => None,
+        }
+    }
+}
+
+/// What to make of a known node answering from an address other than the one we have.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressChangePolicy {
+    /// It moved: file the new address.
+    Accept,
+    /// Someone may be impersonating it: keep the old address.
+    Reject,
+}
+
+impl AddressChangePolicy {
+    /// Parses the `--address-changes` spelling.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "accept" => Some(AddressChangePolicy::Accept),
+            "reject" => Some(AddressChangePolicy::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// How much we serve other nodes, for deployments that only use the DHT to find peers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ServeMode {
+    /// Every query we implement.
+    Full,
+    /// Enough to stay in others' tables: `ping` and `find_node`. `get_peers` gets closer
+    /// nodes but no peers or token; announces and BEP 44 queries are refused.
+    FindOnly,
+    /// Only `ping`; everything else is refused.
+    ClientOnly,
+}
+
+impl ServeMode {
+    /// Parses the `--serve` spelling.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "full" => Some(ServeMode::Full),
+            "find-only" => Some(ServeMode::FindOnly),
+            "client-only" => Some(ServeMode::ClientOnly),
+            _ => None,
+        }
+    }
+
+    /// Whether we answer `query` at all in this mode. Unknown methods are left to be
+    /// refused as such.
+    pub fn serves(&self, query: &Query) -> bool {
+        match (*self, query) {
+            (ServeMode::Full, _) | (_, &Query::Ping) | (_, &Query::Unknown(_)) => true,
+            (ServeMode::FindOnly, &Query::FindNode(_)) |
+            (ServeMode::FindOnly, &Query::GetPeers {..}) => true,
+            _ => false,
+        }
+    }
+}
+
+/// What to make of a reply under a different ID from the node we meant to query.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IdChangePolicy {
+    /// Ignore the reply, leaving the query to time out: someone may be spoofing the node.
+    Reject,
+    /// The node changed its ID, say on restart: take the reply and file it under the new one.
+    Accept,
+}
+
+impl IdChangePolicy {
+    /// Parses the `--id-changes` spelling.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "reject" => Some(IdChangePolicy::Reject),
+            "accept" => Some(IdChangePolicy::Accept),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    /// The address to bind our socket to, taking `source` and `random_port` into account.
+    pub fn local_addr(&self) -> SocketAddr {
+        let port = if self.random_port { 0 } else { self.bind.port() };
+        match self.source {
+            Some(ip) => SocketAddr::new(ip, port),
+            None => SocketAddr::new(self.bind.ip(), port),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "0.0.0.0:6881".parse().unwrap(),
+            node_id: None,
+            source: None,
+            random_port: false,
+            recv_buffer: None,
+            send_buffer: None,
+            max_datagram: 1400,
+            bootstrap: vec!["dht.transmissionbt.com:6881".to_string()],
+            contacts: vec![],
+            warm_start_min: 8,
+            find: None,
+            announce: vec![],
+            reannounce_interval_ms: 15 * 60 * 1000,
+            lookup_timeout_ms: 30_000,
+            lookup_stall_ms: 1_500,
+            bootstrap_alpha: lookup::ALPHA,
+            get_peers_alpha: lookup::ALPHA,
+            find_node_alpha: lookup::ALPHA,
+            max_lookup_iterations: 20,
+            max_lookup_queries: 100,
+            peer_store_file: None,
+            contacts_file: None,
+            peer_cache_size: 64,
+            peer_cache_ttl_ms: 5 * 60 * 1000,
+            refresh_tick_ms: 60_000,
+            self_refresh_ms: 15 * 60 * 1000,
+            summary_interval_ms: 0,
+            network: None,
+            json_events: false,
+            observe_queries: None,
+            control: None,
+            trace_txs: false,
+            ping_policy: PingPolicy::Eager,
+            max_discovery_pings: 32,
+            learn_from_queriers: true,
+            recent_txs: 256,
+            max_concurrent_announces: 8,
+            home_eviction: true,
+            eviction: Eviction::LeastReliable,
+            split: Split::Eager,
+            max_buckets: table::MAX_BUCKETS,
+            bad_after_failures: 1,
+            prefer_bep42: false,
+            refused: vec![],
+            idless_policy: IdlessPolicy::Reject,
+            address_changes: AddressChangePolicy::Accept,
+            id_changes: IdChangePolicy::Reject,
+            serve_mode: ServeMode::Full,
+            serve_min_good: 0,
+            max_reply_nodes: messages::MAX_REPLY_NODES,
+            accept_private_addrs: false,
+            impair_loss: 0.0,
+            impair_latency_ms: 0,
+            impair_seed: 1,
+            echo_ip: true,
+            bans: vec![],
+            ban_file: None,
+            max_strikes: 10,
+        }
+    }
+}
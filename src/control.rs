@@ -0,0 +1,167 @@
+/// Commands for a running node, sent as text datagrams to the `--control` socket.
+///
+/// Whoever can reach the socket can steer the node, so it only ever listens on loopback.
+
+use std::net::SocketAddr;
+
+use announce::AnnounceId;
+use messages::NodeId;
+
+/// Most bytes of reply text we send back in one datagram; longer replies are cut short.
+pub const MAX_REPLY_LEN: usize = 60_000;
+
+/// What `help`, or a command we don't know, gets back.
+pub const HELP: &'static str = "\
+commands:
+    status  stats and health
+    buckets each bucket's occupancy, farthest from our ID first
+    txs     our queries awaiting a reply
+    closest ID
+            the good nodes in our table closest to ID, without asking the network
+    reverify ID [ADDR:PORT]
+            ping a node in our table, where we have it or at ADDR:PORT, and report
+            whether it's still there
+    peers INFOHASH [refresh]
+            peers for INFOHASH, from the cache or else a get_peers lookup; with
+            refresh, look them up again even on a cache hit
+    announce INFOHASH:PORT
+            keep announcing a peer on PORT for INFOHASH, as --announce does
+    seed INFOHASH:PORT
+            the same, as a seed, as --seed does
+    announces
+            our standing announces, with the number to cancel each by
+    cancel N
+            stop renewing announce N";
+
+/// One datagram's worth of instruction, as parsed by `Command::parse`.
+///
+/// The reply to each is plain text sent back to wherever the command came from.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Command {
+    Help,
+    /// Reports `Stats` and `Health`.
+    Status,
+    /// Lists `Table::bucket_summaries`.
+    Buckets,
+    /// Lists every query of ours still awaiting a reply.
+    Txs,
+    /// Lists the nodes `ServerHandler::closest_cached` has for this target.
+    Closest(NodeId),
+    /// Has `ServerHandler::reverify` ping a node, at the address given or where our table
+    /// has it.
+    Reverify(NodeId, Option<SocketAddr>),
+    /// Has `ServerHandler::get_peers` find peers for the infohash, looking again even if
+    /// some are cached if the flag's set.
+    Peers(NodeId, bool),
+    /// Starts a standing announce for the infohash on the port, as a seed if the flag's set.
+    Announce(NodeId, u16, bool),
+    /// Lists our standing announces.
+    Announces,
+    /// Has `ServerHandler::cancel_announce` stop renewing this announce.
+    Cancel(AnnounceId),
+}
+
+impl Command {
+    /// Parses a command line such as `txs`; `Err` holds what to tell the sender.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return Err(HELP.to_string()),
+        };
+        let command = match name {
+            "help" => Command::Help,
+            "status" => Command::Status,
+            "buckets" => Command::Buckets,
+            "txs" => Command::Txs,
+            "closest" => Command::Closest(node_id(name, words.next())?),
+            "reverify" => {
+                let id = node_id(name, words.next())?;
+                let addr = match words.next() {
+                    Some(addr) => Some(addr.parse().map_err(|_| format!("{}: bad address {:?}", name, addr))?),
+                    None => None,
+                };
+                Command::Reverify(id, addr)
+            }
+            "peers" => {
+                let info_hash = node_id(name, words.next())?;
+                let refresh = match words.next() {
+                    Some("refresh") => true,
+                    Some(other) => return Err(format!("{}: unexpected {:?}", name, other)),
+                    None => false,
+                };
+                Command::Peers(info_hash, refresh)
+            }
+            "announce" | "seed" => {
+                let spec = words.next().ok_or(format!("{}: expected INFOHASH:PORT", name))?;
+                let (hex, port) = match spec.find(':') {
+                    Some(i) => (&spec[..i], &spec[i + 1..]),
+                    None => return Err(format!("{}: expected INFOHASH:PORT, got {:?}", name, spec)),
+                };
+                let info_hash = node_id(name, Some(hex))?;
+                let port = port.parse().map_err(|_| format!("{}: bad port {:?}", name, port))?;
+                Command::Announce(info_hash, port, name == "seed")
+            }
+            "announces" => Command::Announces,
+            "cancel" => {
+                let id = words.next().ok_or(format!("{}: expected an announce number", name))?;
+                Command::Cancel(id.parse().map_err(|_| format!("{}: bad announce number {:?}", name, id))?)
+            }
+            _ => return Err(format!("unknown command {:?}\n{}", name, HELP)),
+        };
+        if let Some(extra) = words.next() {
+            return Err(format!("{}: unexpected {:?}", name, extra))
+        }
+        Ok(command)
+    }
+}
+
+/// Reads the hex ID argument to `command`.
+fn node_id(command: &str, word: Option<&str>) -> Result<NodeId, String> {
+    let word = word.ok_or(format!("{}: expected a 40-digit hex ID", command))?;
+    NodeId::from_hex(word).map_err(|e| format!("{}: {}", command, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use messages::NodeId;
+    use super::Command;
+
+    const HEX: &'static str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn parses_commands_and_surrounding_whitespace() {
+        assert_eq!(Command::parse("txs"), Ok(Command::Txs));
+        assert_eq!(Command::parse("  txs\n"), Ok(Command::Txs));
+        assert_eq!(Command::parse("help"), Ok(Command::Help));
+        assert_eq!(Command::parse("status"), Ok(Command::Status));
+        assert_eq!(Command::parse("buckets"), Ok(Command::Buckets));
+        let id = NodeId::from_hex(HEX).unwrap();
+        assert_eq!(Command::parse(&format!("closest {}", HEX)), Ok(Command::Closest(id)));
+        assert_eq!(Command::parse(&format!("reverify {}", HEX)), Ok(Command::Reverify(id, None)));
+        assert_eq!(Command::parse(&format!("reverify {} 198.51.100.1:6881", HEX)),
+                   Ok(Command::Reverify(id, Some("198.51.100.1:6881".parse().unwrap()))));
+        assert_eq!(Command::parse(&format!("peers {}", HEX)), Ok(Command::Peers(id, false)));
+        assert_eq!(Command::parse(&format!("peers {} refresh", HEX)), Ok(Command::Peers(id, true)));
+        assert_eq!(Command::parse(&format!("announce {}:6881", HEX)), Ok(Command::Announce(id, 6881, false)));
+        assert_eq!(Command::parse(&format!("seed {}:6881", HEX)), Ok(Command::Announce(id, 6881, true)));
+        assert_eq!(Command::parse("announces"), Ok(Command::Announces));
+        assert_eq!(Command::parse("cancel 3"), Ok(Command::Cancel(3)));
+    }
+
+    #[test]
+    fn rejects_empty_unknown_and_overlong_commands() {
+        assert!(Command::parse("").is_err());
+        assert!(Command::parse("frobnicate").is_err());
+        assert!(Command::parse("txs now").is_err());
+        assert!(Command::parse("closest").is_err());
+        assert!(Command::parse("closest 0123").is_err());
+        assert!(Command::parse(&format!("closest {} {}", HEX, HEX)).is_err());
+        assert!(Command::parse(&format!("reverify {} nowhere", HEX)).is_err());
+        assert!(Command::parse(&format!("peers {} now", HEX)).is_err());
+        assert!(Command::parse(&format!("announce {}", HEX)).is_err());
+        assert!(Command::parse(&format!("announce {}:http", HEX)).is_err());
+        assert!(Command::parse("cancel").is_err());
+        assert!(Command::parse("cancel first").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+/// The ways a node's operations can fail.
+
+use std::error::Error as StdError;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+use std::result;
+use std::time::Duration;
+
+use messages::{DecodeError, NodeId};
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Everything `ServerHandler` can fail with, so callers can tell transport trouble
+/// apart from misbehaving peers.
+#[derive(Debug)]
+pub enum Error {
+    /// The socket, event loop or name resolver failed.
+    Transport(io::Error),
+    /// A message couldn't be decoded.
+    Decode(DecodeError),
+    /// A reply named a transaction we don't have (any more).
+    NoSuchTransaction,
+    /// A second reply from the same node to a transaction it answered this long ago.
+    DuplicateReply(Duration),
+    /// A reply came from somewhere other than the address we queried, given here.
+    WrongSource(SocketAddr),
+    /// A reply came from the right address but under an ID other than this one, which we
+    /// queried; see `Config::id_changes`.
+    WrongSender(NodeId),
+    /// A node has an address we can't talk to, i.e. not IPv4.
+    NotIpv4,
+    /// The socket wasn't ready to take a datagram.
+    WouldBlock,
+    /// The socket broke and hasn't been replaced yet.
+    SocketDown,
+    /// None of the bootstrap names resolved to an IPv4 address.
+    NoBootstrap,
+    /// The node asked about isn't in our routing table.
+    UnknownNode,
+    /// The table has no bucket with this index (yet).
+    NoSuchBucket(usize),
+    /// A lookup had no nodes to start from: our table is empty, say because bootstrap
+    /// failed. Bootstrapping again is the way out.
+    NoNodes,
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Transport(ref e) => e.description(),
+            Error::Decode(ref e) => e.description(),
+            Error::NoSuchTransaction => "unknown tx",
+            Error::DuplicateReply(_) => "duplicate reply",
+            Error::WrongSource(_) => "reply from the wrong address",
+            Error::WrongSender(_) => "reply under the wrong node ID",
+            Error::NotIpv4 => "not IPv4",
+            Error::WouldBlock => "socket not ready to send",
+            Error::SocketDown => "socket down, rebinding",
+            Error::NoBootstrap => "no IPv4 bootstrap addresses",
+            Error::UnknownNode => "node not in our table",
+            Error::NoSuchBucket(_) => "no such bucket",
+            Error::NoNodes => "no nodes available",
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Error::Transport(ref e) => Some(e),
+            Error::Decode(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::Transport(ref e) => write!(f, "{}", e),
+            Error::Decode(ref e) => write!(f, "{}", e),
+            Error::WrongSource(queried) => write!(f, "reply to a query sent to {}", queried),
+            Error::NoSuchBucket(index) => write!(f, "no bucket {}", index),
+            Error::DuplicateReply(after) => {
+                let ms = after.as_secs() * 1000 + (after.subsec_nanos() / 1_000_000) as u64;
+                write!(f, "duplicate reply, {}ms after the first", ms)
+            }
+            Error::WrongSender(ref queried) => write!(f, "reply to a query sent to {:?} under another ID", queried),
+            _ => f.write_str(self.description()),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Transport(error)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(error: DecodeError) -> Error {
+        Error::Decode(error)
+    }
+}
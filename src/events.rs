@@ -0,0 +1,151 @@
+/// Line-delimited JSON lifecycle events, for tools that watch a running node.
+///
+/// Each event is one JSON object on its own line, always with an `"event"` key naming it.
+/// Fields are only ever added, never renamed, so scripts can rely on them:
+///
+/// - `node_added`: `id`, `addr` — a node answered our ping and entered our table
+/// - `lookup_started`: `target`
+/// - `lookup_progress`: `target`, `queried`, `candidates`, `peers` — running totals, sent each
+///   time a node answers the lookup
+/// - `lookup_finished`: `target`, `outcome` (`converged`, `timed_out`, `iteration_limit` or
+///   `query_budget`), `nodes` (array of `{id, addr}`, closest first)
+/// - `peers_found`: `target`, `peers` (array of `"ip:port"` strings) — sent after
+///   `lookup_finished` for a `get_peers` lookup, including an announce's
+/// - `error`: `addr`, `message` — a peer sent us something we couldn't handle
+
+use std::net::SocketAddr;
+
+use lookup::{Outcome, Progress};
+use messages::{Node4Info, NodeId, Peer4Info};
+
+pub enum Event<'a> {
+    NodeAdded {id: &'a NodeId, addr: &'a SocketAddr},
+    LookupStarted {target: &'a NodeId},
+    LookupProgress {target: &'a NodeId, progress: Progress},
+    LookupFinished {target: &'a NodeId, nodes: &'a [Node4Info], outcome: Outcome},
+    PeersFound {target: &'a NodeId, peers: &'a [Peer4Info]},
+    Error {addr: &'a SocketAddr, message: &'a str},
+}
+
+impl<'a> Event<'a> {
+    /// Serializes this event as a single line of JSON (without the newline).
+    pub fn to_json(&self) -> String {
+        match *self {
+            Event::NodeAdded {id, addr} => {
+                let mut obj = Object::new("node_added");
+                obj.string("id", &id.to_hex());
+                obj.string("addr", &addr.to_string());
+                obj.finish()
+            }
+            Event::LookupStarted {target} => {
+                let mut obj = Object::new("lookup_started");
+                obj.string("target", &target.to_hex());
+                obj.finish()
+            }
+            Event::LookupProgress {target, progress} => {
+                let mut obj = Object::new("lookup_progress");
+                obj.string("target", &target.to_hex());
+                obj.raw("queried", &progress.queried.to_string());
+                obj.raw("candidates", &progress.candidates.to_string());
+                obj.raw("peers", &progress.peers.to_string());
+                obj.finish()
+            }
+            Event::LookupFinished {target, nodes, outcome} => {
+                let mut list = String::from("[");
+                for (i, node) in nodes.iter().enumerate() {
+                    if i > 0 {
+                        list.push(',');
+                    }
+                    let mut obj = Object(String::from("{"));
+                    obj.string("id", &node.id.to_hex());
+                    obj.string("addr", &node.peer.socket_addr().to_string());
+                    list.push_str(&obj.finish());
+                }
+                list.push(']');
+
+                let mut obj = Object::new("lookup_finished");
+                obj.string("target", &target.to_hex());
+                obj.string("outcome", match outcome {
+                    Outcome::Converged => "converged",
+                    Outcome::TimedOut => "timed_out",
+                    Outcome::IterationLimit => "iteration_limit",
+                    Outcome::QueryBudget => "query_budget",
+                });
+                obj.raw("nodes", &list);
+                obj.finish()
+            }
+            Event::PeersFound {target, peers} => {
+                let mut list = String::from("[");
+                for (i, peer) in peers.iter().enumerate() {
+                    if i > 0 {
+                        list.push(',');
+                    }
+                    push_escaped(&mut list, &peer.socket_addr().to_string());
+                }
+                list.push(']');
+
+                let mut obj = Object::new("peers_found");
+                obj.string("target", &target.to_hex());
+                obj.raw("peers", &list);
+                obj.finish()
+            }
+            Event::Error {addr, message} => {
+                let mut obj = Object::new("error");
+                obj.string("addr", &addr.to_string());
+                obj.string("message", message);
+                obj.finish()
+            }
+        }
+    }
+}
+
+/// Just enough of a JSON object writer for our flat events.
+struct Object(String);
+
+impl Object {
+    fn new(event: &str) -> Self {
+        let mut obj = Object(String::from("{"));
+        obj.string("event", event);
+        obj
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        push_escaped(&mut self.0, key);
+        self.0.push(':');
+    }
+
+    fn string(&mut self, key: &str, value: &str) {
+        self.key(key);
+        push_escaped(&mut self.0, value);
+    }
+
+    /// Inserts `json` verbatim; it must already be valid JSON.
+    fn raw(&mut self, key: &str, json: &str) {
+        self.key(key);
+        self.0.push_str(json);
+    }
+
+    fn finish(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+fn push_escaped(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -0,0 +1,72 @@
+/// A single verdict on whether our routing table is fit to use.
+
+use std::time::Duration;
+
+use table::K;
+
+/// Fewer good nodes than this and lookups have too little to start from.
+const MIN_GOOD: usize = K;
+
+/// Fewest occupied buckets for a table that covers the keyspace at all evenly.
+const MIN_BUCKETS: usize = 3;
+
+/// Home bucket population below which our own neighbourhood is poorly known.
+const MIN_HOME: usize = K / 2;
+
+/// Silence after which we suspect our traffic isn't getting through.
+const QUIET_SECS: u64 = 60;
+
+/// Silence after which we assume it isn't.
+const DEAD_SECS: u64 = 5 * 60;
+
+/// How well the node is doing, coarsely.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    /// Well connected; lookups should work.
+    Good,
+    /// Working, but thinly: lookups may be slow or miss nodes.
+    Degraded,
+    /// No good nodes, or nobody has answered us in a long while.
+    Unusable,
+}
+
+/// A snapshot from `ServerHandler::health`: the figures, and what they add up to.
+#[derive(Clone, Debug)]
+pub struct Health {
+    /// Buckets holding at least one node.
+    pub occupied_buckets: usize,
+    /// Nodes in the bucket covering our own ID.
+    pub home_nodes: usize,
+    pub good_nodes: usize,
+    /// Good nodes plus those we haven't verified yet.
+    pub total_nodes: usize,
+    /// Time since a reply to one of our queries last arrived; `None` if none ever has.
+    pub since_last_response: Option<Duration>,
+    pub verdict: Verdict,
+}
+
+impl Health {
+    /// Sums up the figures with thresholds that suit a mainline-sized network.
+    pub fn assess(occupied_buckets: usize, home_nodes: usize, good_nodes: usize, total_nodes: usize,
+                  since_last_response: Option<Duration>) -> Self
+    {
+        let quiet = since_last_response.map_or(true, |d| d >= Duration::from_secs(QUIET_SECS));
+        let dead = since_last_response.map_or(true, |d| d >= Duration::from_secs(DEAD_SECS));
+        let verdict = if good_nodes == 0 || dead {
+            Verdict::Unusable
+        } else if good_nodes < MIN_GOOD || occupied_buckets < MIN_BUCKETS || home_nodes < MIN_HOME ||
+                  good_nodes * 2 < total_nodes || quiet {
+            Verdict::Degraded
+        } else {
+            Verdict::Good
+        };
+        Health {
+            occupied_buckets: occupied_buckets,
+            home_nodes: home_nodes,
+            good_nodes: good_nodes,
+            total_nodes: total_nodes,
+            since_last_response: since_last_response,
+            verdict: verdict,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+/// Making the network deliberately worse, to see how lookups and timeouts cope.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+/// Drops and delays incoming datagrams before we process them.
+///
+/// Runs are reproducible: the same seed drops the same datagrams of the same traffic.
+pub struct Impairment {
+    /// Fraction of datagrams dropped, from 0 to 1.
+    loss: f64,
+    /// Added to every datagram that isn't dropped.
+    latency_ms: u64,
+    rng: XorShiftRng,
+    /// Datagrams waiting out their latency, by `Timer::Deliver` sequence number.
+    held: HashMap<u64, (Vec<u8>, SocketAddr)>,
+    next_seq: u64,
+}
+
+impl Impairment {
+    pub fn new(loss: f64, latency_ms: u64, seed: u32) -> Self {
+        Impairment {
+            loss: loss,
+            latency_ms: latency_ms,
+            // XorShift needs some nonzero seed word
+            rng: XorShiftRng::from_seed([seed, 0x193a_6754, 0xa8a7_d469, 0x9783_0e05]),
+            held: HashMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    pub fn latency_ms(&self) -> u64 {
+        self.latency_ms
+    }
+
+    /// Whether to drop the next datagram.
+    pub fn drops(&mut self) -> bool {
+        self.loss > 0.0 && self.rng.gen::<f64>() < self.loss
+    }
+
+    /// Keeps a datagram until `release`, returning the sequence number to release it by.
+    pub fn hold(&mut self, bytes: &[u8], addr: SocketAddr) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.held.insert(seq, (bytes.to_vec(), addr));
+        seq
+    }
+
+    pub fn release(&mut self, seq: u64) -> Option<(Vec<u8>, SocketAddr)> {
+        self.held.remove(&seq)
+    }
+}
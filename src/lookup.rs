@@ -0,0 +1,476 @@
+/// Iterative search for the nodes closest to a target ID.
+
+use std::cmp;
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+
+use mio::Timeout;
+
+use messages::{Node4Info, NodeId, Peer4Info};
+use table::{Distance, K};
+
+/// Number of queries a lookup keeps in flight at once, unless told otherwise.
+pub const ALPHA: usize = 3;
+
+/// Most distinct peers one lookup collects; nodes that answer with more, honestly or not,
+/// can't make it grow without bound.
+const MAX_PEERS: usize = 1024;
+
+/// Identifies a running `Lookup` in the server.
+pub type LookupId = usize;
+
+/// How a lookup came to an end.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// The closest `K` live nodes have all replied.
+    Converged,
+    /// The deadline passed first; results are partial.
+    TimedOut,
+    /// The lookup kept getting closer for too many iterations; results are partial.
+    IterationLimit,
+    /// The lookup sent as many queries as it may; results are partial.
+    QueryBudget,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Outcome::Converged => "converged",
+            Outcome::TimedOut => "timed out",
+            Outcome::IterationLimit => "hit the iteration limit",
+            Outcome::QueryBudget => "used up its query budget",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CandidateState {
+    Unqueried,
+    Querying,
+    /// Queried but slow to answer; no longer counts against `alpha`.
+    Stalled,
+    Responded,
+    Failed,
+}
+
+/// What a finished `get_peers` lookup turned up.
+pub struct LookupResult {
+    /// Every peer any node told us about, without duplicates.
+    pub peers: Vec<Peer4Info>,
+    /// The closest nodes that answered with an announce token, and their tokens;
+    /// exactly who to announce to next.
+    pub closest_with_tokens: Vec<(Node4Info, Vec<u8>)>,
+}
+
+/// How far a running lookup has got, for anyone watching it.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    /// Queries sent so far, to seeds and candidates alike.
+    pub queried: usize,
+    /// Nodes heard of so far, queried or not.
+    pub candidates: usize,
+    /// Distinct peers found so far.
+    pub peers: usize,
+}
+
+struct Candidate {
+    node: Node4Info,
+    distance: Distance,
+    state: CandidateState,
+    /// Announce token from its `get_peers` reply, if it sent one.
+    token: Option<Vec<u8>>,
+}
+
+/// Tracks the shortlist of an iterative `find_node` lookup.
+///
+/// The server feeds in replies and timeouts; the lookup decides whom to query next,
+/// and when the closest `K` responsive nodes have all been heard from.
+pub struct Lookup {
+    target: NodeId,
+    /// Every candidate we've heard of, closest first.
+    shortlist: Vec<Candidate>,
+    /// Number of queries sent and not yet answered or timed out.
+    in_flight: usize,
+    /// Most queries to keep in flight at once.
+    alpha: usize,
+    /// Number of queries sent in all.
+    queried: usize,
+    /// Timer that cuts the lookup short.
+    deadline: Timeout,
+    /// Peers from `get_peers` replies.
+    peers: Vec<Peer4Info>,
+    /// Replies so far that brought us a new closest candidate.
+    iterations: usize,
+    /// Iterations after which we stop, however promising the next hop looks.
+    max_iterations: usize,
+    /// Queries after which we send no more, answered or not.
+    max_queries: usize,
+}
+
+impl Lookup {
+    /// A lookup for `target` keeping up to `alpha` queries in flight; 1 queries strictly serially.
+    /// It sends at most `max_queries` in all.
+    pub fn new(target: NodeId, deadline: Timeout, max_iterations: usize, alpha: usize,
+               max_queries: usize) -> Self {
+        Lookup {
+            target: target,
+            shortlist: Vec::new(),
+            in_flight: 0,
+            alpha: cmp::max(alpha, 1),
+            queried: 0,
+            deadline: deadline,
+            peers: Vec::new(),
+            iterations: 0,
+            max_iterations: max_iterations,
+            max_queries: max_queries,
+        }
+    }
+
+    pub fn target(&self) -> &NodeId {
+        &self.target
+    }
+
+    pub fn deadline(&self) -> Timeout {
+        self.deadline
+    }
+
+    pub fn iterations(&self) -> usize {
+        self.iterations
+    }
+
+    /// Notes a query sent to a node outside the shortlist, such as a bootstrap address.
+    pub fn sent(&mut self) {
+        self.in_flight += 1;
+        self.queried += 1;
+    }
+
+    /// Counts a query resent to a node already in flight against the budget. Returns false,
+    /// counting nothing, if the budget is spent.
+    pub fn retried(&mut self) -> bool {
+        if self.queried >= self.max_queries {
+            return false
+        }
+        self.queried += 1;
+        true
+    }
+
+    /// Adds `node` as a candidate to query, unless it's already on the shortlist; for seeds
+    /// that turn up after the lookup has started.
+    pub fn add(&mut self, node: Node4Info) {
+        if node.id != self.target && self.shortlist.iter().all(|c| c.node.id != node.id) {
+            self.insert(node, CandidateState::Unqueried);
+        }
+    }
+
+    /// Picks the closest unqueried candidate, if we have room for another query.
+    pub fn next_to_query(&mut self) -> Option<Node4Info> {
+        if self.in_flight >= self.alpha || self.queried >= self.max_queries {
+            return None
+        }
+        self.pending().map(|i| {
+            self.shortlist[i].state = CandidateState::Querying;
+            self.in_flight += 1;
+            self.queried += 1;
+            self.shortlist[i].node
+        })
+    }
+
+    /// Records a reply from `id` at `addr`, merging the nodes it sent into the shortlist.
+    ///
+    /// `token` is the announce token from a `get_peers` reply, kept for `result`.
+    pub fn responded(&mut self, id: &NodeId, addr: &SocketAddr, found: &[Node4Info],
+                     token: Option<Vec<u8>>)
+    {
+        let closest_before = self.shortlist.first().map(|c| c.node.id);
+        match self.position(addr) {
+            Some(i) => {
+                if self.shortlist[i].state == CandidateState::Querying {
+                    self.in_flight -= 1;
+                }
+                self.shortlist[i].state = CandidateState::Responded;
+                self.shortlist[i].token = token;
+            }
+            None => {
+                // a seed we queried before learning its ID
+                self.in_flight = self.in_flight.saturating_sub(1);
+                if let Some(node) = Node4Info::from_addr(id, addr) {
+                    let i = self.insert(node, CandidateState::Responded);
+                    self.shortlist[i].token = token;
+                }
+            }
+        }
+        for node in found {
+            if node.id != self.target && self.shortlist.iter().all(|c| c.node.id != node.id) {
+                self.insert(*node, CandidateState::Unqueried);
+            }
+        }
+        if self.shortlist.first().map(|c| c.node.id) != closest_before {
+            self.iterations += 1;
+        }
+    }
+
+    /// Collects peers from a `get_peers` reply, each once, up to `MAX_PEERS`.
+    pub fn found_peers(&mut self, values: &[Peer4Info]) {
+        for peer in values {
+            if self.peers.len() == MAX_PEERS {
+                break
+            }
+            if !self.peers.contains(peer) {
+                self.peers.push(*peer);
+            }
+        }
+    }
+
+    /// Records that the query sent to `addr` went unanswered.
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        match self.position(addr) {
+            Some(i) => {
+                if self.shortlist[i].state == CandidateState::Querying {
+                    self.in_flight -= 1;
+                }
+                self.shortlist[i].state = CandidateState::Failed;
+            }
+            None => self.in_flight = self.in_flight.saturating_sub(1),
+        }
+    }
+
+    /// Gives up waiting on a slow query to `addr` so another can take its place.
+    ///
+    /// A late reply is still welcome. Returns true if this freed up room to query someone else.
+    pub fn stalled(&mut self, addr: &SocketAddr) -> bool {
+        match self.position(addr) {
+            Some(i) if self.shortlist[i].state == CandidateState::Querying => {
+                self.shortlist[i].state = CandidateState::Stalled;
+                self.in_flight -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            queried: self.queried,
+            candidates: self.shortlist.len(),
+            peers: self.peers.len(),
+        }
+    }
+
+    /// True once the lookup has taken as many steps closer as it's allowed.
+    ///
+    /// A hostile network can keep feeding us ever-so-slightly closer fake nodes; this
+    /// stops us chasing them until the deadline.
+    pub fn exhausted(&self) -> bool {
+        self.iterations >= self.max_iterations
+    }
+
+    /// True once the query budget is spent and the last queries are answered or timed out.
+    ///
+    /// Unlike iterations, this counts every query, so a region of the network that never
+    /// answers can't keep a lookup sending until the deadline either.
+    pub fn out_of_budget(&self) -> bool {
+        self.queried >= self.max_queries && self.in_flight == 0
+    }
+
+    /// True once nothing is in flight and the closest `K` live candidates have all replied.
+    pub fn is_done(&self) -> bool {
+        self.in_flight == 0 && self.pending().is_none()
+    }
+
+    /// The closest nodes that answered us, nearest first.
+    pub fn closest(&self) -> Vec<Node4Info> {
+        self.shortlist.iter()
+            .filter(|c| c.state == CandidateState::Responded)
+            .take(K)
+            .map(|c| c.node)
+            .collect()
+    }
+
+    /// The peers found so far, and the closest responders' tokens.
+    pub fn result(&self) -> LookupResult {
+        LookupResult {
+            peers: self.peers.clone(),
+            closest_with_tokens: self.shortlist.iter()
+                .filter(|c| c.state == CandidateState::Responded)
+                .take(K)
+                .filter_map(|c| c.token.as_ref().map(|token| (c.node, token.clone())))
+                .collect(),
+        }
+    }
+
+    /// Index of the closest unqueried candidate among the `K` closest live ones.
+    fn pending(&self) -> Option<usize> {
+        let mut live = 0;
+        for (i, candidate) in self.shortlist.iter().enumerate() {
+            if live >= K {
+                break
+            }
+            match candidate.state {
+                CandidateState::Unqueried => return Some(i),
+                CandidateState::Failed => (),
+                CandidateState::Querying |
+                CandidateState::Stalled |
+                CandidateState::Responded => live += 1,
+            }
+        }
+        None
+    }
+
+    fn position(&self, addr: &SocketAddr) -> Option<usize> {
+        self.shortlist.iter().position(|c| &c.node.peer.socket_addr() == addr)
+    }
+
+    /// Adds a candidate in distance order, returning where it went.
+    fn insert(&mut self, node: Node4Info, state: CandidateState) -> usize {
+        let distance = Distance::between(&self.target, &node.id);
+        // equidistant means the same ID; order those by address so results are reproducible
+        let i = self.shortlist.iter()
+                              .position(|c| (c.distance, c.node.peer) > (distance, node.peer))
+                              .unwrap_or(self.shortlist.len());
+        self.shortlist.insert(i, Candidate {
+            node: node,
+            distance: distance,
+            state: state,
+            token: None,
+        });
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use mio::{EventLoop, Handler, Timeout};
+
+    use messages::{Node4Info, NodeId, Peer4Info};
+    use super::Lookup;
+
+    struct NoHandler;
+
+    impl Handler for NoHandler {
+        type Timeout = ();
+        type Message = ();
+    }
+
+    /// A deadline that never fires, as nothing runs the loop.
+    fn deadline() -> Timeout {
+        let mut event_loop: EventLoop<NoHandler> = EventLoop::new().unwrap();
+        event_loop.timeout_ms((), 60_000).unwrap()
+    }
+
+    fn addr(n: u8) -> SocketAddr {
+        format!("198.51.100.{}:6881", n).parse().unwrap()
+    }
+
+    /// Node `n`, which for a target of all zeros is closer the smaller `n` is.
+    fn node(n: u8) -> Node4Info {
+        Node4Info::from_addr(&NodeId([n; 20]), &addr(n)).unwrap()
+    }
+
+    fn lookup(alpha: usize, max_queries: usize) -> Lookup {
+        Lookup::new(NodeId([0; 20]), deadline(), 100, alpha, max_queries)
+    }
+
+    #[test]
+    fn queries_the_closest_first_and_at_most_alpha_at_once() {
+        let mut lookup = lookup(3, 100);
+        for &n in &[5, 3, 9, 1] {
+            lookup.add(node(n));
+        }
+        // the target itself is never a candidate
+        lookup.add(Node4Info::from_addr(&NodeId([0; 20]), &addr(100)).unwrap());
+        assert_eq!(lookup.next_to_query(), Some(node(1)));
+        assert_eq!(lookup.next_to_query(), Some(node(3)));
+        assert_eq!(lookup.next_to_query(), Some(node(5)));
+        assert_eq!(lookup.next_to_query(), None);
+
+        lookup.responded(&node(1).id, &addr(1), &[], None);
+        assert_eq!(lookup.next_to_query(), Some(node(9)));
+        assert_eq!(lookup.next_to_query(), None);
+        assert_eq!(lookup.progress().candidates, 4);
+    }
+
+    #[test]
+    fn follows_closer_nodes_until_the_closest_have_all_answered() {
+        let mut lookup = lookup(1, 100);
+        lookup.add(node(9));
+        assert_eq!(lookup.next_to_query(), Some(node(9)));
+        assert!(!lookup.is_done());
+        lookup.responded(&node(9).id, &addr(9), &[node(4), node(2), node(9)], None);
+        assert_eq!(lookup.iterations(), 1);
+
+        assert_eq!(lookup.next_to_query(), Some(node(2)));
+        lookup.responded(&node(2).id, &addr(2), &[node(1)], None);
+        assert_eq!(lookup.iterations(), 2);
+        assert_eq!(lookup.next_to_query(), Some(node(1)));
+        lookup.responded(&node(1).id, &addr(1), &[], None);
+        assert_eq!(lookup.next_to_query(), Some(node(4)));
+        lookup.responded(&node(4).id, &addr(4), &[], None);
+
+        assert_eq!(lookup.next_to_query(), None);
+        assert!(lookup.is_done());
+        assert_eq!(lookup.closest(), vec![node(1), node(2), node(4), node(9)]);
+        assert_eq!(lookup.progress().queried, 4);
+    }
+
+    #[test]
+    fn failed_and_stalled_queries_make_room_for_others() {
+        let mut lookup = lookup(1, 100);
+        for n in 1..4 {
+            lookup.add(node(n));
+        }
+        assert_eq!(lookup.next_to_query(), Some(node(1)));
+        lookup.failed(&addr(1));
+        assert_eq!(lookup.next_to_query(), Some(node(2)));
+        assert!(lookup.stalled(&addr(2)));
+        assert!(!lookup.stalled(&addr(2)));
+        assert_eq!(lookup.next_to_query(), Some(node(3)));
+        lookup.responded(&node(3).id, &addr(3), &[], None);
+
+        // a stalled node's late reply still counts; a failed one never makes the results
+        lookup.responded(&node(2).id, &addr(2), &[], None);
+        assert!(lookup.is_done());
+        assert_eq!(lookup.closest(), vec![node(2), node(3)]);
+    }
+
+    #[test]
+    fn stops_sending_once_the_budget_is_spent() {
+        let mut lookup = lookup(3, 2);
+        for n in 1..5 {
+            lookup.add(node(n));
+        }
+        assert!(lookup.next_to_query().is_some());
+        assert!(lookup.next_to_query().is_some());
+        assert_eq!(lookup.next_to_query(), None);
+        assert!(!lookup.retried());
+        assert!(!lookup.out_of_budget());
+        lookup.failed(&addr(1));
+        lookup.responded(&node(2).id, &addr(2), &[], None);
+        assert!(lookup.out_of_budget());
+        assert!(!lookup.is_done());
+    }
+
+    #[test]
+    fn seeds_are_filed_once_they_answer() {
+        let mut lookup = lookup(3, 100);
+        lookup.sent();
+        lookup.responded(&node(7).id, &addr(7), &[node(3)], Some(vec![1]));
+        assert_eq!(lookup.next_to_query(), Some(node(3)));
+        lookup.responded(&node(3).id, &addr(3), &[], Some(vec![2]));
+        assert!(lookup.is_done());
+        let result = lookup.result();
+        assert_eq!(result.closest_with_tokens, vec![(node(3), vec![2]), (node(7), vec![1])]);
+    }
+
+    #[test]
+    fn collects_each_peer_once() {
+        let mut lookup = lookup(3, 100);
+        let a = Peer4Info::from_socket_addr(&addr(50)).unwrap();
+        let b = Peer4Info::from_socket_addr(&addr(51)).unwrap();
+        lookup.found_peers(&[a, b]);
+        lookup.found_peers(&[b, a, b]);
+        assert_eq!(lookup.result().peers, vec![a, b]);
+        assert_eq!(lookup.progress().peers, 2);
+    }
+}
@@ -1,227 +1,2824 @@
-#![feature(ip, question_mark)]
+#![feature(question_mark)]
 
 extern crate bencode;
+extern crate crypto;
+extern crate libc;
 extern crate mio;
 extern crate rand;
+extern crate sha1;
 
-use std::collections::HashMap;
-use std::io;
-use std::net::SocketAddr;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::process;
+use std::str;
+use std::time::{Duration, Instant};
 
-use bencode::{Bencode, FromBencode, ToBencode};
+use bencode::{Bencode, ToBencode};
 use mio::{EventLoop, EventSet, Handler, PollOpt, Timeout, Token};
 use mio::udp::UdpSocket;
 
+use announce::{Announce, AnnounceId};
+use blacklist::Blacklist;
+use control::Command;
+use config::{AddressChangePolicy, Config, IdChangePolicy, IdlessPolicy, PingPolicy, ServeMode};
+use error::Error;
+use events::Event;
+use health::Health;
+use impair::Impairment;
+use lookup::{Lookup, LookupId, LookupResult, Outcome};
 use messages::*;
-use table::{NodeState, Slot, Table};
+use observe::{Interest, Observer, QueryHook};
+use peers::{PeerCache, PeerStore};
+use reachability::Reachability;
+use recent::RecentTxs;
+use resolver::{Resolver, SystemResolver};
+use stats::{DecodeErrorCounts, Stats, Summary, TxInfo};
+use storage::ItemStore;
+use table::{Entry, Eviction, NodeState, Slot, Split, Table};
+use token::TokenManager;
 
+mod announce;
+mod blacklist;
+mod config;
+mod control;
+mod error;
+mod events;
+mod health;
+mod impair;
+mod lookup;
 mod messages;
+mod observe;
+mod peers;
+mod reachability;
+mod recent;
+mod resolver;
+mod sockbuf;
+mod stats;
+mod storage;
 mod table;
+mod token;
+
+/// `println!`, but only with `Config::trace_txs` on; for one-line-per-packet chatter.
+macro_rules! trace {
+    ($handler:expr, $($arg:tt)*) => {
+        if $handler.config.trace_txs {
+            println!($($arg)*)
+        }
+    }
+}
+
+const USAGE: &'static str = "\
+usage: dht [--bind ADDR:PORT] [--id HEX] [--source IP] [--random-port] [--bootstrap HOST:PORT]... [--contacts FILE] [--find INFOHASH] [--timeout SECS] [--json-events]
+           [--network NAME] [--announce INFOHASH:PORT]... [--seed INFOHASH:PORT]...
+           [--ping-policy POLICY]
+           [--ban IP[/BITS]]... [--ban-file FILE] [--trace] [--control ADDR:PORT]
+           [--peer-store FILE] [--save-contacts FILE] [--recv-buffer BYTES] [--send-buffer BYTES]
+           [--idless-queries POLICY] [--observe PER_SEC] [--refuse INFOHASH]...
+           [--address-changes POLICY] [--id-changes POLICY] [--self-refresh SECS]
+           [--summary SECS] [--serve MODE]
+           [--eviction POLICY] [--split WHEN] [--max-buckets N] [--bad-after N]
+           [--prefer-bep42] [--max-reply-nodes N] [--accept-private] [--serve-min-good N]
+           [--no-learn-from-queriers]
+           [--impair-loss FRACTION] [--impair-latency MS] [--impair-seed N]
+
+    --bind ADDR:PORT        local IPv4 address to listen on (default 0.0.0.0:6881); [::]:PORT
+                            means 0.0.0.0:PORT
+    --id HEX                use this 40-digit hex node ID rather than a random one
+    --source IP             send and listen on this IPv4 address only, keeping --bind's port;
+                            use on hosts with several public IPs
+    --random-port           listen on a port the OS picks, different each run, rather than
+                            --bind's
+    --bootstrap HOST:PORT   node to join the network through; may be repeated
+    --contacts FILE         try these saved [ID] ADDR:PORT lines first, only using --bootstrap
+                            nodes if too few answer
+    --find INFOHASH         print the nodes closest to this 40-digit hex ID, then exit
+    --timeout SECS          give up on a lookup after this long, keeping the nodes found so
+                            far; bounds how long --find runs (default 30)
+    --json-events           print lifecycle events to stdout as line-delimited JSON
+    --network NAME          join the private DHT with this identifier instead of mainline
+    --announce INFOHASH:PORT
+                            keep announcing a peer on PORT for this infohash; may be repeated
+    --seed INFOHASH:PORT    like --announce, but as a seed: other nodes are told we have the
+                            whole torrent, and we only ask them for peers that don't
+    --ping-policy POLICY    how to treat nodes we hear of secondhand: eager (ping at once,
+                            the default), lazy (don't ping) or bucket-not-full (ping only if
+                            their bucket has room)
+    --ban IP[/BITS]         ignore everything from this address or subnet; may be repeated
+    --ban-file FILE         load bans from FILE, one IP[/BITS] per line, and append new ones
+    --trace                 log every query, reply and timeout as it happens
+    --control ADDR:PORT     take commands as UDP datagrams on this loopback address, replying
+                            to each; send \"help\" for the list
+    --peer-store FILE       keep the peers announced to us in FILE across restarts
+    --save-contacts FILE    keep our good nodes in FILE as ID ADDR:PORT lines, for --contacts
+    --recv-buffer BYTES     ask the OS for a socket receive buffer this big
+    --send-buffer BYTES     ask the OS for a socket send buffer this big
+    --idless-queries POLICY what to do with queries missing a valid sender ID: reject
+                            (error 203, the default) or serve
+    --observe PER_SEC       print infohashes others look up or announce to us, at most
+                            PER_SEC a second
+    --refuse INFOHASH       answer get_peers and announce_peer for this infohash with an
+                            error; may be repeated
+    --address-changes POLICY
+                            when a node we recheck answers from a new address: accept (the
+                            default) or reject the new address
+    --id-changes POLICY     when a node we query by ID answers under another: reject the
+                            reply (the default) or accept the new ID
+    --self-refresh SECS     look up our own ID this often to keep our closest neighbours
+                            current (default 900; 0 disables)
+    --summary SECS          log a one-line routing table summary this often
+    --serve MODE            what we answer for others: full (the default), find-only (ping,
+                            find_node, and get_peers without peers) or client-only (ping)
+    --eviction POLICY       which node a full home bucket gives up for a newcomer:
+                            least-reliable (most unanswered queries, the default), oldest
+                            (silent longest) or slowest-rtt
+    --split WHEN            when the deepest bucket splits: eager (as soon as a closer node
+                            turns up, the default) or when-full (only once it's full)
+    --max-buckets N         stop splitting buckets once the table is N deep (default 161)
+    --bad-after N           count a node bad, and evictable, once it leaves N queries in a
+                            row unanswered (default 1)
+    --prefer-bep42          evict nodes whose IDs don't match their IP per BEP 42 first
+    --max-reply-nodes N     take at most N nodes from each node list in a reply (default 32)
+    --accept-private        take private and loopback addresses in replies' node and peer
+                            lists, as a private DHT on a LAN needs
+    --serve-min-good N      refuse find_node and get_peers until we have N good nodes
+    --no-learn-from-queriers
+                            don't ping nodes that query us to add them to our table
+    --impair-loss FRACTION  for testing: drop this fraction of incoming datagrams
+    --impair-latency MS     for testing: hold every incoming datagram this long
+    --impair-seed N         seed for --impair-loss, to repeat a run exactly (default 1)";
 
 fn main() {
-    serve().unwrap()
+    let config = match parse_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(msg) => {
+            let _ = writeln!(io::stderr(), "dht: {}\n{}", msg, USAGE);
+            process::exit(2)
+        }
+    };
+    serve(config, Box::new(SystemResolver)).unwrap()
+}
+
+fn parse_args<I: Iterator<Item=String>>(mut args: I) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut bootstrap = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match &arg[..] {
+            "--bind" => {
+                let addr = value()?;
+                config.bind = addr.parse().map_err(|_| format!("bad address {:?}", addr))?;
+                if let SocketAddr::V6(v6) = config.bind {
+                    // we'd have to listen somewhere else than asked
+                    if !v6.ip().is_unspecified() {
+                        return Err(format!("--bind: IPv6 isn't supported yet, got {:?}", addr))
+                    }
+                }
+            }
+            "--source" => {
+                let ip = value()?;
+                match ip.parse() {
+                    Ok(ip @ IpAddr::V4(_)) => config.source = Some(ip),
+                    _ => return Err(format!("--source: bad IPv4 address {:?}", ip)),
+                }
+            }
+            "--id" => {
+                let id = NodeId::from_hex(&value()?).map_err(|e| format!("--id: {}", e))?;
+                config.node_id = Some(id);
+            }
+            "--random-port" => config.random_port = true,
+            "--bootstrap" => bootstrap.push(value()?),
+            "--contacts" => {
+                let path = value()?;
+                config.contacts = read_contacts(&path).map_err(|e| format!("--contacts: {}", e))?;
+            }
+            "--find" => {
+                let id = NodeId::from_hex(&value()?).map_err(|e| format!("--find: {}", e))?;
+                config.find = Some(id);
+            }
+            "--announce" | "--seed" => {
+                let seed = arg == "--seed";
+                let spec = value()?;
+                let (hex, port) = match spec.find(':') {
+                    Some(i) => (&spec[..i], &spec[i + 1..]),
+                    None => return Err(format!("{}: expected INFOHASH:PORT, got {:?}", arg, spec)),
+                };
+                let info_hash = NodeId::from_hex(hex).map_err(|e| format!("{}: {}", arg, e))?;
+                let port = port.parse().map_err(|_| format!("{}: bad port {:?}", arg, port))?;
+                config.announce.push((info_hash, port, seed));
+            }
+            "--json-events" => config.json_events = true,
+            "--trace" => config.trace_txs = true,
+            "--control" => {
+                let addr = value()?;
+                let addr: SocketAddr = addr.parse().map_err(|_| format!("--control: bad address {:?}", addr))?;
+                // anyone who can reach it can steer us
+                if !addr.ip().is_loopback() {
+                    return Err(format!("--control: {} isn't a loopback address", addr))
+                }
+                config.control = Some(addr);
+            }
+            "--observe" => {
+                let rate = value()?;
+                config.observe_queries = Some(rate.parse().map_err(|_| format!("--observe: bad rate {:?}", rate))?);
+            }
+            "--peer-store" => config.peer_store_file = Some(value()?),
+            "--save-contacts" => config.contacts_file = Some(value()?),
+            "--recv-buffer" => {
+                let size = value()?;
+                config.recv_buffer = Some(size.parse().map_err(|_| format!("--recv-buffer: bad size {:?}", size))?);
+            }
+            "--send-buffer" => {
+                let size = value()?;
+                config.send_buffer = Some(size.parse().map_err(|_| format!("--send-buffer: bad size {:?}", size))?);
+            }
+            "--network" => config.network = Some(value()?.into_bytes()),
+            "--ping-policy" => {
+                let name = value()?;
+                config.ping_policy = PingPolicy::from_name(&name)
+                    .ok_or(format!("--ping-policy: unknown policy {:?}", name))?;
+            }
+            "--ban" => {
+                let spec = value()?;
+                let subnet = blacklist::parse_subnet(&spec)
+                    .ok_or(format!("--ban: expected IP or IP/BITS, got {:?}", spec))?;
+                config.bans.push(subnet);
+            }
+            "--ban-file" => {
+                let path = value()?;
+                // a missing file is fine; we'll create it on the first ban
+                match File::open(&path) {
+                    Ok(_) => {
+                        let bans = read_bans(&path).map_err(|e| format!("--ban-file: {}", e))?;
+                        config.bans.extend(bans);
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(format!("--ban-file: {}: {}", path, e)),
+                }
+                config.ban_file = Some(path);
+            }
+            "--refuse" => {
+                let id = NodeId::from_hex(&value()?).map_err(|e| format!("--refuse: {}", e))?;
+                config.refused.push(id);
+            }
+            "--timeout" => {
+                let secs = value()?;
+                let secs: u64 = secs.parse().map_err(|_| format!("--timeout: bad duration {:?}", secs))?;
+                if secs == 0 {
+                    return Err("--timeout must be at least 1".to_string())
+                }
+                config.lookup_timeout_ms = secs * 1000;
+            }
+            "--self-refresh" => {
+                let secs = value()?;
+                let secs: u64 = secs.parse().map_err(|_| format!("--self-refresh: bad interval {:?}", secs))?;
+                config.self_refresh_ms = secs * 1000;
+            }
+            "--max-reply-nodes" => {
+                let max = value()?;
+                config.max_reply_nodes = max.parse().map_err(|_| format!("--max-reply-nodes: bad count {:?}", max))?;
+            }
+            "--prefer-bep42" => config.prefer_bep42 = true,
+            "--accept-private" => config.accept_private_addrs = true,
+            "--no-learn-from-queriers" => config.learn_from_queriers = false,
+            "--serve-min-good" => {
+                let min = value()?;
+                config.serve_min_good = min.parse().map_err(|_| format!("--serve-min-good: bad count {:?}", min))?;
+            }
+            "--eviction" => {
+                let name = value()?;
+                config.eviction = Eviction::from_name(&name)
+                    .ok_or(format!("--eviction: unknown policy {:?}", name))?;
+            }
+            "--split" => {
+                let name = value()?;
+                config.split = Split::from_name(&name).ok_or(format!("--split: unknown strategy {:?}", name))?;
+            }
+            "--max-buckets" => {
+                let max = value()?;
+                config.max_buckets = max.parse().map_err(|_| format!("--max-buckets: bad count {:?}", max))?;
+            }
+            "--bad-after" => {
+                let n = value()?;
+                config.bad_after_failures = n.parse().map_err(|_| format!("--bad-after: bad count {:?}", n))?;
+            }
+            "--impair-loss" => {
+                let loss = value()?;
+                config.impair_loss = match loss.parse() {
+                    Ok(loss) if loss >= 0.0 && loss <= 1.0 => loss,
+                    _ => return Err(format!("--impair-loss: expected a fraction from 0 to 1, got {:?}", loss)),
+                };
+            }
+            "--impair-latency" => {
+                let ms = value()?;
+                config.impair_latency_ms = ms.parse().map_err(|_| format!("--impair-latency: bad delay {:?}", ms))?;
+            }
+            "--impair-seed" => {
+                let seed = value()?;
+                config.impair_seed = seed.parse().map_err(|_| format!("--impair-seed: bad seed {:?}", seed))?;
+            }
+            "--serve" => {
+                let name = value()?;
+                config.serve_mode = ServeMode::from_name(&name)
+                    .ok_or(format!("--serve: unknown mode {:?}", name))?;
+            }
+            "--summary" => {
+                let secs = value()?;
+                let secs: u64 = secs.parse().map_err(|_| format!("--summary: bad interval {:?}", secs))?;
+                config.summary_interval_ms = secs * 1000;
+            }
+            "--id-changes" => {
+                let name = value()?;
+                config.id_changes = IdChangePolicy::from_name(&name)
+                    .ok_or(format!("--id-changes: unknown policy {:?}", name))?;
+            }
+            "--address-changes" => {
+                let name = value()?;
+                config.address_changes = AddressChangePolicy::from_name(&name)
+                    .ok_or(format!("--address-changes: unknown policy {:?}", name))?;
+            }
+            "--idless-queries" => {
+                let name = value()?;
+                config.idless_policy = IdlessPolicy::from_name(&name)
+                    .ok_or(format!("--idless-queries: unknown policy {:?}", name))?;
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0)
+            }
+            _ => return Err(format!("unknown argument {:?}", arg)),
+        }
+    }
+    if !bootstrap.is_empty() {
+        config.bootstrap = bootstrap;
+    }
+    Ok(config)
 }
 
 const SERVER: Token = Token(0);
+const CONTROL: Token = Token(1);
+
+/// Room for the largest UDP payload there is, so the OS never has to cut one short.
+/// `Config::max_datagram` only limits what we send.
+const RECV_BUF_LEN: usize = 65_536;
+
+/// Most secondhand nodes waiting for a discovery ping; any more are forgotten.
+const MAX_QUEUED_PINGS: usize = 256;
+
+/// How long we wait for a reply to any query.
+const TX_TIMEOUT_MS: u64 = 5_000;
+
+/// Times we resend a query answered with error 202, in case the node was only briefly
+/// overloaded.
+const MAX_SERVER_ERROR_RETRIES: u32 = 1;
+
+/// First and longest waits between attempts to replace a broken socket.
+const REBIND_MIN_MS: u64 = 1_000;
+const REBIND_MAX_MS: u64 = 60_000;
 
 struct ServerHandler {
     sock: UdpSocket,
+    /// Where `sock` is bound, so a replacement can take its place.
+    local_addr: SocketAddr,
+    /// Set while `sock` is a placeholder awaiting a `Timer::Rebind`: the current backoff.
+    rebind_backoff_ms: Option<u64>,
     table: Table,
-    txs: HashMap<TxId, Tx>,
+    txs: HashMap<TxId, Pending>,
+    tx_ids: TxIdAllocator,
+    /// Transactions answered lately, to tell a repeated reply from one to a tx we never sent.
+    recent_txs: RecentTxs,
+    lookups: HashMap<LookupId, Lookup>,
+    announces: HashMap<AnnounceId, Announce>,
+    /// What each lookup is for.
+    purposes: HashMap<LookupId, Purpose>,
+    /// The self-lookup bootstrap pongs feed into, once the first has arrived.
+    bootstrap_lookup: Option<LookupId>,
+    /// The lookup `--find` started; we exit once it's done.
+    find_lookup: Option<LookupId>,
+    next_announce: AnnounceId,
+    tokens: TokenManager,
+    peers: PeerStore,
+    /// Peers our own `get_peers` lookups found lately.
+    peer_cache: PeerCache,
+    /// Control clients awaiting the peers each `get_peers` lookup finds.
+    peer_waiters: HashMap<LookupId, Vec<SocketAddr>>,
+    items: ItemStore,
+    next_lookup: LookupId,
+    config: Config,
+    /// When the next `Timer::Refresh` fires.
+    refresh_due: Instant,
+    /// Our closest neighbours as the running self-refresh lookup started, to count how many
+    /// it turned up.
+    self_refresh_baseline: Vec<NodeId>,
+    /// When a reply to one of our queries last arrived.
+    last_response: Option<Instant>,
+    /// `Tx::FirstPing`s awaiting a reply or timeout.
+    discovery_pings: usize,
+    /// Secondhand nodes to ping once `discovery_pings` drops below the limit.
+    ping_queue: VecDeque<SocketAddr>,
+    /// Every address with a discovery ping queued or awaiting a pong, so none is pinged
+    /// twice over.
+    discovering: HashSet<SocketAddr>,
+    /// Announce rounds waiting for a slot under `Config::max_concurrent_announces`, each with
+    /// the addresses to start from (or none, to start from our table).
+    announce_queue: VecDeque<(AnnounceId, Vec<SocketAddr>)>,
+    /// How other nodes see our address.
+    reachability: Reachability,
+    /// Why incoming messages failed to decode, for `Stats`.
+    decode_errors: DecodeErrorCounts,
+    /// Datagrams dropped because they filled the whole receive buffer, for `Stats`.
+    truncated_datagrams: u64,
+    /// Destination for JSON lifecycle events; `None` unless `Config::json_events` is set.
+    events: Option<Box<Write>>,
+    /// Sources we drop on arrival and nodes we won't file.
+    blacklist: Blacklist,
+    /// Turns `Config::bootstrap` names into addresses.
+    resolver: Box<Resolver>,
+    /// Hears about the infohashes others query us for, if anyone's listening.
+    observer: Option<Observer>,
+    /// Gets a say in whether each query is served.
+    query_policy: Option<QueryPolicy>,
+    /// Deliberate loss and latency on incoming datagrams, if testing asked for any.
+    impairment: Option<Impairment>,
+    /// Where `--control` commands arrive, if anywhere.
+    control: Option<UdpSocket>,
 }
 
 impl Handler for ServerHandler {
-    type Timeout = TxId;
+    type Timeout = Timer;
     type Message = ();
 
     fn ready(&mut self, event_loop: &mut EventLoop<ServerHandler>, token: Token, _: EventSet) {
         if token == SERVER {
-            let mut buf = [0u8; 512];
-            match self.sock.recv_from(&mut buf) {
-                Ok(Some((len, addr))) => {
-                    assert!(len < 512, "big packet");
-
-                    match bencode::from_buffer(&buf[..len]) {
-                        Ok(msg) => {
-                            match self.received(event_loop, &addr, &msg) {
-                                Ok(()) => (),
-                                Err(e) => println!("{:?}: {:?}", addr, e)
+            // We're registered edge-triggered, so drain every queued datagram now;
+            // there won't be another readiness event for the ones we leave behind.
+            let mut buf = [0u8; RECV_BUF_LEN];
+            loop {
+                match self.sock.recv_from(&mut buf) {
+                    Ok(Some((len, addr))) => {
+                        if len == buf.len() {
+                            // can't tell a datagram that just fits from one the OS cut short
+                            self.truncated_datagrams += 1;
+                            trace!(self, "dropped a datagram of {}+ bytes from {:?}", len, addr);
+                            continue
+                        }
+                        let held = match self.impairment {
+                            Some(ref mut impairment) => {
+                                if impairment.drops() {
+                                    trace!(self, "impair: dropped datagram from {:?}", addr);
+                                    continue
+                                }
+                                match impairment.latency_ms() {
+                                    0 => None,
+                                    latency => Some((impairment.hold(&buf[..len], addr), latency)),
+                                }
+                            }
+                            None => None,
+                        };
+                        match held {
+                            Some((seq, latency)) => {
+                                event_loop.timeout_ms(Timer::Deliver(seq), latency).unwrap();
                             }
+                            None => self.datagram(event_loop, &buf[..len], &addr),
                         }
-                        Err(e) => println!("{:?}: at pos {}: {}", addr, e.pos, e.msg)
+                    }
+                    Ok(None) => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    // ICMP unreachables for earlier sends surface here on some platforms;
+                    // they say nothing about this socket's health
+                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused ||
+                                  e.kind() == io::ErrorKind::ConnectionReset => {
+                        println!("S: {}", e);
+                        continue
+                    }
+                    Err(e) => {
+                        println!("S: error: {}; rebinding", e);
+                        self.schedule_rebind(event_loop);
+                        break
                     }
                 }
-                Ok(None) => println!("S: got nothing?"),
-                Err(e) => println!("S: error: {}", e),
             }
 
+        } else if token == CONTROL {
+            self.control_ready(event_loop);
         } else {
             panic!(token);
         }
     }
 
-    fn timeout(&mut self, _: &mut EventLoop<ServerHandler>, id: TxId) {
-        if let Some(_) = self.txs.remove(&id) {
-            println!("timeout {:?}", id);
+    fn timeout(&mut self, event_loop: &mut EventLoop<ServerHandler>, timer: Timer) {
+        match timer {
+            Timer::Deliver(seq) => {
+                let released = self.impairment.as_mut().and_then(|impairment| impairment.release(seq));
+                if let Some((bytes, addr)) = released {
+                    self.datagram(event_loop, &bytes, &addr);
+                }
+            }
+            Timer::Tx(id) => {
+                if let Some(pending) = self.txs.remove(&id) {
+                    trace!(self, "timeout {:?}", id);
+                    self.abandon(event_loop, pending.tx, pending.expected, None);
+                }
+            }
+            Timer::Lookup(id) => {
+                if self.lookups.contains_key(&id) {
+                    self.finish_lookup(event_loop, id, Outcome::TimedOut);
+                }
+            }
+            Timer::Stall(id, addr) => {
+                let stalled = match self.lookups.get_mut(&id) {
+                    Some(lookup) => lookup.stalled(&addr),
+                    None => false,
+                };
+                if stalled {
+                    println!("lookup: {:?} is slow, trying someone else", addr);
+                    if let Err(e) = self.advance_lookup(event_loop, id) {
+                        println!("lookup: {}", e);
+                    }
+                }
+            }
+            Timer::Reannounce(id) => {
+                if let Err(e) = self.run_announce(event_loop, id) {
+                    println!("announce: {}", e);
+                }
+            }
+            Timer::SelfRefresh => {
+                if let Err(e) = self.refresh_self(event_loop) {
+                    println!("self-refresh: {}", e);
+                }
+                event_loop.timeout_ms(Timer::SelfRefresh, self.config.self_refresh_ms).unwrap();
+            }
+            Timer::Summary => {
+                println!("{}", self.summary());
+                event_loop.timeout_ms(Timer::Summary, self.config.summary_interval_ms).unwrap();
+            }
+            Timer::Rebind => self.rebind(event_loop),
+            Timer::WarmStart => self.check_warm_start(event_loop),
+            Timer::Refresh => {
+                if let Err(e) = self.refresh_stalest(event_loop) {
+                    println!("refresh: {}", e);
+                }
+                if let Err(e) = self.probe_reachability(event_loop) {
+                    println!("reachability: {}", e);
+                }
+                if let Err(e) = self.save_peer_store() {
+                    println!("peer store: {}", e);
+                }
+                if let Err(e) = self.save_contacts() {
+                    println!("contacts: {}", e);
+                }
+                let tick = self.config.refresh_tick_ms;
+                self.refresh_due = Instant::now() + Duration::from_millis(tick);
+                event_loop.timeout_ms(Timer::Refresh, tick).unwrap();
+                println!("{:?}", self.stats());
+                if let Some(ref observer) = self.observer {
+                    if observer.dropped() > 0 {
+                        println!("observe: {} queries skipped for coming too fast", observer.dropped());
+                    }
+                }
+                println!("{:?}", self.health());
+            }
         }
     } 
 }
 
 impl ServerHandler {
-    fn send(&mut self, event_loop: &mut EventLoop<ServerHandler>, dest: &SocketAddr, query: Query)
-        -> io::Result<()>
+    /// A handler for `sock`, already registered as `SERVER`, with its timers set and its
+    /// control socket, if `config` asks for one, bound and registered. Sends nothing.
+    fn new(config: Config, sock: UdpSocket, resolver: Box<Resolver>,
+           event_loop: &mut EventLoop<ServerHandler>) -> error::Result<Self>
     {
-        // Generate a unique ID for this transaction.
-        let tx_id;
-        let mut attempts = 0;
-        loop {
-            let try_id = TxId::random();
-            if !self.txs.contains_key(&try_id) {
-                tx_id = try_id;
-                break
+        let my_id = config.node_id.unwrap_or_else(NodeId::random);
+        let mut table = Table::new(my_id);
+        table.set_spill_hook(Box::new(|buckets| println!("table now has {} buckets", buckets)));
+        table.set_home_eviction(config.home_eviction);
+        table.set_eviction(config.eviction);
+        table.set_prefer_bep42(config.prefer_bep42);
+        table.set_split(config.split);
+        table.set_max_buckets(config.max_buckets);
+        table.set_bad_after(config.bad_after_failures);
+        let mut peers = PeerStore::new();
+        if let Some(ref path) = config.peer_store_file {
+            match load_peer_store(path, &mut peers) {
+                Ok(loaded) => println!("loaded {} peers from {}", loaded, path),
+                Err(e) => println!("peer store: {}: {}", path, e),
+            }
+        }
+        let mut blacklist = Blacklist::new(config.max_strikes);
+        for &(ip, prefix_len) in &config.bans {
+            blacklist.ban(ip, prefix_len);
+        }
+        let peer_cache = PeerCache::new(config.peer_cache_size,
+                                        Duration::from_millis(config.peer_cache_ttl_ms));
+        let refresh_tick = config.refresh_tick_ms;
+        event_loop.timeout_ms(Timer::Refresh, refresh_tick).unwrap();
+        if config.self_refresh_ms > 0 {
+            event_loop.timeout_ms(Timer::SelfRefresh, config.self_refresh_ms).unwrap();
+        }
+        let impairment = if config.impair_loss > 0.0 || config.impair_latency_ms > 0 {
+            println!("impair: dropping {} of incoming datagrams, delaying the rest {}ms",
+                     config.impair_loss, config.impair_latency_ms);
+            Some(Impairment::new(config.impair_loss, config.impair_latency_ms, config.impair_seed))
+        } else {
+            None
+        };
+        if config.summary_interval_ms > 0 {
+            event_loop.timeout_ms(Timer::Summary, config.summary_interval_ms).unwrap();
+        }
+        let control = match config.control {
+            Some(addr) => {
+                let control = UdpSocket::bound(&addr)?;
+                event_loop.register(&control, CONTROL, EventSet::readable(), PollOpt::edge())?;
+                println!("taking commands on {}", addr);
+                Some(control)
+            }
+            None => None,
+        };
+        let events: Option<Box<Write>> = if config.json_events {
+            Some(Box::new(io::stdout()))
+        } else {
+            None
+        };
+        let local = sock.local_addr()?;
+        Ok(ServerHandler {
+            sock: sock,
+            local_addr: local,
+            rebind_backoff_ms: None,
+            table: table,
+            txs: HashMap::new(),
+            tx_ids: TxIdAllocator::new(),
+            recent_txs: RecentTxs::new(config.recent_txs),
+            lookups: HashMap::new(),
+            next_lookup: 0,
+            announces: HashMap::new(),
+            purposes: HashMap::new(),
+            bootstrap_lookup: None,
+            find_lookup: None,
+            next_announce: 0,
+            tokens: TokenManager::new(),
+            peers: peers,
+            peer_cache: peer_cache,
+            peer_waiters: HashMap::new(),
+            items: ItemStore::new(),
+            config: config,
+            refresh_due: Instant::now() + Duration::from_millis(refresh_tick),
+            self_refresh_baseline: vec![],
+            last_response: None,
+            discovery_pings: 0,
+            ping_queue: VecDeque::new(),
+            discovering: HashSet::new(),
+            announce_queue: VecDeque::new(),
+            reachability: Reachability::new(local.port()),
+            decode_errors: DecodeErrorCounts::default(),
+            truncated_datagrams: 0,
+            events: events,
+            blacklist: blacklist,
+            resolver: resolver,
+            observer: None,
+            query_policy: None,
+            impairment: impairment,
+            control: control,
+        })
+    }
+
+    /// Decodes and acts on one incoming datagram.
+    fn datagram(&mut self, event_loop: &mut EventLoop<ServerHandler>, bytes: &[u8], addr: &SocketAddr) {
+        if self.blacklist.is_banned(&addr.ip()) {
+            return
+        }
+
+        match bencode::from_buffer(bytes) {
+            Ok(msg) => {
+                match self.received(event_loop, addr, &msg) {
+                    Ok(()) => (),
+                    Err(e) => {
+                        println!("{:?}: {:?}", addr, e);
+                        self.emit(Event::Error {addr: addr, message: &e.to_string()});
+                        if let Error::Decode(_) = e {
+                            self.strike(addr);
+                        }
+                    }
+                }
             }
-            attempts += 1;
-            if attempts > 10 {
-                // should make a long random ID here
-                return Err(io::Error::new(io::ErrorKind::Other, "tx IDs unavailable"))
+            Err(e) => {
+                println!("{:?}: at pos {}: {}", addr, e.pos, e.msg);
+                self.strike(addr);
             }
         }
+    }
+
+    /// Sends `query` to `dest`, remembering the transaction as built by `tx`.
+    fn send<F>(&mut self, event_loop: &mut EventLoop<ServerHandler>, dest: &SocketAddr, query: Query,
+               tx: F) -> error::Result<()>
+        where F: FnOnce(SocketAddr, Timeout) -> Tx
+    {
+        self.send_expecting(event_loop, dest, None, query, tx)
+    }
+
+    /// Like `send`, but to a node we know by ID: a reply under any other ID is dealt with as
+    /// `Config::id_changes` says.
+    fn send_to<F>(&mut self, event_loop: &mut EventLoop<ServerHandler>, node: &Node4Info, query: Query,
+                  tx: F) -> error::Result<()>
+        where F: FnOnce(SocketAddr, Timeout) -> Tx
+    {
+        self.send_expecting(event_loop, &node.peer.socket_addr(), Some(node.id), query, tx)
+    }
+
+    fn send_expecting<F>(&mut self, event_loop: &mut EventLoop<ServerHandler>, dest: &SocketAddr,
+                         expected: Option<NodeId>, query: Query, tx: F) -> error::Result<()>
+        where F: FnOnce(SocketAddr, Timeout) -> Tx
+    {
+        self.send_attempt(event_loop, dest, expected, query, tx, 0)
+    }
 
+    /// Sends a query for the first time, or for the `retries`th time over.
+    fn send_attempt<F>(&mut self, event_loop: &mut EventLoop<ServerHandler>, dest: &SocketAddr,
+                       expected: Option<NodeId>, query: Query, tx: F, retries: u32) -> error::Result<()>
+        where F: FnOnce(SocketAddr, Timeout) -> Tx
+    {
+        let tx_id = {
+            let txs = &self.txs;
+            self.tx_ids.next(|id| txs.contains_key(id))
+        };
+        self.recent_txs.forget(&tx_id);
+
+        let method = query.method();
         let full = FullQuery {
             query: query,
-            sender_id: self.table.our_id().clone(),
+            sender_id: Some(*self.table.our_id()),
             tx_id: tx_id.clone(),
         };
-        println!("send to {:?}: {:?}", dest, full);
-        let bytes = full.to_bencode().to_bytes()?;
+        let bytes = if self.config.trace_txs {
+            // decode what we're about to send, so the trace shows what the other end will see
+            let (bytes, decoded) = self.dry_run(full.to_bencode())?;
+            println!("send to {:?}, {} bytes: {:?}", dest, bytes.len(), decoded);
+            bytes
+        } else {
+            self.encode(full.to_bencode())?
+        };
 
-        // TODO completion closure?
+        self.transmit(&bytes, dest)?;
 
-        if let Some(n_sent) = self.sock.send_to(&bytes, dest)? {
-            assert_eq!(n_sent, bytes.len());
+        let timeout = event_loop.timeout_ms(Timer::Tx(tx_id.clone()), TX_TIMEOUT_MS).unwrap();
+        let pending = Pending {
+            tx: tx(dest.clone(), timeout),
+            query: full.query,
+            method: method,
+            sent: Instant::now(),
+            deadline: Instant::now() + Duration::from_millis(TX_TIMEOUT_MS),
+            expected: expected,
+            retries: retries,
+        };
+        let overwritten = self.txs.insert(tx_id, pending);
+        debug_assert!(overwritten.is_none());
 
-            let timeout = event_loop.timeout_ms(tx_id.clone(), 5000).unwrap();
-            let tx = Tx::FirstPing(dest.clone(), timeout);
-            let overwritten = self.txs.insert(tx_id, tx);
-            debug_assert!(overwritten.is_none());
+        Ok(())
+    }
 
-            Ok(())
-        } else {
-            Err(io::Error::new(io::ErrorKind::BrokenPipe, "ServerHandler::send: got None"))
+    /// Whether a query refused with a server error may go out again; a lookup's retries come
+    /// out of its query budget.
+    fn may_retry(&mut self, tx: &Tx) -> bool {
+        match *tx {
+            Tx::Lookup(id, _, _) => self.lookups.get_mut(&id).map_or(false, |lookup| lookup.retried()),
+            _ => true,
+        }
+    }
+
+    /// Sends a refused query to the same node again, as the same kind of transaction. If it
+    /// can't go out, the transaction ends as refused with `refusal`.
+    fn resend(&mut self, event_loop: &mut EventLoop<ServerHandler>, pending: Pending, refusal: DhtError) {
+        let Pending {tx, query, expected, retries, ..} = pending;
+        let dest = tx.addr();
+        // held out here so a failed send hands it back rather than dropping it
+        let mut unsent = Some(tx);
+        let sent = self.send_attempt(event_loop, &dest, expected, query,
+                                     |_, t| unsent.take().unwrap().rearmed(t), retries + 1);
+        if let Err(e) = sent {
+            println!("retry to {:?}: {}", dest, e);
+            if let Some(tx) = unsent.take() {
+                self.abandon(event_loop, tx, expected, Some(refusal));
+            }
+        }
+    }
+
+    /// Ends a transaction that won't be answered, because it timed out or was refused with
+    /// `error`. Either way it counts as a failure against `expected`, the node we asked.
+    fn abandon(&mut self, event_loop: &mut EventLoop<ServerHandler>, tx: Tx, expected: Option<NodeId>,
+               error: Option<DhtError>)
+    {
+        if let Some(node_id) = expected {
+            if let Some(entry) = self.table.entry_mut(&node_id) {
+                entry.failures += 1;
+            }
+        }
+        match tx {
+            Tx::Lookup(lookup, addr, _) => {
+                if let Some(lookup) = self.lookups.get_mut(&lookup) {
+                    lookup.failed(&addr);
+                }
+                if let Err(e) = self.advance_lookup(event_loop, lookup) {
+                    println!("lookup: {}", e);
+                }
+            }
+            Tx::Callback(_, _, mut on_done) => {
+                on_done(self, event_loop, error.map_or(TxOutcome::TimedOut, TxOutcome::Error))
+            }
+            Tx::FirstPing(addr, _) => self.discovery_ping_done(event_loop, &addr),
+            _ => (),
         }
     }
 
+    /// Sends `query` to `dest`, calling `on_done` once it's answered, refused, or timed out.
+    fn send_with(&mut self, event_loop: &mut EventLoop<ServerHandler>, dest: &SocketAddr, query: Query,
+                 on_done: Completion) -> error::Result<()>
+    {
+        self.send(event_loop, dest, query, move |a, t| Tx::Callback(a, t, on_done))
+    }
+
     fn received(&mut self, event_loop: &mut EventLoop<ServerHandler>, addr: &SocketAddr, msg: &Bencode)
-        -> io::Result<()>
+        -> error::Result<()>
     {
-        match DhtMessage::from_bencode(msg)? {
+        let msg = {
+            let network = self.config.network.as_ref().map(|n| &n[..]);
+            match DhtMessage::decode(msg, network, self.list_policy()) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.decode_errors.record(&e);
+                    return Err(e.into())
+                }
+            }
+        };
+        match msg {
             DhtMessage::Query(query) => {
-                println!("query from {:?}: {:?}", addr, query);
-                Ok(())
+                trace!(self, "query from {:?}: {:?}", addr, query);
+                self.reachability.inbound_query();
+                if let Some(ref mut observer) = self.observer {
+                    match query.query {
+                        Query::GetPeers {ref info_hash, ..} => observer.observe(info_hash, addr, Interest::GetPeers),
+                        Query::AnnouncePeer {ref info_hash, ..} => {
+                            observer.observe(info_hash, addr, Interest::Announce)
+                        }
+                        _ => (),
+                    }
+                }
+                if let Some(sender_id) = query.sender_id {
+                    // the query gets its answer whether or not we manage to ping back
+                    if let Err(e) = self.learn_from_querier(event_loop, sender_id, addr) {
+                        println!("discovery: couldn't ping querier {:?}: {}", addr, e);
+                    }
+                }
+                self.serve(addr, query)
             }
             DhtMessage::Response(resp) => {
-                match self.txs.remove(&resp.tx_id) {
-                    Some(tx) => self.handle(event_loop, addr, resp, tx),
+                if resp.ignored_nodes > 0 {
+                    trace!(self, "{:?} listed {} nodes past the first {}", addr, resp.ignored_nodes,
+                           self.config.max_reply_nodes);
+                }
+                // Check the source before taking the tx; otherwise anyone who guesses a tx ID
+                // could cancel our query by replying first from somewhere else.
+                let (queried, expected) = match self.txs.get(&resp.tx_id) {
+                    Some(pending) => (pending.tx.addr(), pending.expected),
                     None => {
-                        Err(io::Error::new(io::ErrorKind::Other,
-                            format!("{:?}: {:?} has unknown tx", addr, resp)))
+                        return Err(match self.recent_txs.answered(&resp.tx_id, addr) {
+                            Some(answered) => Error::DuplicateReply(answered.elapsed()),
+                            None => Error::NoSuchTransaction,
+                        })
+                    }
+                };
+                if addr != &queried {
+                    return Err(Error::WrongSource(queried))
+                }
+                // Likewise the ID, when we know whose it should be.
+                if let Some(expected) = expected {
+                    if resp.sender_id != expected {
+                        match self.config.id_changes {
+                            IdChangePolicy::Reject => return Err(Error::WrongSender(expected)),
+                            IdChangePolicy::Accept => {
+                                println!("{:?} at {:?} now answers as {:?}", expected, addr, resp.sender_id)
+                            }
+                        }
                     }
                 }
+                let Pending {tx, sent, ..} = self.txs.remove(&resp.tx_id).unwrap();
+                self.recent_txs.record(resp.tx_id.clone(), *addr);
+                self.last_response = Some(Instant::now());
+                // the node we asked answered, even if under a new ID
+                if let Some(entry) = self.table.entry_mut(&expected.unwrap_or(resp.sender_id)) {
+                    entry.failures = 0;
+                    entry.rtt = Some(sent.elapsed());
+                }
+                if let Some(seen_as) = resp.ip {
+                    self.reachability.report(seen_as);
+                }
+                let discovery = match tx {
+                    Tx::FirstPing(..) => true,
+                    _ => false,
+                };
+                let handled = self.handle(event_loop, addr, resp, tx);
+                if discovery {
+                    self.discovery_ping_done(event_loop, addr);
+                }
+                handled
             }
             DhtMessage::Error(e) => {
-                println!("error from {:?}: {:?}", addr, e);
+                trace!(self, "error from {:?}: {:?}", addr, e);
+                let awaited = match self.txs.get(&e.tx_id) {
+                    Some(pending) => addr == &pending.tx.addr(),
+                    None => false,
+                };
+                if !awaited {
+                    return Ok(())
+                }
+                let pending = self.txs.remove(&e.tx_id).unwrap();
+                event_loop.clear_timeout(pending.tx.timeout());
+                // 202 is the node's own trouble and may pass; 201, 203 and 204 will just
+                // happen again. Only a retry that's answered spares the node a failure.
+                if e.code == 202 && pending.retries < MAX_SERVER_ERROR_RETRIES && self.may_retry(&pending.tx) {
+                    trace!(self, "{:?} had a server error, resending {}", addr, pending.method);
+                    self.resend(event_loop, pending, e);
+                    return Ok(())
+                }
+                if e.code == 204 {
+                    println!("{:?} doesn't support {}", addr, pending.method);
+                }
+                self.abandon(event_loop, pending.tx, pending.expected, Some(e));
                 Ok(())
             }
         }
     }
 
+    /// Has `hook` called with the infohash of every inbound `get_peers` and `announce_peer`,
+    /// up to `max_per_sec` a second, whether or not we go on to serve them.
+    fn set_query_hook(&mut self, hook: QueryHook, max_per_sec: usize) {
+        self.observer = Some(Observer::new(hook, max_per_sec));
+    }
+
+    /// Lets `policy` vet each query before we serve it; whatever code and message it refuses
+    /// one with go back to the querier as a `DhtError`.
+    fn set_query_policy(&mut self, policy: QueryPolicy) {
+        self.query_policy = Some(policy);
+    }
+
+    /// Answers a query from `addr`, the datagram's source address.
+    ///
+    /// Everything that depends on where the querier is uses `addr`, never an address from the
+    /// message: the tokens we issue and check, the port an `implied_port` announce is filed
+    /// under, and the `ip` we echo. For a NATed querier, that's its mapped address.
+    fn serve(&mut self, addr: &SocketAddr, query: FullQuery) -> error::Result<()> {
+        let refusal = match self.query_policy {
+            Some(ref mut policy) => policy(&query, addr).err(),
+            None => None,
+        };
+        if let Some((code, message)) = refusal {
+            let error = DhtError {
+                message: message,
+                code: code,
+                tx_id: query.tx_id,
+                ip: self.seen_as(addr),
+            };
+            return self.reply(addr, error.to_bencode())
+        }
+        if query.sender_id.is_none() && self.config.idless_policy == IdlessPolicy::Reject {
+            let error = DhtError {
+                message: "missing or malformed id".to_string(),
+                code: 203,
+                tx_id: query.tx_id,
+                ip: self.seen_as(addr),
+            };
+            return self.reply(addr, error.to_bencode())
+        }
+        let mode = self.config.serve_mode;
+        if !mode.serves(&query.query) {
+            let error = DhtError {
+                message: format!("{} not served here", query.query.method()),
+                code: 201,
+                tx_id: query.tx_id,
+                ip: self.seen_as(addr),
+            };
+            return self.reply(addr, error.to_bencode())
+        }
+        let routing = match query.query {
+            Query::FindNode(_) | Query::GetPeers {..} => true,
+            _ => false,
+        };
+        if routing && self.config.serve_min_good > 0 && self.table.good_count() < self.config.serve_min_good {
+            // we'd only hand out an empty or useless node list
+            let error = DhtError {
+                message: "still bootstrapping".to_string(),
+                code: 202,
+                tx_id: query.tx_id,
+                ip: self.seen_as(addr),
+            };
+            return self.reply(addr, error.to_bencode())
+        }
+        let response = match query.query {
+            Query::Ping => Response::Pong,
+            // nodes only: without a token nobody can announce to us
+            Query::GetPeers {ref info_hash, ..} if mode != ServeMode::Full => Response::FoundNodes {
+                nodes4: self.table.find_closest(info_hash, table::K),
+            },
+            Query::FindNode(ref target) => Response::FoundNodes {
+                nodes4: self.table.find_closest(target, table::K),
+            },
+            Query::GetPeers {ref info_hash, noseed} => Response::Peers {
+                // bound to where the query really came from, not any address it claims
+                token: self.tokens.issue(addr),
+                values: self.peers.get(info_hash, noseed),
+                nodes4: self.table.find_closest(info_hash, table::K),
+            },
+            Query::AnnouncePeer {ref info_hash, port, implied_port, ref token, seed} => {
+                // recomputed over the announcer's source IP, so tokens can't be replayed
+                // from anywhere else
+                if !self.tokens.validate(token, addr) {
+                    let error = DhtError {
+                        message: "bad token".to_string(),
+                        code: 203,
+                        tx_id: query.tx_id,
+                        ip: self.seen_as(addr),
+                    };
+                    return self.reply(addr, error.to_bencode())
+                }
+                if let Some(peer) = Peer4Info::from_socket_addr(addr) {
+                    let peer = if implied_port { peer } else { peer.with_port(port) };
+                    self.peers.announce(info_hash, peer, seed);
+                }
+                Response::Pong
+            }
+            Query::SampleInfohashes(ref target) => {
+                let (samples, num) = self.peers.sample();
+                Response::Samples {
+                    interval: peers::SAMPLE_INTERVAL_SECS,
+                    num: num as u32,
+                    samples: samples,
+                    nodes4: self.table.find_closest(target, table::K),
+                }
+            }
+            Query::Get(ref target) => {
+                let (v, signed) = match self.items.get(target) {
+                    Some((v, signed)) => (Some(v), signed),
+                    None => (None, None),
+                };
+                Response::Item {
+                    token: self.tokens.issue(addr),
+                    v: v,
+                    signed: signed,
+                    nodes4: self.table.find_closest(target, table::K),
+                }
+            }
+            Query::Put {ref token, ref v, ref signed, ref salt, cas} => {
+                if !self.tokens.validate(token, addr) {
+                    let error = DhtError {
+                        message: "bad token".to_string(),
+                        code: 203,
+                        tx_id: query.tx_id,
+                        ip: self.seen_as(addr),
+                    };
+                    return self.reply(addr, error.to_bencode())
+                }
+                let stored = match *signed {
+                    Some(ref signed) => self.items.put_mutable(v.clone(), signed.clone(), salt, cas),
+                    None => self.items.put_immutable(v.clone()),
+                };
+                if let Err(e) = stored {
+                    let error = DhtError {
+                        message: e.message().to_string(),
+                        code: e.code(),
+                        tx_id: query.tx_id,
+                        ip: self.seen_as(addr),
+                    };
+                    return self.reply(addr, error.to_bencode())
+                }
+                Response::Pong
+            }
+            Query::Unknown(ref method) => {
+                println!("{:?} asked for unknown method {:?}", addr, method);
+                let error = DhtError {
+                    message: "Method Unknown".to_string(),
+                    code: 204,
+                    tx_id: query.tx_id,
+                    ip: self.seen_as(addr),
+                };
+                return self.reply(addr, error.to_bencode())
+            }
+        };
+
+        let mut full = FullResponse {
+            response: response,
+            sender_id: self.table.our_id().clone(),
+            tx_id: query.tx_id,
+            ip: self.seen_as(addr),
+            ignored_nodes: 0,
+        };
+        let mut bytes = self.encode(full.to_bencode())?;
+        while bytes.len() > self.config.max_datagram && full.response.shrink() {
+            bytes = self.encode(full.to_bencode())?;
+        }
+        if bytes.len() > self.config.max_datagram {
+            println!("reply to {:?} is {} bytes even with nothing left to trim", addr, bytes.len());
+        }
+        self.transmit(&bytes, addr)
+    }
+
+    /// The querier's address to echo back in our reply, unless `Config::echo_ip` is off.
+    fn seen_as(&self, querier: &SocketAddr) -> Option<SocketAddrV4> {
+        match *querier {
+            SocketAddr::V4(v4) if self.config.echo_ip => Some(v4),
+            _ => None,
+        }
+    }
+
+    /// Sends a reply to someone else's query; there's no transaction of ours to track.
+    fn reply(&mut self, dest: &SocketAddr, msg: Bencode) -> error::Result<()> {
+        let bytes = self.encode(msg)?;
+        self.transmit(&bytes, dest)
+    }
+
+    /// Puts one datagram on the wire.
+    fn transmit(&mut self, bytes: &[u8], dest: &SocketAddr) -> error::Result<()> {
+        if self.rebind_backoff_ms.is_some() {
+            // sending on the placeholder would bind it to some random port
+            return Err(Error::SocketDown)
+        }
+        if let Some(n_sent) = self.sock.send_to(bytes, dest)? {
+            assert_eq!(n_sent, bytes.len());
+            Ok(())
+        } else {
+            Err(Error::WouldBlock)
+        }
+    }
+
+    /// Gives up on a broken socket and arranges to bind a fresh one, unless that's already
+    /// under way.
+    fn schedule_rebind(&mut self, event_loop: &mut EventLoop<ServerHandler>) {
+        if self.rebind_backoff_ms.is_some() {
+            return
+        }
+        let _ = event_loop.deregister(&self.sock);
+        // drop the old socket so its port is free again
+        match UdpSocket::v4() {
+            Ok(placeholder) => self.sock = placeholder,
+            Err(e) => println!("S: couldn't release socket: {}", e),
+        }
+        self.rebind_backoff_ms = Some(REBIND_MIN_MS);
+        event_loop.timeout_ms(Timer::Rebind, REBIND_MIN_MS).unwrap();
+    }
+
+    /// Tries to bind and register a new socket on our old address, backing off on failure.
+    fn rebind(&mut self, event_loop: &mut EventLoop<ServerHandler>) {
+        let backoff = match self.rebind_backoff_ms {
+            Some(backoff) => backoff,
+            None => return,
+        };
+        let bound = UdpSocket::bound(&self.local_addr).and_then(|sock| {
+            event_loop.register(&sock, SERVER, EventSet::readable(), PollOpt::edge())?;
+            Ok(sock)
+        });
+        match bound {
+            Ok(sock) => {
+                println!("S: rebound to {}", self.local_addr);
+                size_buffers(&sock, &self.config);
+                self.sock = sock;
+                self.rebind_backoff_ms = None;
+            }
+            Err(e) => {
+                let backoff = cmp::min(backoff * 2, REBIND_MAX_MS);
+                println!("S: couldn't rebind to {}: {}; retrying in {}ms", self.local_addr, e, backoff);
+                self.rebind_backoff_ms = Some(backoff);
+                event_loop.timeout_ms(Timer::Rebind, backoff).unwrap();
+            }
+        }
+    }
+
+    /// Serializes an outgoing message, tagging it if we're on a private network.
+    fn encode(&self, mut msg: Bencode) -> error::Result<Vec<u8>> {
+        if let Some(ref network) = self.config.network {
+            tag_network(&mut msg, network);
+        }
+        Ok(msg.to_bytes()?)
+    }
+
+    /// What `send` or `reply` would put on the wire for `msg`, and what a node like us would
+    /// decode from it, without touching the socket.
+    ///
+    /// A message that fails to decode here would be dropped at the other end too. With
+    /// `Config::trace_txs` on, every query we send goes through here to be logged.
+    fn dry_run(&self, msg: Bencode) -> error::Result<(Vec<u8>, DhtMessage)> {
+        let bytes = self.encode(msg)?;
+        let parsed = bencode::from_buffer(&bytes).map_err(|_| DecodeError::WrongType)?;
+        let network = self.config.network.as_ref().map(|n| &n[..]);
+        let decoded = DhtMessage::decode(&parsed, network, self.list_policy())?;
+        Ok((bytes, decoded))
+    }
+
+    /// How we take node and peer lists from the messages we decode.
+    fn list_policy(&self) -> ListPolicy {
+        ListPolicy {
+            max_nodes: self.config.max_reply_nodes,
+            allow_private: self.config.accept_private_addrs,
+        }
+    }
+
     fn handle(&mut self, event_loop: &mut EventLoop<ServerHandler>, addr: &SocketAddr,
-              resp: FullResponse, tx: Tx) -> io::Result<()>
+              resp: FullResponse, tx: Tx) -> error::Result<()>
     {
+        if let Tx::Lookup(id, _, timeout) = tx {
+            event_loop.clear_timeout(timeout);
+            let (found, token) = match resp.response {
+                Response::Pong => (vec![], None),
+                Response::FoundNodes {nodes4} |
+                Response::Samples {nodes4, ..} => (nodes4, None),
+                Response::Item {nodes4, token, ..} => (nodes4, Some(token)),
+                Response::Peers {nodes4, token, values} => {
+                    if !values.is_empty() {
+                        trace!(self, "{:?} knows {} peers", addr, values.len());
+                        if let Some(lookup) = self.lookups.get_mut(&id) {
+                            lookup.found_peers(&values);
+                        }
+                    }
+                    (nodes4, Some(token))
+                }
+            };
+            let found: Vec<Node4Info> = found.into_iter()
+                .filter(|node| !self.is_us(node) && !self.is_banned(node))
+                .collect();
+            let progress = match self.lookups.get_mut(&id) {
+                Some(lookup) => {
+                    lookup.responded(&resp.sender_id, addr, &found, token);
+                    Some((*lookup.target(), lookup.progress()))
+                }
+                None => None,
+            };
+            if let Some((target, progress)) = progress {
+                self.emit(Event::LookupProgress {target: &target, progress: progress});
+            }
+            if let Some(node) = Node4Info::from_addr(&resp.sender_id, addr) {
+                self.heard_from(node);
+            }
+            return self.advance_lookup(event_loop, id)
+        }
+        let tx = match tx {
+            Tx::Callback(_, timeout, mut on_done) => {
+                event_loop.clear_timeout(timeout);
+                on_done(self, event_loop, TxOutcome::Response(resp));
+                return Ok(())
+            }
+            tx => tx,
+        };
+
         let ref sender = resp.sender_id;
         match resp.response {
             Response::Pong => {
-                println!("pong from {:?}", resp.sender_id);
+                trace!(self, "pong from {:?}", resp.sender_id);
 
                 match tx {
-                    Tx::FirstPing(pinged_addr, timeout) => {
-                        if addr != &pinged_addr {
-                            return Err(io::Error::new(io::ErrorKind::InvalidData, "wrong addr"))
-                        }
+                    Tx::FirstPing(_, timeout) => {
                         event_loop.clear_timeout(timeout);
+                        let node = match Node4Info::from_addr(sender, addr) {
+                            Some(node) => node,
+                            None => return Err(Error::NotIpv4),
+                        };
 
                         // okay, we got the first-ping back from our peer.
-                        // try to add them to our routing table.
-                        if let Some(slot) = self.table.allocate(sender) {
-                            match *slot {
-                                Slot::Empty => {
-                                    *slot = Slot::Node(sender.clone(), NodeState::Good);
-                                    // set timeout here...
-                                }
-                                Slot::Node(_, ref mut state@NodeState::Pinging) => {
-                                    // XXX there's no way to *get* to here from a FirstPing!
-                                    //     or is there??? could be a collision...
-                                    *state = NodeState::Good;
-                                }
-                                Slot::Node(_, NodeState::Good) => {
-                                    // refresh timeout?
-                                    println!("first-pong: {:?} already Good", sender)
-                                }
-                            }
-                        } else {
-                            println!("first-pong from now-evicted {:?}", sender);
-                        }
+                        self.heard_from(node);
+                    }
+                    Tx::Bootstrap(_, timeout) => {
+                        event_loop.clear_timeout(timeout);
+                        let node = match Node4Info::from_addr(sender, addr) {
+                            Some(node) => node,
+                            None => return Err(Error::NotIpv4),
+                        };
+                        self.heard_from(node);
+                        return self.bootstrap_from(event_loop, node)
+                    }
+                    Tx::FindNode(_, timeout) | Tx::Lookup(_, _, timeout) | Tx::Probe(_, timeout) |
+                    Tx::Callback(_, timeout, _) => {
+                        event_loop.clear_timeout(timeout);
+                        return Ok(())
                     }
                 }
 
                 let target = NodeId::random();
-                println!("ask for {:?}", target);
-                self.send(event_loop, addr, Query::FindNode(target))
-            }
-            Response::FoundNodes {nodes4} => {
-                println!("found {} nodes...", nodes4.len());
-                for found_node in nodes4 {
-                    let ping: bool;
-                    if let Some(slot) = self.table.allocate(&found_node.id) {
-                        ping = slot.is_empty();
-                        if ping {
-                            println!("{:?} is new, will ping", found_node.id);
-                            *slot = Slot::Node(found_node.id, NodeState::Pinging);
-                        }
-                    } else {
-                        // no space for it, so just drop it
-                        ping = false;
+                trace!(self, "ask for {:?}", target);
+                self.send(event_loop, addr, Query::FindNode(target), Tx::FindNode)
+            }
+            Response::FoundNodes {nodes4} |
+            Response::Peers {nodes4, ..} |
+            Response::Samples {nodes4, ..} |
+            Response::Item {nodes4, ..} => {
+                event_loop.clear_timeout(tx.timeout());
+                trace!(self, "found {} nodes...", nodes4.len());
+                let policy = self.config.ping_policy;
+                // a poisoned reply could otherwise have us pinging ourselves
+                let nodes4: Vec<Node4Info> = nodes4.into_iter()
+                    .filter(|node| !self.is_us(node) && !self.is_banned(node))
+                    .collect();
+                let nodes4: Vec<Node4Info> = match policy {
+                    PingPolicy::BucketNotFull => {
+                        nodes4.into_iter().filter(|node| self.table.has_room_for(&node.id)).collect()
                     }
-                    if ping {
-                        self.send(event_loop, &found_node.peer.socket_addr(), Query::Ping)?
+                    PingPolicy::Eager | PingPolicy::Lazy => nodes4,
+                };
+                // any we have no space for are just dropped
+                for (found_node, is_new) in self.table.allocate_many(&nodes4) {
+                    if is_new && policy != PingPolicy::Lazy {
+                        trace!(self, "{:?} is new, will ping", found_node.id);
+                        // one ping we can't send shouldn't cost us the rest
+                        if let Err(e) = self.discovery_ping(event_loop, found_node.peer.socket_addr()) {
+                            println!("discovery: couldn't ping {:?}: {}", found_node.peer.socket_addr(), e);
+                        }
                     }
                 }
                 Ok(())
             }
         }
     }
-}
 
-enum Tx {
-    FirstPing(SocketAddr, Timeout),
-}
+    /// Pings a node we heard of secondhand, or queues it if `Config::max_discovery_pings`
+    /// are already in flight.
+    fn discovery_ping(&mut self, event_loop: &mut EventLoop<ServerHandler>, addr: SocketAddr)
+        -> error::Result<()>
+    {
+        if !self.discovering.insert(addr) {
+            return Ok(())
+        }
+        if self.discovery_pings >= self.config.max_discovery_pings {
+            if self.ping_queue.len() < MAX_QUEUED_PINGS {
+                self.ping_queue.push_back(addr);
+            } else {
+                trace!(self, "ping queue full, dropping {:?}", addr);
+                self.discovering.remove(&addr);
+            }
+            return Ok(())
+        }
+        self.first_ping(event_loop, addr)
+    }
 
-fn serve() -> io::Result<()> {
-    let ref my_addr = "0.0.0.0:6881".parse().unwrap(); // todo cast to io error
-    let sock = UdpSocket::bound(my_addr)?;
+    /// Sends the discovery ping `discovering` already lists `addr` for.
+    fn first_ping(&mut self, event_loop: &mut EventLoop<ServerHandler>, addr: SocketAddr) -> error::Result<()> {
+        if let Err(e) = self.send(event_loop, &addr, Query::Ping, Tx::FirstPing) {
+            self.discovering.remove(&addr);
+            return Err(e)
+        }
+        self.discovery_pings += 1;
+        Ok(())
+    }
 
-    let ref mut event_loop: EventLoop<ServerHandler> = EventLoop::new()?;
-    event_loop.register(&sock, SERVER, EventSet::readable(), PollOpt::edge())?;
+    /// Pings a node we don't know yet that just queried us, so it's filed like any other
+    /// discovery if it answers.
+    ///
+    /// Its query proves the address is live, but anyone can claim any ID in a query; BEP 5 has
+    /// us hear it in a response to our own ping before we route to it.
+    fn learn_from_querier(&mut self, event_loop: &mut EventLoop<ServerHandler>, sender_id: NodeId,
+                          addr: &SocketAddr) -> error::Result<()>
+    {
+        if !self.config.learn_from_queriers {
+            return Ok(())
+        }
+        let node = match Node4Info::from_addr(&sender_id, addr) {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        if self.is_us(&node) || self.is_banned(&node) || self.table.get(&sender_id).is_some() ||
+            !self.table.has_room_for(&sender_id)
+        {
+            return Ok(())
+        }
+        // don't ping it again for every query it sends before answering the first
+        if self.discovering.contains(addr) {
+            return Ok(())
+        }
+        trace!(self, "{:?} queried us, will ping", sender_id);
+        self.discovery_ping(event_loop, *addr)
+    }
+
+    /// Notes that the discovery ping to `addr` was answered or timed out, sending the next
+    /// queued one.
+    fn discovery_ping_done(&mut self, event_loop: &mut EventLoop<ServerHandler>, addr: &SocketAddr) {
+        self.discovering.remove(addr);
+        self.discovery_pings = self.discovery_pings.saturating_sub(1);
+        while self.discovery_pings < self.config.max_discovery_pings {
+            let addr = match self.ping_queue.pop_front() {
+                Some(addr) => addr,
+                None => break,
+            };
+            if let Err(e) = self.first_ping(event_loop, addr) {
+                println!("discovery: couldn't ping {:?}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Falls back to the DNS bootstrap nodes if too few of our saved contacts answered.
+    fn check_warm_start(&mut self, event_loop: &mut EventLoop<ServerHandler>) {
+        let good = self.table.good_count();
+        if good >= self.config.warm_start_min {
+            println!("warm start: {} contacts answered, skipping DNS bootstrap", good);
+            return
+        }
+        println!("warm start: only {} contacts answered, bootstrapping", good);
+        let bootstrap = match resolve(&*self.resolver, &self.config.bootstrap) {
+            Ok(bootstrap) => bootstrap,
+            Err(e) => {
+                println!("bootstrap: {}", e);
+                return
+            }
+        };
+        for addr in &bootstrap {
+            if let Err(e) = self.send(event_loop, addr, Query::Ping, Tx::Bootstrap) {
+                println!("bootstrap: couldn't ping {:?}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Ignores `ip` and every address sharing its first `prefix_len` bits for the rest of the
+    /// session, recording the ban in `Config::ban_file` if there is one.
+    fn ban(&mut self, ip: Ipv4Addr, prefix_len: u8) {
+        if !self.blacklist.ban(ip, prefix_len) {
+            return
+        }
+        println!("banned {}/{}", ip, prefix_len);
+        if let Some(ref path) = self.config.ban_file {
+            let saved = OpenOptions::new().append(true).create(true).open(path)
+                                          .and_then(|mut f| writeln!(f, "{}/{}", ip, prefix_len));
+            if let Err(e) = saved {
+                println!("ban: couldn't save to {}: {}", path, e);
+            }
+        }
+    }
+
+    /// Counts garbage from `addr` against it, banning it once it has sent too much.
+    fn strike(&mut self, addr: &SocketAddr) {
+        if self.blacklist.strike(&addr.ip()) {
+            if let IpAddr::V4(ip) = addr.ip() {
+                self.ban(ip, 32);
+            }
+        }
+    }
+
+    /// Feeds a bootstrap node that answered our ping into the one lookup for our own ID that
+    /// fills the table at startup, starting it on the first answer.
+    ///
+    /// With several bootstrap nodes, each pong would otherwise set off an identical lookup.
+    /// Answers that come in after the lookup has finished are just filed.
+    fn bootstrap_from(&mut self, event_loop: &mut EventLoop<ServerHandler>, node: Node4Info)
+        -> error::Result<()>
+    {
+        let id = match self.bootstrap_lookup {
+            Some(id) => id,
+            None => {
+                let target = *self.table.our_id();
+                let id = self.start_lookup(event_loop, target, &[], Purpose::Bootstrap)?;
+                self.bootstrap_lookup = Some(id);
+                id
+            }
+        };
+        match self.lookups.get_mut(&id) {
+            Some(lookup) => lookup.add(node),
+            None => return Ok(()),
+        }
+        self.advance_lookup(event_loop, id)
+    }
+
+    /// Pings a known node to check it's still alive: at `addr` if given, say because we heard
+    /// out-of-band that it moved, or else where our table has it. How it went is logged and
+    /// sent to the control client at `client`.
+    ///
+    /// If it answers with the right ID it's marked good again. An answer from a new address
+    /// updates our table or not, as `Config::address_changes` says. If it doesn't answer, or
+    /// answers under another ID, it's dropped from our table.
+    fn reverify(&mut self, event_loop: &mut EventLoop<ServerHandler>, node_id: NodeId,
+                addr: Option<SocketAddr>, client: SocketAddr) -> error::Result<()>
+    {
+        let stored = match self.table.get(&node_id) {
+            Some((peer, _, _)) => peer.socket_addr(),
+            None => return Err(Error::UnknownNode),
+        };
+        let dest = addr.unwrap_or(stored);
+        let on_done = Box::new(move |handler: &mut ServerHandler, _: &mut EventLoop<ServerHandler>,
+                                     outcome: TxOutcome| {
+            let report = match outcome {
+                TxOutcome::Response(resp) => {
+                    if resp.sender_id == node_id {
+                        handler.reverified(&node_id, &dest);
+                        format!("reverify: {:?} answered at {:?}", node_id, dest)
+                    } else {
+                        handler.table.remove(&node_id);
+                        format!("reverify: {:?} answered as {:?}, not {:?}; forgot it", dest, resp.sender_id, node_id)
+                    }
+                }
+                TxOutcome::Error(e) => format!("reverify: {:?} refused our ping: {}", dest, e.message),
+                TxOutcome::TimedOut => {
+                    handler.table.remove(&node_id);
+                    format!("reverify: {:?} didn't answer at {:?}; forgot it", node_id, dest)
+                }
+            };
+            println!("{}", report);
+            handler.control_reply(&report, &client);
+        });
+        self.send_with(event_loop, &dest, Query::Ping, on_done)
+    }
+
+    /// Records that `node_id` answered a `reverify` ping from `addr`.
+    fn reverified(&mut self, node_id: &NodeId, addr: &SocketAddr) {
+        let peer = match Peer4Info::from_socket_addr(addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let policy = self.config.address_changes;
+        if let Some(entry) = self.table.entry_mut(node_id) {
+            if entry.info.peer != peer {
+                match policy {
+                    AddressChangePolicy::Accept => {
+                        println!("{:?} moved from {:?} to {:?}", node_id, entry.info.peer, peer);
+                        entry.info.peer = peer;
+                    }
+                    AddressChangePolicy::Reject => {
+                        println!("{:?} answered from {:?}, not {:?}; keeping the old address",
+                                 node_id, peer, entry.info.peer);
+                        return
+                    }
+                }
+            }
+            entry.last_seen = Instant::now();
+            entry.state = NodeState::Good;
+        }
+    }
+
+    /// Whether `node` is really us, going by its ID or by an address we know we have.
+    fn is_us(&self, node: &Node4Info) -> bool {
+        let addr = node.peer.socket_addr();
+        &node.id == self.table.our_id() ||
+            addr == self.local_addr ||
+            self.reachability.external().map_or(false, |external| addr == SocketAddr::V4(external))
+    }
+
+    fn is_banned(&self, node: &Node4Info) -> bool {
+        self.blacklist.is_banned(&node.peer.socket_addr().ip())
+    }
+
+    /// Tries to add a node that just answered us to our routing table.
+    fn heard_from(&mut self, node: Node4Info) {
+        if self.is_banned(&node) {
+            return
+        }
+        let added = if let Some(slot) = self.table.allocate(&node.id) {
+            match *slot {
+                Slot::Empty => {
+                    *slot = Slot::Node(Entry::new(node, NodeState::Good));
+                    // set timeout here...
+                    true
+                }
+                Slot::Node(ref mut entry) => {
+                    entry.last_seen = Instant::now();
+                    match entry.state {
+                        NodeState::Pinging => {
+                            // XXX there's no way to *get* to here from a FirstPing!
+                            //     or is there??? could be a collision...
+                            entry.state = NodeState::Good;
+                            true
+                        }
+                        NodeState::Good => {
+                            // refresh timeout?
+                            trace!(self, "{:?} already Good", node.id);
+                            false
+                        }
+                    }
+                }
+            }
+        } else {
+            println!("no room in our table for {:?}", node.id);
+            false
+        };
+        if added {
+            self.emit(Event::NodeAdded {id: &node.id, addr: &node.peer.socket_addr()});
+        }
+    }
+
+    /// Launches a lookup in the range of the single stalest bucket, if any is due.
+    ///
+    /// Called once per tick, so refresh traffic trickles out rather than bursting after idle spells.
+    fn refresh_stalest(&mut self, event_loop: &mut EventLoop<ServerHandler>) -> error::Result<()> {
+        let max_age = Duration::from_secs(table::REFRESH_AFTER_SECS);
+        match self.table.stalest_bucket(max_age) {
+            Some(index) => self.refresh_bucket(event_loop, index).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Refreshes bucket `index` now, stale or not, with a `find_node` lookup for a random ID
+    /// in its range.
+    ///
+    /// Returns the lookup, or `None` if we know no nodes to start it from.
+    fn refresh_bucket(&mut self, event_loop: &mut EventLoop<ServerHandler>, index: usize)
+        -> error::Result<Option<LookupId>>
+    {
+        if index >= self.table.bucket_count() {
+            return Err(Error::NoSuchBucket(index))
+        }
+        self.table.touch_bucket(index);
+        let target = self.table.random_id_in_bucket(index);
+        let seeds: Vec<SocketAddr> = self.table.find_closest(&target, table::K)
+                                               .iter().map(|node| node.peer.socket_addr()).collect();
+        if seeds.is_empty() {
+            return Ok(None)
+        }
+        println!("refreshing bucket {}", index);
+        self.start_lookup(event_loop, target, &seeds, Purpose::FindNodes).map(Some)
+    }
+
+    /// Looks up our own ID starting from our closest neighbours, so we learn of any nodes that
+    /// have joined nearer to us. Bucket refresh never targets our ID itself, and it's our
+    /// neighbours that `get_peers` and BEP 44 placement depend on.
+    fn refresh_self(&mut self, event_loop: &mut EventLoop<ServerHandler>) -> error::Result<()> {
+        if self.purposes.values().any(|&purpose| purpose == Purpose::SelfRefresh) {
+            return Ok(()) // the last one's still running
+        }
+        let neighbours = self.table.neighborhood(table::K);
+        if neighbours.is_empty() {
+            return Ok(())
+        }
+        println!("self-refresh: looking up our own ID");
+        self.self_refresh_baseline = neighbours.iter().map(|node| node.id).collect();
+        let seeds: Vec<SocketAddr> = neighbours.iter().map(|node| node.peer.socket_addr()).collect();
+        let target = *self.table.our_id();
+        self.start_lookup(event_loop, target, &seeds, Purpose::SelfRefresh).map(|_| ())
+    }
+
+    /// Announces us as a peer for `info_hash` on `port`, starting from `seeds`, and keeps
+    /// doing so every `Config::reannounce_interval_ms` until `cancel_announce`. With `seed`,
+    /// we announce that we have the whole torrent.
+    ///
+    /// The returned ID is the handle for cancelling. Rounds beyond
+    /// `Config::max_concurrent_announces` wait their turn.
+    fn announce(&mut self, event_loop: &mut EventLoop<ServerHandler>, info_hash: NodeId, port: u16,
+                seed: bool, seeds: &[SocketAddr]) -> error::Result<AnnounceId>
+    {
+        let id = self.next_announce;
+        self.next_announce += 1;
+        self.announces.insert(id, Announce::new(info_hash, port, seed));
+        self.run_or_queue_announce(event_loop, id, seeds.to_vec())?;
+        Ok(id)
+    }
+
+    /// How many announce rounds have a lookup running.
+    fn running_announces(&self) -> usize {
+        self.purposes.values().filter(|purpose| match **purpose {
+            Purpose::Announce(_) => true,
+            _ => false,
+        }).count()
+    }
+
+    /// Starts a round of announce `id` if there's a free slot, or else queues it.
+    fn run_or_queue_announce(&mut self, event_loop: &mut EventLoop<ServerHandler>, id: AnnounceId,
+                             seeds: Vec<SocketAddr>) -> error::Result<()>
+    {
+        if self.running_announces() >= self.config.max_concurrent_announces {
+            self.announce_queue.push_back((id, seeds));
+            return Ok(())
+        }
+        self.start_announce_round(event_loop, id, seeds)
+    }
+
+    /// Starts queued announce rounds while there are free slots.
+    fn drain_announce_queue(&mut self, event_loop: &mut EventLoop<ServerHandler>) {
+        while self.running_announces() < self.config.max_concurrent_announces {
+            let (id, seeds) = match self.announce_queue.pop_front() {
+                Some(queued) => queued,
+                None => break,
+            };
+            if let Err(e) = self.start_announce_round(event_loop, id, seeds) {
+                println!("announce: {}", e);
+            }
+        }
+    }
+
+    /// The K good nodes closest to `target` that our table already knows, nearest first.
+    ///
+    /// A quick best-effort answer with no network traffic; `start_lookup` is the thorough one.
+    fn closest_cached(&self, target: &NodeId) -> Vec<Node4Info> {
+        self.table.find_closest(target, table::K)
+    }
+
+    /// Peers for `info_hash`, straight from the cache if a recent lookup found some.
+    ///
+    /// On a miss this returns `None` and starts a `get_peers` lookup from our table, whose
+    /// peers are cached when it finishes and sent to the control client `waiter`, or fails
+    /// with `NoNodes` if the table is empty. With `refresh`, a hit starts one too, so the
+    /// cache stays current for next time.
+    ///
+    /// A `get_peers` lookup already running for `info_hash`, ours or an announce's, stands in
+    /// for a new one: `waiter` joins whoever else is waiting on it.
+    fn get_peers(&mut self, event_loop: &mut EventLoop<ServerHandler>, info_hash: NodeId,
+                 refresh: bool, waiter: SocketAddr) -> error::Result<Option<Vec<Peer4Info>>>
+    {
+        let cached = self.peer_cache.get(&info_hash);
+        if cached.is_some() && !refresh {
+            return Ok(cached)
+        }
+        let id = match self.peer_lookup_for(&info_hash) {
+            Some(id) => {
+                trace!(self, "get_peers for {:?} joins lookup {}", info_hash, id);
+                id
+            }
+            None => {
+                let seeds: Vec<SocketAddr> = self.table.find_closest(&info_hash, table::K)
+                                                       .iter().map(|node| node.peer.socket_addr()).collect();
+                match self.start_lookup(event_loop, info_hash, &seeds, Purpose::FindPeers) {
+                    Ok(id) => id,
+                    // the cached peers will have to do
+                    Err(Error::NoNodes) if cached.is_some() => return Ok(cached),
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        if cached.is_none() {
+            let waiters = self.peer_waiters.entry(id).or_insert_with(Vec::new);
+            if !waiters.contains(&waiter) {
+                waiters.push(waiter);
+            }
+        }
+        Ok(cached)
+    }
+
+    /// Sends the peers lookup `id` found to every control client waiting on it.
+    fn answer_peer_waiters(&mut self, id: LookupId, peers: &[Peer4Info]) {
+        if let Some(waiters) = self.peer_waiters.remove(&id) {
+            let text = peer_list(peers);
+            for waiter in &waiters {
+                self.control_reply(&text, waiter);
+            }
+        }
+    }
+
+    /// The running lookup asking `get_peers` about `info_hash`, if there is one.
+    fn peer_lookup_for(&self, info_hash: &NodeId) -> Option<LookupId> {
+        self.purposes.iter()
+            .filter(|&(_, purpose)| match *purpose {
+                Purpose::FindPeers | Purpose::Announce(_) => true,
+                _ => false,
+            })
+            .map(|(&id, _)| id)
+            .find(|id| self.lookups.get(id).map_or(false, |lookup| lookup.target() == info_hash))
+    }
+
+    /// Stops renewing an announce, returning false if there was no such announce. Nodes we've
+    /// already announced to forget us in their own time.
+    fn cancel_announce(&mut self, event_loop: &mut EventLoop<ServerHandler>, id: AnnounceId) -> bool {
+        match self.announces.remove(&id) {
+            Some(announce) => {
+                if let Some(timeout) = announce.next {
+                    event_loop.clear_timeout(timeout);
+                }
+                // a round already in progress finds the announce gone when its lookup finishes
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Starts the next round of a standing announce, seeded from our own table.
+    fn run_announce(&mut self, event_loop: &mut EventLoop<ServerHandler>, id: AnnounceId)
+        -> error::Result<()>
+    {
+        match self.announces.get_mut(&id) {
+            Some(announce) => announce.next = None,
+            None => return Ok(()),
+        }
+        self.run_or_queue_announce(event_loop, id, vec![])
+    }
+
+    /// Starts the `get_peers` lookup for a round of announce `id`, from `seeds` or, if there
+    /// are none, from our table. Does nothing if the announce has been cancelled.
+    fn start_announce_round(&mut self, event_loop: &mut EventLoop<ServerHandler>, id: AnnounceId,
+                            seeds: Vec<SocketAddr>) -> error::Result<()>
+    {
+        let info_hash = match self.announces.get(&id) {
+            Some(announce) => announce.info_hash,
+            None => return Ok(()),
+        };
+        let seeds = if seeds.is_empty() {
+            self.table.find_closest(&info_hash, table::K).iter().map(|node| node.peer.socket_addr()).collect()
+        } else {
+            seeds
+        };
+        if seeds.is_empty() {
+            println!("announce: no nodes to start from, will try again later");
+            self.schedule_announce(event_loop, id);
+            return Ok(())
+        }
+        self.start_lookup(event_loop, info_hash, &seeds, Purpose::Announce(id)).map(|_| ())
+    }
+
+    /// Sends `announce_peer` to the nodes a finished `get_peers` lookup got tokens from,
+    /// then schedules the next round.
+    fn finish_announce(&mut self, event_loop: &mut EventLoop<ServerHandler>, id: AnnounceId,
+                       result: LookupResult)
+    {
+        let (info_hash, port, seed) = match self.announces.get(&id) {
+            Some(announce) => (announce.info_hash, announce.port, announce.seed),
+            None => return, // cancelled while the lookup ran
+        };
+        println!("announcing {:?} to {} nodes", info_hash, result.closest_with_tokens.len());
+        for (node, token) in result.closest_with_tokens {
+            let query = Query::AnnouncePeer {
+                info_hash: info_hash,
+                port: port,
+                implied_port: false,
+                token: token,
+                seed: seed,
+            };
+            let addr = node.peer.socket_addr();
+            let on_done = Box::new(move |_: &mut ServerHandler, _: &mut EventLoop<ServerHandler>,
+                                         outcome: TxOutcome| {
+                match outcome {
+                    TxOutcome::Response(resp) => {
+                        println!("announced {:?} to {:?} at {:?}", info_hash, resp.sender_id, addr)
+                    }
+                    TxOutcome::Error(e) => println!("{:?} refused our announce: {}", addr, e.message),
+                    TxOutcome::TimedOut => println!("announce to {:?} went unanswered", addr),
+                }
+            });
+            if let Err(e) = self.send_with(event_loop, &addr, query, on_done) {
+                println!("announce: couldn't reach {:?}: {}", addr, e);
+            }
+        }
+        self.schedule_announce(event_loop, id);
+    }
+
+    fn schedule_announce(&mut self, event_loop: &mut EventLoop<ServerHandler>, id: AnnounceId) {
+        let timeout = event_loop.timeout_ms(Timer::Reannounce(id), self.config.reannounce_interval_ms)
+                                .unwrap();
+        if let Some(announce) = self.announces.get_mut(&id) {
+            announce.next = Some(timeout);
+        }
+    }
+
+    /// While we can't yet tell whether we're reachable, pings a few of our neighbours so the
+    /// `ip` in their replies tells us how they see us.
+    fn probe_reachability(&mut self, event_loop: &mut EventLoop<ServerHandler>) -> error::Result<()> {
+        if self.is_reachable().is_some() {
+            return Ok(())
+        }
+        for node in self.table.neighborhood(reachability::MIN_REPORTS) {
+            self.send_to(event_loop, &node, Query::Ping, Tx::Probe)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the peers announced to us to `Config::peer_store_file`, if there is one.
+    fn save_peer_store(&mut self) -> error::Result<()> {
+        let path = match self.config.peer_store_file {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let bytes = self.peers.export().to_bytes()?;
+        // write beside it and swap it in, so a crash mid-write can't lose the old copy
+        let tmp = format!("{}.tmp", path);
+        File::create(&tmp).and_then(|mut f| f.write_all(&bytes))?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Writes our good nodes to `Config::contacts_file`, if there is one, in the form
+    /// `read_contacts` reads.
+    fn save_contacts(&self) -> error::Result<()> {
+        let path = match self.config.contacts_file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut text = String::new();
+        for (id, addr) in self.table.export_contacts() {
+            text.push_str(&format!("{} {}\n", id.to_hex(), addr));
+        }
+        let tmp = format!("{}.tmp", path);
+        File::create(&tmp).and_then(|mut f| f.write_all(text.as_bytes()))?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Whether nodes outside can reach us on our bound port, once enough have told us.
+    fn is_reachable(&self) -> Option<bool> {
+        self.reachability.is_reachable()
+    }
+
+    /// Whether other nodes have queried us lately, as they could only if they can reach us.
+    fn inbound_observed(&self) -> bool {
+        self.reachability.inbound_observed()
+    }
+
+    fn stats(&self) -> Stats {
+        let now = Instant::now();
+        Stats {
+            buckets: self.table.bucket_count(),
+            transactions: self.txs.len(),
+            tx_ids_issued: self.tx_ids.issued(),
+            tx_id_collisions: self.tx_ids.collisions(),
+            lookups: self.lookups.len(),
+            infohashes: self.peers.infohashes().len(),
+            reachable: self.is_reachable(),
+            inbound_observed: self.inbound_observed(),
+            next_refresh: if self.refresh_due > now { self.refresh_due - now } else { Duration::from_secs(0) },
+            decode_errors: self.decode_errors.clone(),
+            truncated_datagrams: self.truncated_datagrams,
+        }
+    }
+
+    /// Counts for the periodic summary line; cheaper than `stats`.
+    fn summary(&self) -> Summary {
+        let good = self.table.good_count();
+        let total = self.table.node_count();
+        Summary {
+            buckets: self.table.bucket_count(),
+            good: good,
+            questionable: total - good,
+            total: total,
+            txs: self.txs.len(),
+        }
+    }
+
+    /// Every query of ours still awaiting a reply, for working out why a lookup is stuck.
+    fn pending_transactions(&self) -> Vec<TxInfo> {
+        let now = Instant::now();
+        self.txs.iter().map(|(tx_id, pending)| {
+            TxInfo {
+                tx_id: tx_id.clone(),
+                addr: pending.tx.addr(),
+                method: pending.method,
+                purpose: pending.tx.purpose(),
+                lookup: match pending.tx {
+                    Tx::Lookup(id, _, _) => Some(id),
+                    _ => None,
+                },
+                retries: pending.retries,
+                expires_in: if pending.deadline > now { pending.deadline - now } else { Duration::from_secs(0) },
+            }
+        }).collect()
+    }
+
+    /// Sums up how well connected we are; see `Health` for what counts as good.
+    fn health(&self) -> Health {
+        Health::assess(self.table.occupied_buckets(), self.table.home_bucket_len(),
+                       self.table.good_count(), self.table.node_count(),
+                       self.last_response.map(|at| at.elapsed()))
+    }
+
+    /// Begins an iterative search for the nodes closest to `target`, starting from `seeds`.
+    ///
+    /// `purpose` decides whether it asks `find_node` or `get_peers`, and what becomes of the result.
+    /// Without seeds there's no one to ask, so this fails with `NoNodes`; only the bootstrap
+    /// lookup starts empty, as `bootstrap_from` feeds it nodes.
+    fn start_lookup(&mut self, event_loop: &mut EventLoop<ServerHandler>, target: NodeId,
+                    seeds: &[SocketAddr], purpose: Purpose) -> error::Result<LookupId>
+    {
+        if seeds.is_empty() && purpose != Purpose::Bootstrap {
+            return Err(Error::NoNodes)
+        }
+        let id = self.next_lookup;
+        self.next_lookup += 1;
+        self.purposes.insert(id, purpose);
+        let deadline = event_loop.timeout_ms(Timer::Lookup(id), self.config.lookup_timeout_ms).unwrap();
+        let alpha = match purpose {
+            Purpose::Bootstrap | Purpose::SelfRefresh => self.config.bootstrap_alpha,
+            Purpose::FindPeers | Purpose::Announce(_) => self.config.get_peers_alpha,
+            Purpose::FindNodes => self.config.find_node_alpha,
+        };
+        self.lookups.insert(id, Lookup::new(target, deadline, self.config.max_lookup_iterations, alpha,
+                                            self.config.max_lookup_queries));
+        println!("lookup for {:?} started", target);
+        self.emit(Event::LookupStarted {target: &target});
+
+        for seed in seeds {
+            let query = self.lookup_query(id, target);
+            let sent = self.send(event_loop, seed, query, move |a, t| Tx::Lookup(id, a, t));
+            let lookup = self.lookups.get_mut(&id).unwrap();
+            // counted either way, so `failed` has a query in flight to take back
+            lookup.sent();
+            if let Err(e) = sent {
+                println!("lookup: couldn't query {:?}: {}", seed, e);
+                lookup.failed(seed);
+            }
+        }
+        Ok(id)
+    }
+
+    /// Sends the lookup's next queries, or wraps it up if it has converged.
+    fn advance_lookup(&mut self, event_loop: &mut EventLoop<ServerHandler>, id: LookupId)
+        -> error::Result<()>
+    {
+        let exhausted = match self.lookups.get(&id) {
+            Some(lookup) if lookup.exhausted() => Some((lookup.iterations(), lookup.deadline())),
+            Some(_) => None,
+            None => return Ok(()),
+        };
+        if let Some((iterations, deadline)) = exhausted {
+            println!("lookup {}: cut off after {} iterations", id, iterations);
+            event_loop.clear_timeout(deadline);
+            self.finish_lookup(event_loop, id, Outcome::IterationLimit);
+            return Ok(())
+        }
+
+        loop {
+            let (next, target) = match self.lookups.get_mut(&id) {
+                Some(lookup) => (lookup.next_to_query(), *lookup.target()),
+                None => return Ok(()),
+            };
+            let node = match next {
+                Some(node) => node,
+                None => break,
+            };
+            let addr = node.peer.socket_addr();
+            let query = self.lookup_query(id, target);
+            let sent = self.send_to(event_loop, &node, query, move |a, t| Tx::Lookup(id, a, t));
+            match sent {
+                Ok(()) => {
+                    event_loop.timeout_ms(Timer::Stall(id, addr), self.config.lookup_stall_ms).unwrap();
+                }
+                Err(e) => {
+                    println!("lookup: couldn't query {:?}: {}", addr, e);
+                    self.lookups.get_mut(&id).unwrap().failed(&addr);
+                }
+            }
+        }
+
+        if self.lookups[&id].is_done() {
+            event_loop.clear_timeout(self.lookups[&id].deadline());
+            self.finish_lookup(event_loop, id, Outcome::Converged);
+        } else if self.lookups[&id].out_of_budget() {
+            println!("lookup {}: out of queries", id);
+            event_loop.clear_timeout(self.lookups[&id].deadline());
+            self.finish_lookup(event_loop, id, Outcome::QueryBudget);
+        }
+        Ok(())
+    }
+
+    fn lookup_query(&self, id: LookupId, target: NodeId) -> Query {
+        match self.purposes.get(&id) {
+            Some(&Purpose::FindPeers) => Query::GetPeers {info_hash: target, noseed: false},
+            Some(&Purpose::Announce(announce)) => {
+                // a seed has no use for other seeds
+                let noseed = self.announces.get(&announce).map_or(false, |a| a.seed);
+                Query::GetPeers {info_hash: target, noseed: noseed}
+            }
+            Some(&Purpose::Bootstrap) | Some(&Purpose::SelfRefresh) | Some(&Purpose::FindNodes) |
+            None => Query::FindNode(target),
+        }
+    }
+
+    /// Reports the lookup's results, partial or not, and forgets it.
+    fn finish_lookup(&mut self, event_loop: &mut EventLoop<ServerHandler>, id: LookupId,
+                     outcome: Outcome)
+    {
+        let lookup = self.lookups.remove(&id).unwrap();
+        let closest = lookup.closest();
+        println!("lookup for {:?} {}; closest nodes:", lookup.target(), outcome);
+        for node in &closest {
+            println!("    {} {}", node.id.to_hex(), node.peer.socket_addr());
+        }
+        self.emit(Event::LookupFinished {target: lookup.target(), nodes: &closest, outcome: outcome});
+        match self.purposes.remove(&id) {
+            Some(Purpose::FindPeers) => {
+                let result = lookup.result();
+                println!("{} peers for {:?}", result.peers.len(), lookup.target());
+                self.emit(Event::PeersFound {target: lookup.target(), peers: &result.peers});
+                self.answer_peer_waiters(id, &result.peers);
+                self.peer_cache.insert(*lookup.target(), result.peers);
+            }
+            Some(Purpose::Announce(announce)) => {
+                let result = lookup.result();
+                println!("{} peers for {:?}", result.peers.len(), lookup.target());
+                self.emit(Event::PeersFound {target: lookup.target(), peers: &result.peers});
+                self.answer_peer_waiters(id, &result.peers);
+                self.peer_cache.insert(*lookup.target(), result.peers.clone());
+                self.finish_announce(event_loop, announce, result);
+                self.drain_announce_queue(event_loop);
+            }
+            Some(Purpose::SelfRefresh) => {
+                let new = {
+                    let before = &self.self_refresh_baseline;
+                    self.table.neighborhood(table::K).iter().filter(|node| !before.contains(&node.id)).count()
+                };
+                println!("self-refresh: {} new neighbours", new);
+                self.self_refresh_baseline.clear();
+            }
+            Some(Purpose::Bootstrap) | Some(Purpose::FindNodes) | None => (),
+        }
+        if self.find_lookup == Some(id) {
+            if outcome == Outcome::TimedOut {
+                println!("find: timed out after {}s; these are only the closest nodes found so far",
+                         self.config.lookup_timeout_ms / 1000);
+            }
+            event_loop.shutdown();
+        }
+    }
+
+    /// Runs every command waiting on the control socket, replying to each.
+    fn control_ready(&mut self, event_loop: &mut EventLoop<ServerHandler>) {
+        let mut buf = [0u8; 1024];
+        loop {
+            let received = match self.control {
+                Some(ref control) => control.recv_from(&mut buf),
+                None => return,
+            };
+            match received {
+                Ok(Some((len, from))) => {
+                    let reply = match str::from_utf8(&buf[..len]) {
+                        Ok(line) => match Command::parse(line) {
+                            Ok(command) => self.command(event_loop, command, from),
+                            Err(e) => Some(e),
+                        },
+                        Err(_) => Some("commands are UTF-8 text".to_string()),
+                    };
+                    if let Some(reply) = reply {
+                        self.control_reply(&reply, &from);
+                    }
+                }
+                Ok(None) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("control: {}", e);
+                    break
+                }
+            }
+        }
+    }
+
+    /// Sends `text` to a control client. It's on this host, so no need to mind fragmentation.
+    fn control_reply(&self, text: &str, to: &SocketAddr) {
+        let bytes = &text.as_bytes()[..cmp::min(text.len(), control::MAX_REPLY_LEN)];
+        if let Some(ref control) = self.control {
+            if let Err(e) = control.send_to(bytes, to) {
+                println!("control: couldn't reply to {}: {}", to, e);
+            }
+        }
+    }
+
+    /// Carries out `command` for the control client at `client`, returning what to tell it,
+    /// or `None` if the answer is sent once it's known.
+    fn command(&mut self, event_loop: &mut EventLoop<ServerHandler>, command: Command, client: SocketAddr)
+        -> Option<String>
+    {
+        let reply = match command {
+            Command::Help => control::HELP.to_string(),
+            Command::Status => format!("{}\n{:?}\n{:?}", self.summary(), self.stats(), self.health()),
+            Command::Buckets => {
+                let mut text = String::new();
+                for (i, bucket) in self.table.bucket_summaries().iter().enumerate() {
+                    text.push_str(&format!("{:03}: good={} questionable={} empty={} changed {}s ago\n", i,
+                                           bucket.good, bucket.questionable, bucket.empty,
+                                           bucket.since_changed.as_secs()));
+                }
+                text
+            }
+            Command::Txs => {
+                let txs = self.pending_transactions();
+                let mut text = format!("{} transactions", txs.len());
+                for tx in txs {
+                    text.push_str(&format!("\n{}", tx));
+                }
+                text
+            }
+            Command::Closest(target) => {
+                let closest = self.closest_cached(&target);
+                let mut text = format!("{} nodes", closest.len());
+                for node in closest {
+                    text.push_str(&format!("\n{} {}", node.id.to_hex(), node.peer.socket_addr()));
+                }
+                text
+            }
+            Command::Reverify(node_id, addr) => {
+                match self.reverify(event_loop, node_id, addr, client) {
+                    Ok(()) => return None,
+                    Err(e) => format!("reverify: {}", e),
+                }
+            }
+            Command::Peers(info_hash, refresh) => {
+                match self.get_peers(event_loop, info_hash, refresh, client) {
+                    Ok(Some(peers)) => peer_list(&peers),
+                    Ok(None) => return None,
+                    Err(e) => format!("peers: {}", e),
+                }
+            }
+            Command::Announce(info_hash, port, seed) => {
+                match self.announce(event_loop, info_hash, port, seed, &[]) {
+                    Ok(id) => format!("announce {} started", id),
+                    Err(e) => format!("announce: {}", e),
+                }
+            }
+            Command::Announces => {
+                let mut ids: Vec<&AnnounceId> = self.announces.keys().collect();
+                ids.sort();
+                let mut text = format!("{} announces", ids.len());
+                for id in ids {
+                    let announce = &self.announces[id];
+                    text.push_str(&format!("\n{} {}:{}{}", id, announce.info_hash.to_hex(), announce.port,
+                                           if announce.seed { " seed" } else { "" }));
+                }
+                text
+            }
+            Command::Cancel(id) => {
+                if self.cancel_announce(event_loop, id) {
+                    format!("announce {} cancelled", id)
+                } else {
+                    format!("no announce {}", id)
+                }
+            }
+        };
+        Some(reply)
+    }
+
+    /// Writes `event` to the JSON event stream, if one is attached.
+    fn emit(&mut self, event: Event) {
+        if let Some(ref mut out) = self.events {
+            if let Err(e) = writeln!(out, "{}", event.to_json()).and_then(|()| out.flush()) {
+                println!("events: {}", e);
+            }
+        }
+    }
+}
+
+/// What a lookup is for.
+#[derive(Clone, Copy, PartialEq)]
+enum Purpose {
+    /// `find_node` for our own ID, to fill the table at startup.
+    Bootstrap,
+    /// `find_node` for our own ID, every `Config::self_refresh_ms`.
+    SelfRefresh,
+    /// `find_node`, for a bucket refresh or `--find`.
+    FindNodes,
+    /// `get_peers`, to learn who's downloading the infohash; see `ServerHandler::get_peers`.
+    FindPeers,
+    /// `get_peers` for a round of this announce.
+    Announce(AnnounceId),
+}
 
-    let ref bootstrap_addr = "212.129.33.50:6881".parse().unwrap(); // dht.transmissionbt.com
+/// Everything we set mio timers for.
+enum Timer {
+    /// Transaction went unanswered.
+    Tx(TxId),
+    /// Overall deadline for a `Lookup`, after which it returns what it has.
+    Lookup(LookupId),
+    /// A lookup's query to this address is taking a while; consider replacing it.
+    Stall(LookupId, SocketAddr),
+    /// Time for another round of this announce.
+    Reannounce(AnnounceId),
+    /// Time to refresh the stalest bucket, if any.
+    Refresh,
+    /// Time to look up our own ID again.
+    SelfRefresh,
+    /// Time to log another `Summary` line.
+    Summary,
+    /// Time to process a datagram the `Impairment` held back.
+    Deliver(u64),
+    /// Time for another attempt at replacing a broken socket.
+    Rebind,
+    /// Time to see whether enough saved contacts answered, or we need DNS bootstrap after all.
+    WarmStart,
+}
+
+enum Tx {
+    /// `ping` to a bootstrap node or saved contact; see `ServerHandler::bootstrap_from`.
+    Bootstrap(SocketAddr, Timeout),
+    FirstPing(SocketAddr, Timeout),
+    /// `find_node` sent while filling our table after a first pong.
+    FindNode(SocketAddr, Timeout),
+    /// `find_node` or `get_peers` sent on behalf of a `Lookup`.
+    Lookup(LookupId, SocketAddr, Timeout),
+    /// `ping` sent to learn how the node sees our address.
+    Probe(SocketAddr, Timeout),
+    /// Any query whose sender wants to hear how it went; see `ServerHandler::send_with`.
+    Callback(SocketAddr, Timeout, Completion),
+}
+
+/// A `Tx` in flight, with what `pending_transactions` reports about it.
+struct Pending {
+    tx: Tx,
+    /// What we asked, kept to resend after a server error.
+    query: Query,
+    /// KRPC method of the query.
+    method: &'static str,
+    sent: Instant,
+    /// When its `Timer::Tx` fires.
+    deadline: Instant,
+    /// ID of the node we queried, if we knew it.
+    expected: Option<NodeId>,
+    /// Times this query was resent after a server error.
+    retries: u32,
+}
+
+/// How a transaction ended.
+enum TxOutcome {
+    Response(FullResponse),
+    Error(DhtError),
+    TimedOut,
+}
+
+/// Vets a query before we serve it: `Err` holds the error code and message to refuse it with.
+type QueryPolicy = Box<FnMut(&FullQuery, &SocketAddr) -> Result<(), (u32, String)>>;
+
+/// Called once with a transaction's outcome. It gets the handler back, so it can carry on
+/// with whatever the query was for.
+type Completion = Box<FnMut(&mut ServerHandler, &mut EventLoop<ServerHandler>, TxOutcome)>;
+
+impl Tx {
+    /// Where the query went; only a reply from there counts.
+    fn addr(&self) -> SocketAddr {
+        match *self {
+            Tx::Bootstrap(addr, _) | Tx::FirstPing(addr, _) | Tx::FindNode(addr, _) | Tx::Lookup(_, addr, _) |
+            Tx::Probe(addr, _) | Tx::Callback(addr, _, _) => addr,
+        }
+    }
+
+    /// Short name for what the query was sent for.
+    fn purpose(&self) -> &'static str {
+        match *self {
+            Tx::Bootstrap(..) => "bootstrap",
+            Tx::FirstPing(..) => "first_ping",
+            Tx::FindNode(..) => "find_node",
+            Tx::Lookup(..) => "lookup",
+            Tx::Probe(..) => "probe",
+            Tx::Callback(..) => "callback",
+        }
+    }
+
+    /// The same transaction, now waiting on `timeout`; for a resent query.
+    fn rearmed(self, timeout: Timeout) -> Tx {
+        match self {
+            Tx::Bootstrap(addr, _) => Tx::Bootstrap(addr, timeout),
+            Tx::FirstPing(addr, _) => Tx::FirstPing(addr, timeout),
+            Tx::FindNode(addr, _) => Tx::FindNode(addr, timeout),
+            Tx::Lookup(id, addr, _) => Tx::Lookup(id, addr, timeout),
+            Tx::Probe(addr, _) => Tx::Probe(addr, timeout),
+            Tx::Callback(addr, _, on_done) => Tx::Callback(addr, timeout, on_done),
+        }
+    }
+
+    fn timeout(&self) -> Timeout {
+        match *self {
+            Tx::Bootstrap(_, timeout) | Tx::FirstPing(_, timeout) | Tx::FindNode(_, timeout) | Tx::Lookup(_, _, timeout) |
+            Tx::Probe(_, timeout) | Tx::Callback(_, timeout, _) => timeout,
+        }
+    }
+}
 
-    let my_id = NodeId::random();
-    let ref mut handler = ServerHandler {
-        sock: sock,
-        table: Table::new(my_id),
-        txs: HashMap::new(),
+/// A control reply listing `peers`, one `ADDR:PORT` per line.
+fn peer_list(peers: &[Peer4Info]) -> String {
+    let mut text = format!("{} peers", peers.len());
+    for peer in peers {
+        text.push_str(&format!("\n{}", peer.socket_addr()));
+    }
+    text
+}
+
+/// Reads saved contacts, one `ADDR:PORT` per line; blank lines and `#` comments are skipped.
+///
+/// A line may start with the node's hex ID, as `--save-contacts` writes them. We only check
+/// it's well-formed: the node may have a new ID by now, and its answer to our ping says.
+fn read_contacts(path: &str) -> Result<Vec<SocketAddr>, String> {
+    let mut text = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+                    .map_err(|e| format!("{}: {}", path, e))?;
+    let mut contacts = vec![];
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let addr = match line.find(' ') {
+            Some(space) => {
+                NodeId::from_hex(&line[..space]).map_err(|e| format!("{}: {:?}: {}", path, line, e))?;
+                line[space..].trim()
+            }
+            None => line,
+        };
+        match addr.parse() {
+            Ok(addr @ SocketAddr::V4(_)) => contacts.push(addr),
+            _ => return Err(format!("{}: bad IPv4 contact {:?}", path, line)),
+        }
+    }
+    Ok(contacts)
+}
+
+/// Reads saved bans, one `IP[/BITS]` per line; blank lines and `#` comments are skipped.
+fn read_bans(path: &str) -> Result<Vec<(Ipv4Addr, u8)>, String> {
+    let mut text = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+                    .map_err(|e| format!("{}: {}", path, e))?;
+    let mut bans = vec![];
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        match blacklist::parse_subnet(line) {
+            Some(subnet) => bans.push(subnet),
+            None => return Err(format!("{}: bad ban {:?}", path, line)),
+        }
+    }
+    Ok(bans)
+}
+
+/// Applies `Config::recv_buffer` and `send_buffer` to `sock`, logging the sizes the OS
+/// settled on; it may not grant what we ask.
+fn size_buffers(sock: &UdpSocket, config: &Config) {
+    let wanted = [(sockbuf::Buffer::Recv, config.recv_buffer), (sockbuf::Buffer::Send, config.send_buffer)];
+    for &(buffer, size) in &wanted {
+        let size = match size {
+            Some(size) => size,
+            None => continue,
+        };
+        match sockbuf::set(sock, buffer, size).and_then(|()| sockbuf::get(sock, buffer)) {
+            Ok(actual) => println!("S: {:?} buffer is {} bytes (asked for {})", buffer, actual, size),
+            Err(e) => println!("S: couldn't size {:?} buffer: {}", buffer, e),
+        }
+    }
+}
+
+/// Loads announces saved by `ServerHandler::save_peer_store`; a missing file loads nothing.
+fn load_peer_store(path: &str, peers: &mut PeerStore) -> error::Result<usize> {
+    let mut bytes = vec![];
+    match File::open(path) {
+        Ok(mut f) => f.read_to_end(&mut bytes)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
     };
-    handler.send(event_loop, bootstrap_addr, Query::Ping)?;
+    let saved = bencode::from_buffer(&bytes).map_err(|_| DecodeError::WrongType)?;
+    Ok(peers.import(&saved)?)
+}
 
-    event_loop.run(handler)
+/// Resolves `host:port` names, keeping the IPv4 addresses we can talk to.
+fn resolve(resolver: &Resolver, names: &[String]) -> error::Result<Vec<SocketAddr>> {
+    let mut addrs = vec![];
+    for name in names {
+        for addr in resolver.resolve(name)? {
+            if let SocketAddr::V4(_) = addr {
+                addrs.push(addr);
+            }
+        }
+    }
+    Ok(addrs)
+}
+
+fn serve(config: Config, resolver: Box<Resolver>) -> error::Result<()> {
+    let mut bind = config.local_addr();
+    if let SocketAddr::V6(v6) = bind {
+        // we only speak IPv4 so far; a v6 socket would bind and then reach no one. Only
+        // "every interface" has an IPv4 equivalent that listens no wider than asked.
+        if !v6.ip().is_unspecified() {
+            return Err(Error::NotIpv4)
+        }
+        println!("IPv6 isn't supported yet; listening on IPv4 port {} instead", bind.port());
+        bind = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), bind.port());
+    }
+    let sock = UdpSocket::bound(&bind)?;
+    size_buffers(&sock, &config);
+    let local = sock.local_addr()?;
+    if config.random_port {
+        println!("picked port {}", local.port());
+    }
+    if local.ip().is_unspecified() {
+        println!("listening on {}; sends go out whichever interface the OS picks", local);
+    } else {
+        println!("listening and sending on {}", local);
+    }
+
+    let ref mut event_loop: EventLoop<ServerHandler> = EventLoop::new()?;
+    event_loop.register(&sock, SERVER, EventSet::readable(), PollOpt::edge())?;
+
+    // with contacts from an earlier run, DNS bootstrap is only a fallback
+    let warm = !config.contacts.is_empty();
+    let seeds = if warm {
+        config.contacts.clone()
+    } else {
+        let bootstrap = resolve(&*resolver, &config.bootstrap)?;
+        if bootstrap.is_empty() {
+            return Err(Error::NoBootstrap)
+        }
+        bootstrap
+    };
+
+    let find = config.find;
+    let announce = config.announce.clone();
+    let ref mut handler = ServerHandler::new(config, sock, resolver, event_loop)?;
+    if !handler.config.refused.is_empty() {
+        let refused = handler.config.refused.clone();
+        handler.set_query_policy(Box::new(move |query: &FullQuery, _: &SocketAddr| {
+            let info_hash = match query.query {
+                Query::GetPeers {ref info_hash, ..} |
+                Query::AnnouncePeer {ref info_hash, ..} => info_hash,
+                _ => return Ok(()),
+            };
+            if refused.contains(info_hash) {
+                Err((201, "infohash refused".to_string()))
+            } else {
+                Ok(())
+            }
+        }));
+    }
+    if let Some(rate) = handler.config.observe_queries {
+        handler.set_query_hook(Box::new(|info_hash: &NodeId, source: &SocketAddr, interest: Interest| {
+            println!("observed {:?} for {} from {}", interest, info_hash.to_hex(), source)
+        }), rate);
+    }
+    for addr in &seeds {
+        handler.send(event_loop, addr, Query::Ping, Tx::Bootstrap)?;
+    }
+    if warm {
+        // give every contact's ping time to be answered or time out
+        event_loop.timeout_ms(Timer::WarmStart, TX_TIMEOUT_MS + 1_000).unwrap();
+    }
+    if let Some(target) = find {
+        handler.find_lookup = Some(handler.start_lookup(event_loop, target, &seeds, Purpose::FindNodes)?);
+    }
+    for (info_hash, port, seed) in announce {
+        handler.announce(event_loop, info_hash, port, seed, &seeds)?;
+    }
+
+    Ok(event_loop.run(handler)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs;
+    use std::net::{self, SocketAddr};
+    use std::time::Duration;
+
+    use bencode::{self, FromBencode, ToBencode};
+    use bencode::Bencode::ByteString;
+    use mio::{EventLoop, EventSet, Handler, PollOpt};
+    use mio::udp::UdpSocket;
+
+    use config::Config;
+    use error::Error;
+    use messages::{DhtError, DhtMessage, FullQuery, FullResponse, ListPolicy, MAX_REPLY_NODES, Node4Info, NodeId,
+                   Peer4Info, Query, Response, TxId};
+    use resolver::SystemResolver;
+    use storage;
+    use table::NodeState;
+    use super::{SERVER, ServerHandler, Timer, Tx, read_contacts};
+
+    /// A handler on a loopback port, with private addresses allowed so it'll talk to `Mock`s.
+    fn server(event_loop: &mut EventLoop<ServerHandler>, mut config: Config) -> ServerHandler {
+        config.node_id = Some(NodeId([0; 20]));
+        config.accept_private_addrs = true;
+        let sock = UdpSocket::bound(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        event_loop.register(&sock, SERVER, EventSet::readable(), PollOpt::edge()).unwrap();
+        ServerHandler::new(config, sock, Box::new(SystemResolver), event_loop).unwrap()
+    }
+
+    /// A remote node we play by hand, on its own loopback socket.
+    struct Mock {
+        id: NodeId,
+        sock: net::UdpSocket,
+    }
+
+    impl Mock {
+        fn new(id: u8) -> Self {
+            let sock = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            Mock {id: NodeId([id; 20]), sock: sock}
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.sock.local_addr().unwrap()
+        }
+
+        fn node(&self) -> Node4Info {
+            Node4Info::from_addr(&self.id, &self.addr()).unwrap()
+        }
+
+        /// The next datagram the handler sent us, as raw bytes.
+        fn datagram(&self) -> Vec<u8> {
+            let mut buf = [0u8; 65_536];
+            let (len, _) = self.sock.recv_from(&mut buf).unwrap();
+            buf[..len].to_vec()
+        }
+
+        /// The next query the handler sent us.
+        fn query(&self) -> FullQuery {
+            FullQuery::from_bencode(&bencode::from_buffer(&self.datagram()).unwrap()).unwrap()
+        }
+
+        /// Sends `bytes` to the handler and has it read them.
+        fn send(&self, handler: &mut ServerHandler, event_loop: &mut EventLoop<ServerHandler>, bytes: &[u8]) {
+            self.sock.send_to(bytes, handler.local_addr).unwrap();
+            handler.ready(event_loop, SERVER, EventSet::readable());
+        }
+
+        /// The next datagram the handler sent us that isn't a query of its own.
+        fn answer(&self) -> Vec<u8> {
+            loop {
+                let bytes = self.datagram();
+                if FullQuery::from_bencode(&bencode::from_buffer(&bytes).unwrap()).is_err() {
+                    return bytes
+                }
+            }
+        }
+
+        /// Sends the handler `query` and returns its answer, undecoded.
+        fn ask(&self, handler: &mut ServerHandler, event_loop: &mut EventLoop<ServerHandler>,
+               query: Query) -> Vec<u8>
+        {
+            let full = FullQuery {query: query, sender_id: Some(self.id), tx_id: TxId::Short([1, 2])};
+            self.send(handler, event_loop, &full.to_bencode().to_bytes().unwrap());
+            self.answer()
+        }
+
+        /// Answers `query` with `response`.
+        fn reply(&self, handler: &mut ServerHandler, event_loop: &mut EventLoop<ServerHandler>,
+                 query: &FullQuery, response: Response)
+        {
+            let resp = FullResponse {
+                response: response,
+                sender_id: self.id,
+                tx_id: query.tx_id.clone(),
+                ip: None,
+                ignored_nodes: 0,
+            };
+            self.send(handler, event_loop, &resp.to_bencode().to_bytes().unwrap());
+        }
+    }
+
+    /// Decodes the handler's answer, taking the loopback nodes it hands out.
+    fn response(bytes: &[u8]) -> FullResponse {
+        let policy = ListPolicy {max_nodes: MAX_REPLY_NODES, allow_private: true};
+        FullResponse::decode(&bencode::from_buffer(bytes).unwrap(), policy).unwrap()
+    }
+
+    #[test]
+    fn discovers_nodes_through_ping_and_find_node() {
+        let ref mut event_loop = EventLoop::new().unwrap();
+        let ref mut handler = server(event_loop, Config::default());
+        let (a, b, c) = (Mock::new(1), Mock::new(2), Mock::new(3));
+
+        handler.discovery_ping(event_loop, a.addr()).unwrap();
+        let ping = a.query();
+        assert_eq!(ping.query, Query::Ping);
+        a.reply(handler, event_loop, &ping, Response::Pong);
+
+        // a pong to a discovery ping has us ask the new node for more
+        let find_node = a.query();
+        match find_node.query {
+            Query::FindNode(_) => (),
+            ref other => panic!("expected find_node, got {:?}", other),
+        }
+        a.reply(handler, event_loop, &find_node, Response::FoundNodes {nodes4: vec![b.node(), c.node()]});
+
+        for mock in &[&b, &c] {
+            let ping = mock.query();
+            assert_eq!(ping.query, Query::Ping);
+            mock.reply(handler, event_loop, &ping, Response::Pong);
+        }
+        for mock in &[&a, &b, &c] {
+            match handler.table.get(&mock.id) {
+                Some((peer, NodeState::Good, _)) => assert_eq!(peer.socket_addr(), mock.addr()),
+                other => panic!("{:?} should be good, is {:?}", mock.id, other),
+            }
+        }
+    }
+
+    #[test]
+    fn unanswered_queries_make_a_node_bad_until_it_answers() {
+        let ref mut event_loop = EventLoop::new().unwrap();
+        let mut config = Config::default();
+        config.bad_after_failures = 3;
+        let ref mut handler = server(event_loop, config);
+        let a = Mock::new(1);
+        let node = a.node();
+        handler.heard_from(node);
+
+        for failures in 1..4 {
+            handler.send_to(event_loop, &node, Query::Ping, Tx::Probe).unwrap();
+            let ping = a.query();
+            if failures == 2 {
+                // an error counts the same as silence
+                let error = DhtError {message: "nope".to_string(), code: 201, tx_id: ping.tx_id, ip: None};
+                a.send(handler, event_loop, &error.to_bencode().to_bytes().unwrap());
+            } else {
+                handler.timeout(event_loop, Timer::Tx(ping.tx_id));
+            }
+            let entry = handler.table.entry_mut(&a.id).unwrap();
+            assert_eq!(entry.failures, failures);
+            assert_eq!(entry.is_bad(3), failures == 3);
+        }
+
+        handler.send_to(event_loop, &node, Query::Ping, Tx::Probe).unwrap();
+        let ping = a.query();
+        a.reply(handler, event_loop, &ping, Response::Pong);
+        let entry = handler.table.entry_mut(&a.id).unwrap();
+        assert_eq!(entry.failures, 0);
+        assert!(!entry.is_bad(3));
+    }
+
+    #[test]
+    fn saved_contacts_read_back_as_our_good_nodes() {
+        let ref mut event_loop = EventLoop::new().unwrap();
+        let a = Mock::new(1);
+        // named for a port we hold, so concurrent runs don't share it
+        let path = env::temp_dir().join(format!("dht-contacts-{}", a.addr().port()));
+        let path = path.to_str().unwrap().to_string();
+        let mut config = Config::default();
+        config.contacts_file = Some(path.clone());
+        let ref mut handler = server(event_loop, config);
+
+        let mut good = HashSet::new();
+        for i in 0..5 {
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), 7100 + i as u16);
+            handler.heard_from(Node4Info::from_addr(&NodeId([20 + i; 20]), &addr).unwrap());
+            good.insert(addr);
+        }
+        // still being pinged, so not worth saving
+        handler.table.allocate_many(&[a.node()]);
+
+        handler.save_contacts().unwrap();
+        let read = read_contacts(&path);
+        fs::remove_file(&path).unwrap();
+        let read = read.unwrap();
+        assert_eq!(read.len(), good.len());
+        assert_eq!(read.into_iter().collect::<HashSet<_>>(), good);
+    }
+
+    #[test]
+    fn refreshing_a_bucket_looks_up_an_id_in_its_range() {
+        let ref mut event_loop = EventLoop::new().unwrap();
+        let ref mut handler = server(event_loop, Config::default());
+        let a = Mock::new(1);
+        handler.heard_from(a.node());
+        assert_eq!(handler.table.bucket_count(), 2);
+
+        match handler.refresh_bucket(event_loop, 2) {
+            Err(Error::NoSuchBucket(2)) => (),
+            Err(e) => panic!("expected no such bucket, got {}", e),
+            Ok(id) => panic!("expected no such bucket, got lookup {:?}", id),
+        }
+        for index in 0..2 {
+            assert!(handler.refresh_bucket(event_loop, index).unwrap().is_some());
+            match a.query().query {
+                Query::FindNode(target) => assert_eq!(handler.table.bucket_index_for(&target), index),
+                ref other => panic!("expected find_node, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn dry_run_matches_what_send_transmits() {
+        let ref mut event_loop = EventLoop::new().unwrap();
+        let ref mut handler = server(event_loop, Config::default());
+        let a = Mock::new(1);
+
+        let target = NodeId([7; 20]);
+        handler.send(event_loop, &a.addr(), Query::FindNode(target), Tx::FindNode).unwrap();
+        let sent = a.datagram();
+        let tx_id = FullQuery::from_bencode(&bencode::from_buffer(&sent).unwrap()).unwrap().tx_id;
+
+        let query = FullQuery {query: Query::FindNode(target), sender_id: Some(NodeId([0; 20])), tx_id: tx_id};
+        let (bytes, decoded) = handler.dry_run(query.to_bencode()).unwrap();
+        assert_eq!(bytes, sent);
+        assert_eq!(decoded, DhtMessage::Query(query));
+    }
+
+    #[test]
+    fn replies_stay_under_max_datagram() {
+        let ref mut event_loop = EventLoop::new().unwrap();
+        let mut config = Config::default();
+        config.max_datagram = 250;
+        let ref mut handler = server(event_loop, config);
+        let info_hash = NodeId([9; 20]);
+        for i in 0..8 {
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), 7000 + i as u16);
+            handler.heard_from(Node4Info::from_addr(&NodeId([10 + i; 20]), &addr).unwrap());
+            let peer = Peer4Info::from_socket_addr(&SocketAddr::new("127.0.0.1".parse().unwrap(), 8000 + i as u16));
+            handler.peers.announce(&info_hash, peer.unwrap(), false);
+        }
+        let a = Mock::new(1);
+
+        // both would run to over 250 bytes with all 8 nodes
+        let bytes = a.ask(handler, event_loop, Query::FindNode(info_hash));
+        assert!(bytes.len() <= 250, "find_node reply is {} bytes", bytes.len());
+        match response(&bytes).response {
+            Response::FoundNodes {ref nodes4} => assert!(!nodes4.is_empty() && nodes4.len() < 8),
+            ref other => panic!("expected nodes, got {:?}", other),
+        }
+        let bytes = a.ask(handler, event_loop, Query::GetPeers {info_hash: info_hash, noseed: false});
+        assert!(bytes.len() <= 250, "get_peers reply is {} bytes", bytes.len());
+        match response(&bytes).response {
+            Response::Peers {ref values, ref nodes4, ..} => assert!(values.len() + nodes4.len() < 16),
+            ref other => panic!("expected peers, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn puts_and_gets_an_item_of_the_largest_size() {
+        let ref mut event_loop = EventLoop::new().unwrap();
+        let ref mut handler = server(event_loop, Config::default());
+        let a = Mock::new(1);
+        let v = ByteString(vec![b'x'; storage::MAX_VALUE_LEN - 5]);
+        let target = storage::immutable_target(&v.to_bytes().unwrap());
+
+        let token = match response(&a.ask(handler, event_loop, Query::Get(target))).response {
+            Response::Item {token, v: None, ..} => token,
+            other => panic!("expected no item yet, got {:?}", other),
+        };
+        let put = Query::Put {token: token, v: v.clone(), signed: None, salt: vec![], cas: None};
+        assert_eq!(response(&a.ask(handler, event_loop, put)).response, Response::Pong);
+        match response(&a.ask(handler, event_loop, Query::Get(target))).response {
+            Response::Item {v: Some(ref got), ..} => assert_eq!(got, &v),
+            other => panic!("expected the item back, got {:?}", other),
+        }
+    }
 }
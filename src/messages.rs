@@ -1,4 +1,5 @@
-use std::{self, io};
+use std;
+use std::cmp::{self, Ordering};
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt::{self, Debug, Display, Formatter};
@@ -13,7 +14,7 @@ use rand;
 // ! Primitives
 
 /// The 160-bit space of BitTorrent infohashes.
-#[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct NodeId(pub [u8; NODE_ID_LEN]);
 
 pub const NODE_ID_LEN: usize = 20;
@@ -33,20 +34,77 @@ impl NodeId {
         }
     }
 
+    /// Parses a 40-digit hex string, as infohashes are usually written.
+    pub fn from_hex(hex: &str) -> DecodeResult<Self> {
+        fn digit(c: u8) -> DecodeResult<u8> {
+            match c {
+                b'0'...b'9' => Ok(c - b'0'),
+                b'a'...b'f' => Ok(c - b'a' + 10),
+                b'A'...b'F' => Ok(c - b'A' + 10),
+                _ => Err(DecodeError::InvalidHex),
+            }
+        }
+        let hex = hex.as_bytes();
+        if hex.len() != NODE_ID_LEN * 2 {
+            return Err(DecodeError::WrongLength);
+        }
+        let mut id = [0u8; NODE_ID_LEN];
+        for (i, pair) in hex.chunks(2).enumerate() {
+            id[i] = (digit(pair[0])? << 4) | digit(pair[1])?;
+        }
+        Ok(NodeId(id))
+    }
+
+    /// Lowercase hex representation; the inverse of `from_hex`.
+    pub fn to_hex(&self) -> String {
+        let mut hex = String::with_capacity(NODE_ID_LEN * 2);
+        for b in &self.0 {
+            hex.push_str(&format!("{:02x}", b));
+        }
+        hex
+    }
+
     pub fn bit(&self, index: usize) -> bool {
         debug_assert!(index < NODE_ID_LEN * 8);
         let mask = 1 << (7 - (index % 8));
         (self.0[index / 8] & mask) != 0
     }
+
+    /// Whether this ID is one BEP 42 lets a node at `ip` choose: its first 21 bits come from
+    /// a CRC32-C of the masked IP, so an attacker can't pick IDs near a target at will.
+    ///
+    /// Non-global addresses are exempt, as BEP 42 says, and always pass.
+    pub fn is_valid_for(&self, ip: &Ipv4Addr) -> bool {
+        if !is_global_v4(ip) {
+            return true
+        }
+        let mut masked = ip.octets();
+        for (octet, mask) in masked.iter_mut().zip(&[0x03, 0x0f, 0x3f, 0xff]) {
+            *octet &= *mask;
+        }
+        masked[0] |= (self.0[19] & 0x07) << 5;
+        let crc = crc32c(&masked);
+        self.0[0] == (crc >> 24) as u8 &&
+            self.0[1] == (crc >> 16) as u8 &&
+            self.0[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+    }
+}
+
+/// CRC32-C (Castagnoli), bit by bit; we only ever checksum four bytes at a time.
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
 }
 
 impl Debug for NodeId {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "Node(")?;
-        for b in &self.0 {
-            write!(f, "{:02x}", b)?;
-        }
-        write!(f, ")")
+        write!(f, "Node({})", self.to_hex())
     }
 }
 
@@ -73,16 +131,6 @@ pub enum TxId {
 }
 
 impl TxId {
-    pub fn random() -> Self {
-        fn alpha() -> u8 {
-            let n = rand::random::<u8>() % 52;
-            let c = if n >= 26 { n - 26 + 97 } else { n + 65 };
-            debug_assert!(match c as char { 'A'...'Z' | 'a'...'z' => true, _ => false });
-            c
-        }
-        TxId::Short([alpha(), alpha()])
-    }
-
     pub fn as_slice(&self) -> &[u8] {
         match *self {
             TxId::Short(ref two) => two,
@@ -91,6 +139,72 @@ impl TxId {
     }
 }
 
+/// Longest transaction ID we'll take from a peer. Ours are two or four bytes and most
+/// clients' no longer; anything past this is a broken or hostile node.
+pub const MAX_TX_ID_LEN: usize = 20;
+
+/// Hands out transaction IDs from counters, so an ID only comes round again after every
+/// other one has been used.
+///
+/// Two-byte IDs come first; longer ones only once all 65536 of those are outstanding.
+/// Either way, peers just echo them back.
+pub struct TxIdAllocator {
+    short: u16,
+    long: u32,
+    /// IDs handed out so far.
+    issued: u64,
+    /// IDs passed over because they were still in use.
+    collisions: u64,
+}
+
+impl TxIdAllocator {
+    /// Starts the counters somewhere random, so our IDs don't give away how long we've run.
+    pub fn new() -> Self {
+        TxIdAllocator {
+            short: rand::random(),
+            long: rand::random(),
+            issued: 0,
+            collisions: 0,
+        }
+    }
+
+    /// How many IDs `next` has handed out.
+    pub fn issued(&self) -> u64 {
+        self.issued
+    }
+
+    /// How many IDs `next` has had to skip because they were in use. A high rate relative to
+    /// `issued` means the two-byte space is crowded and longer IDs are common.
+    pub fn collisions(&self) -> u64 {
+        self.collisions
+    }
+
+    /// The next ID that `in_use` says is free.
+    pub fn next<F: Fn(&TxId) -> bool>(&mut self, in_use: F) -> TxId {
+        for _ in 0..(1 << 16) {
+            let n = self.short;
+            self.short = self.short.wrapping_add(1);
+            let id = TxId::Short([(n >> 8) as u8, n as u8]);
+            if !in_use(&id) {
+                self.issued += 1;
+                return id
+            }
+            self.collisions += 1;
+        }
+        loop {
+            let n = self.long;
+            self.long = self.long.wrapping_add(1);
+            let bytes = [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8];
+            let id = TxId::Arbitrary(Bytes::from_slice(&bytes));
+            if !in_use(&id) {
+                self.issued += 1;
+                return id
+            }
+            self.collisions += 1;
+        }
+    }
+}
+
 impl Debug for TxId {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "Tx(")?;
@@ -105,10 +219,11 @@ impl FromBencode for TxId {
     type Err = DecodeError;
     fn from_bencode(b: &Bencode) -> DecodeResult<Self> {
         let bytes = b.bytes()?;
-        if bytes.len() == 2 {
-            Ok(TxId::Short([bytes[0], bytes[1]]))
-        } else {
-            Ok(TxId::Arbitrary(Bytes::from_slice(bytes)))
+        match bytes.len() {
+            2 => Ok(TxId::Short([bytes[0], bytes[1]])),
+            0 => Err(DecodeError::WrongLength),
+            len if len > MAX_TX_ID_LEN => Err(DecodeError::WrongLength),
+            _ => Ok(TxId::Arbitrary(Bytes::from_slice(bytes))),
         }
     }
 }
@@ -142,9 +257,11 @@ pub enum DecodeError {
     KeyMissing(&'static str),
     InvalidAddress(Ipv4Addr),
     InvalidDiscrim,
+    InvalidHex,
     OutOfRange,
     WrongDiscrim,
     WrongLength,
+    WrongNetwork,
     WrongType,
 }
 
@@ -155,9 +272,11 @@ impl Error for DecodeError {
             KeyMissing(_) => "required key missing",
             InvalidAddress(_) => "invalid peer address",
             InvalidDiscrim => "invalid tag",
+            InvalidHex => "invalid hex digit",
             OutOfRange => "number out of range",
             WrongDiscrim => "wrong tag",
             WrongLength => "wrong array/value length",
+            WrongNetwork => "from another DHT network",
             WrongType => "wrong type",
         }
     }
@@ -173,24 +292,175 @@ impl Display for DecodeError {
     }
 }
 
-impl From<DecodeError> for io::Error {
-    fn from(error: DecodeError) -> io::Error {
-        io::Error::new(io::ErrorKind::InvalidData, error)
-    }
-}
-
 /// The requests a node may make of another.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Query {
     Ping,
     FindNode(NodeId),
+    GetPeers {
+        info_hash: NodeId,
+        /// The querier is seeding, so only wants peers that aren't (the `noseed` flag).
+        noseed: bool,
+    },
+    AnnouncePeer {
+        info_hash: NodeId,
+        port: u16,
+        /// Use the UDP source port rather than `port`, for peers behind NAT.
+        implied_port: bool,
+        token: Vec<u8>,
+        /// The announcer has the whole torrent (the `seed` flag).
+        seed: bool,
+    },
+    /// BEP 51: a random sample of the infohashes we store peers for.
+    SampleInfohashes(NodeId),
+    /// BEP 44: the item stored under `target`, if any, plus closer nodes.
+    Get(NodeId),
+    /// BEP 44: store item `v`. Immutable items go under their SHA-1; `signed` ones under
+    /// the SHA-1 of the public key and `salt`, replacing older versions (only the one at
+    /// sequence number `cas`, if given).
+    Put {
+        token: Vec<u8>,
+        v: Bencode,
+        signed: Option<Signature>,
+        salt: Vec<u8>,
+        cas: Option<i64>,
+    },
+    /// A method we don't implement (yet), such as `vote`.
+    Unknown(String),
+}
+
+impl Query {
+    /// The KRPC method name, as sent in `q`; `"unknown"` for methods we don't implement.
+    pub fn method(&self) -> &'static str {
+        match *self {
+            Query::Ping => "ping",
+            Query::FindNode(_) => "find_node",
+            Query::GetPeers {..} => "get_peers",
+            Query::AnnouncePeer {..} => "announce_peer",
+            Query::SampleInfohashes(_) => "sample_infohashes",
+            Query::Get(_) => "get",
+            Query::Put {..} => "put",
+            Query::Unknown(_) => "unknown",
+        }
+    }
+}
+
+/// Builds a `Query` from the `a` dictionary of one method's queries.
+pub type QueryParser = fn(&DictMap) -> DecodeResult<Query>;
+
+/// Every query method we decode, by its `q` name; any other name decodes as `Query::Unknown`.
+///
+/// Each method names its subject under its own key: `target` for find_node,
+/// sample_infohashes and get, `info_hash` for get_peers and announce_peer. A missing one is
+/// reported by name as `KeyMissing`.
+pub const QUERY_METHODS: &'static [(&'static [u8], QueryParser)] = &[
+    (b"ping", parse_ping),
+    (b"find_node", parse_find_node),
+    (b"get_peers", parse_get_peers),
+    (b"sample_infohashes", parse_sample_infohashes),
+    (b"get", parse_get),
+    (b"put", parse_put),
+    (b"announce_peer", parse_announce_peer),
+];
+
+fn parse_ping(_: &DictMap) -> DecodeResult<Query> {
+    Ok(Query::Ping)
+}
+
+fn parse_find_node(args: &DictMap) -> DecodeResult<Query> {
+    Ok(Query::FindNode(NodeId::from_bencode(args.lookup("target")?)?))
+}
+
+fn parse_get_peers(args: &DictMap) -> DecodeResult<Query> {
+    Ok(Query::GetPeers {
+        info_hash: NodeId::from_bencode(args.lookup("info_hash")?)?,
+        noseed: flag(args, "noseed")?,
+    })
+}
+
+fn parse_sample_infohashes(args: &DictMap) -> DecodeResult<Query> {
+    Ok(Query::SampleInfohashes(NodeId::from_bencode(args.lookup("target")?)?))
+}
+
+fn parse_get(args: &DictMap) -> DecodeResult<Query> {
+    Ok(Query::Get(NodeId::from_bencode(args.lookup("target")?)?))
+}
+
+fn parse_put(args: &DictMap) -> DecodeResult<Query> {
+    Ok(Query::Put {
+        token: args.lookup("token")?.bytes()?.to_vec(),
+        v: args.lookup("v")?.clone(),
+        signed: Signature::from_args(args)?,
+        salt: match args.lookup("salt") {
+            Ok(salt) => salt.bytes()?.to_vec(),
+            Err(_) => vec![],
+        },
+        cas: match args.lookup("cas") {
+            Ok(cas) => Some(cas.i64()?),
+            Err(_) => None,
+        },
+    })
+}
+
+fn parse_announce_peer(args: &DictMap) -> DecodeResult<Query> {
+    let port = args.lookup("port")?.u32()?;
+    if port > (std::u16::MAX as u32) {
+        return Err(DecodeError::OutOfRange)
+    }
+    Ok(Query::AnnouncePeer {
+        info_hash: NodeId::from_bencode(args.lookup("info_hash")?)?,
+        port: port as u16,
+        implied_port: flag(args, "implied_port")?,
+        token: args.lookup("token")?.bytes()?.to_vec(),
+        seed: flag(args, "seed")?,
+    })
+}
+
+/// What makes a BEP 44 item mutable: its owner's ed25519 key, version, and signature.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Signature {
+    /// 32-byte public key.
+    pub k: Vec<u8>,
+    pub seq: i64,
+    /// 64-byte signature over the salt, `seq` and `v`.
+    pub sig: Vec<u8>,
+}
+
+impl Signature {
+    fn from_args(args: &DictMap) -> DecodeResult<Option<Self>> {
+        let k = match args.lookup("k") {
+            Ok(k) => k.bytes()?.to_vec(),
+            Err(_) => return Ok(None),
+        };
+        Ok(Some(Signature {
+            k: k,
+            seq: args.lookup("seq")?.i64()?,
+            sig: args.lookup("sig")?.bytes()?.to_vec(),
+        }))
+    }
+
+    fn add_args(&self, args: &mut DictMap) {
+        args.insert(Bytes::from_str("k"), ByteString(self.k.clone()));
+        args.insert(Bytes::from_str("seq"), Number(self.seq));
+        args.insert(Bytes::from_str("sig"), ByteString(self.sig.clone()));
+    }
+}
+
+/// An optional 0/1 argument such as `implied_port`; absent means 0.
+fn flag(args: &DictMap, key: &'static str) -> DecodeResult<bool> {
+    match args.lookup(key) {
+        Ok(value) => Ok(value.u32()? != 0),
+        Err(_) => Ok(false),
+    }
 }
 
 /// The full payload for a `Query`.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct FullQuery {
     pub query: Query,
-    pub sender_id: NodeId,
+    /// `None` if the sender left its ID out or sent a malformed one; some buggy clients do.
+    /// Such a sender mustn't be routed to; see `Config::idless_policy`.
+    pub sender_id: Option<NodeId>,
     pub tx_id: TxId,
 }
 
@@ -202,13 +472,13 @@ impl FromBencode for FullQuery {
             return Err(DecodeError::WrongDiscrim)
         }
         let args = dict.lookup("a")?.dict()?;
-        let sender_id = NodeId::from_bencode(args.lookup("id")?)?;
+        let sender_id = args.lookup("id").and_then(NodeId::from_bencode).ok();
         let tx_id = TxId::from_bencode(dict.lookup("t")?)?;
 
-        let query = match dict.lookup("q")?.bytes()? {
-            b"ping" => Query::Ping,
-            b"find_node" => Query::FindNode(NodeId::from_bencode(args.lookup("target")?)?),
-            _ => return Err(DecodeError::InvalidDiscrim)
+        let method = dict.lookup("q")?.bytes()?;
+        let query = match QUERY_METHODS.iter().find(|&&(name, _)| name == method) {
+            Some(&(_, parse)) => parse(args)?,
+            None => Query::Unknown(String::from_utf8_lossy(method).into_owned()),
         };
 
         Ok(FullQuery {
@@ -223,14 +493,58 @@ impl FromBencode for FullQuery {
 impl ToBencode for FullQuery {
     fn to_bencode(&self) -> Bencode {
         let mut args = BTreeMap::new();
-        let query_type: &'static [u8];
-        args.insert(Bytes::from_str("id"), self.sender_id.to_bencode());
+        let query_type: &[u8];
+        if let Some(ref sender_id) = self.sender_id {
+            args.insert(Bytes::from_str("id"), sender_id.to_bencode());
+        }
         match self.query {
             Query::Ping => query_type = b"ping",
             Query::FindNode(ref target) => {
                 query_type = b"find_node";
                 args.insert(Bytes::from_str("target"), target.to_bencode());
             }
+            Query::GetPeers {ref info_hash, noseed} => {
+                query_type = b"get_peers";
+                args.insert(Bytes::from_str("info_hash"), info_hash.to_bencode());
+                if noseed {
+                    args.insert(Bytes::from_str("noseed"), Number(1));
+                }
+            }
+            Query::AnnouncePeer {ref info_hash, port, implied_port, ref token, seed} => {
+                query_type = b"announce_peer";
+                args.insert(Bytes::from_str("info_hash"), info_hash.to_bencode());
+                args.insert(Bytes::from_str("port"), port.to_bencode());
+                args.insert(Bytes::from_str("token"), ByteString(token.clone()));
+                if implied_port {
+                    args.insert(Bytes::from_str("implied_port"), Number(1));
+                }
+                if seed {
+                    args.insert(Bytes::from_str("seed"), Number(1));
+                }
+            }
+            Query::SampleInfohashes(ref target) => {
+                query_type = b"sample_infohashes";
+                args.insert(Bytes::from_str("target"), target.to_bencode());
+            }
+            Query::Get(ref target) => {
+                query_type = b"get";
+                args.insert(Bytes::from_str("target"), target.to_bencode());
+            }
+            Query::Put {ref token, ref v, ref signed, ref salt, cas} => {
+                query_type = b"put";
+                args.insert(Bytes::from_str("token"), ByteString(token.clone()));
+                args.insert(Bytes::from_str("v"), v.clone());
+                if let Some(ref signed) = *signed {
+                    signed.add_args(&mut args);
+                }
+                if !salt.is_empty() {
+                    args.insert(Bytes::from_str("salt"), ByteString(salt.clone()));
+                }
+                if let Some(cas) = cas {
+                    args.insert(Bytes::from_str("cas"), Number(cas));
+                }
+            }
+            Query::Unknown(ref method) => query_type = method.as_bytes(),
         }
 
         let mut dict = BTreeMap::new();
@@ -242,33 +556,76 @@ impl ToBencode for FullQuery {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Peer4Info(SocketAddrV4);
 
+/// By IP, then port; only used to break ties between equidistant nodes deterministically.
+impl Ord for Peer4Info {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0.ip().octets(), self.0.port()).cmp(&(other.0.ip().octets(), other.0.port()))
+    }
+}
+
+impl PartialOrd for Peer4Info {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Peer4Info {
-    fn parse(b: &[u8]) -> DecodeResult<Self> {
-        if b.len() != 6 {
-            return Err(DecodeError::WrongLength);
-        }
-        let ip = Ipv4Addr::new(b[0], b[1], b[2], b[3]);
-        if !ip.is_global() {
-            return Err(DecodeError::InvalidAddress(ip));
+    /// Reads the 6-byte compact form, refusing addresses no one could reach.
+    pub fn parse(b: &[u8]) -> DecodeResult<Self> {
+        Peer4Info::parse_scoped(b, false)
+    }
+
+    /// Like `parse`, but with `allow_private` also takes private, loopback and other
+    /// non-global addresses, as found in a private DHT on a LAN.
+    pub fn parse_scoped(b: &[u8], allow_private: bool) -> DecodeResult<Self> {
+        let addr = parse_compact_v4(b)?;
+        if !allow_private && !is_global_v4(addr.ip()) {
+            return Err(DecodeError::InvalidAddress(*addr.ip()));
         }
-        let port = ((b[4] as u16) << 8) + b[5] as u16;
-        if port == 0 {
+        if addr.port() == 0 {
             return Err(DecodeError::OutOfRange);
         }
-        Ok(Peer4Info(SocketAddrV4::new(ip, port)))
+        Ok(Peer4Info(addr))
+    }
+
+    /// Wraps an IPv4 `SocketAddr`; `None` for IPv6.
+    pub fn from_socket_addr(addr: &SocketAddr) -> Option<Self> {
+        match *addr {
+            SocketAddr::V4(v4) => Some(Peer4Info(v4)),
+            SocketAddr::V6(_) => None,
+        }
     }
 
     pub fn socket_addr(&self) -> SocketAddr {
         SocketAddr::V4(self.0)
     }
 
+    /// The same peer reachable on a different port.
+    pub fn with_port(&self, port: u16) -> Self {
+        Peer4Info(SocketAddrV4::new(*self.0.ip(), port))
+    }
+
+    /// The 6-byte "compact" form: IP then port, in network order.
+    pub fn to_compact(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.ip().octets());
+        out.push((self.0.port() >> 8) as u8);
+        out.push(self.0.port() as u8);
+    }
+
+    fn parse_values(list: &ListVec, allow_private: bool) -> DecodeResult<Vec<Self>> {
+        let mut peers = Vec::with_capacity(list.len());
+        for value in list {
+            peers.push(Peer4Info::parse_scoped(value.bytes()?, allow_private)?);
+        }
+        Ok(peers)
+    }
 }
 
 /// Contact info for one IPv4 node.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Node4Info {
     pub id: NodeId,
     pub peer: Peer4Info,
@@ -276,49 +633,159 @@ pub struct Node4Info {
 
 const NODE4_LEN: usize = NODE_ID_LEN + 6;
 
+/// Length of a compact BEP 32 IPv6 node: ID, 16-byte address, port.
+const NODE6_LEN: usize = NODE_ID_LEN + 18;
+
+/// Most nodes `FullResponse::from_bencode` takes from one reply's `nodes` or `nodes6`;
+/// honest nodes send K.
+pub const MAX_REPLY_NODES: usize = 32;
+
+/// How we read the node and peer lists in replies.
+#[derive(Clone, Copy, Debug)]
+pub struct ListPolicy {
+    /// Most entries we take from each of `nodes` and `nodes6`.
+    pub max_nodes: usize,
+    /// Accept non-global addresses, as a private DHT on a LAN needs. On mainline, a node
+    /// handing them out is broken or trying to aim us at someone's internal network.
+    pub allow_private: bool,
+}
+
+impl Default for ListPolicy {
+    fn default() -> Self {
+        ListPolicy {
+            max_nodes: MAX_REPLY_NODES,
+            allow_private: false,
+        }
+    }
+}
+
+/// Checks the `nodes6` value, if any, is a whole number of compact IPv6 nodes.
+///
+/// We only speak IPv4, so the nodes themselves are dropped; but a reply carrying nothing
+/// else is still a `find_node` answer, not a `Pong`. Entries past `max`, which would be
+/// ignored anyway, are added to `ignored`.
+fn check_nodes6(args: &DictMap, max: usize, ignored: &mut usize) -> DecodeResult<bool> {
+    match args.lookup("nodes6") {
+        Ok(nodes6) => {
+            let len = nodes6.bytes()?.len();
+            if len % NODE6_LEN != 0 {
+                return Err(DecodeError::WrongLength)
+            }
+            *ignored += (len / NODE6_LEN).saturating_sub(max);
+            Ok(true)
+        }
+        Err(_) => Ok(false),
+    }
+}
+
 impl Node4Info {
-    fn parse(bytes: &[u8]) -> DecodeResult<Self> {
+    /// Contact info for a node we've heard from directly.
+    pub fn from_addr(id: &NodeId, addr: &SocketAddr) -> Option<Self> {
+        Peer4Info::from_socket_addr(addr).map(|peer| Node4Info {id: *id, peer: peer})
+    }
+
+    fn parse(bytes: &[u8], allow_private: bool) -> DecodeResult<Self> {
         if bytes.len() == NODE4_LEN {
             Ok(Node4Info {
                 id: NodeId::from_slice(&bytes[..NODE_ID_LEN])?,
-                peer: Peer4Info::parse(&bytes[NODE_ID_LEN..])?,
+                peer: Peer4Info::parse_scoped(&bytes[NODE_ID_LEN..], allow_private)?,
             })
         } else {
             Err(DecodeError::WrongLength)
         }
     }
 
-    fn parse_list(bytes: &[u8]) -> DecodeResult<Vec<Self>> {
+    /// The 26-byte "compact" form: ID then `Peer4Info::to_compact`.
+    pub fn to_compact(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.id.0);
+        self.peer.to_compact(out);
+    }
+
+    /// The `nodes` value listing `nodes`. With none, as when our table is still empty, this is
+    /// the zero-length string, which peers parse as no nodes; BEP 5 wants the key present.
+    fn compact_list(nodes: &[Self]) -> Bencode {
+        let mut bytes = Vec::with_capacity(nodes.len() * NODE4_LEN);
+        for node in nodes {
+            node.to_compact(&mut bytes);
+        }
+        ByteString(bytes)
+    }
+
+    /// Parses a `nodes` string under `policy`, keeping at most the first `max_nodes` entries
+    /// so an oversized one can't make us allocate without bound; the count of the rest is
+    /// added to `ignored`.
+    fn parse_list(bytes: &[u8], policy: ListPolicy, ignored: &mut usize) -> DecodeResult<Vec<Self>> {
+        let max = policy.max_nodes;
         if bytes.len() % NODE4_LEN != 0 {
             return Err(DecodeError::WrongLength);
         }
-        let mut nodes = Vec::with_capacity(bytes.len() / NODE4_LEN);
-        // should this be limited to K=8 max?
-        for entry in bytes.chunks(NODE4_LEN) {
-            nodes.push(Node4Info::parse(entry)?);
+        let count = bytes.len() / NODE4_LEN;
+        *ignored += count.saturating_sub(max);
+        let mut nodes = Vec::with_capacity(cmp::min(count, max));
+        for entry in bytes.chunks(NODE4_LEN).take(max) {
+            nodes.push(Node4Info::parse(entry, policy.allow_private)?);
         }
         Ok(nodes)
     }
 }
 
 /// Possible responses to a `Query`.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Response {
     Pong,
     FoundNodes {nodes4: Vec<Node4Info>},
+    /// Reply to `get_peers`: any peers we know for the infohash, closer nodes,
+    /// and a token to present when announcing.
+    Peers {token: Vec<u8>, values: Vec<Peer4Info>, nodes4: Vec<Node4Info>},
+    /// BEP 51 reply: some of the infohashes the sender stores, out of `num` in total,
+    /// plus closer nodes; don't ask again for `interval` seconds.
+    Samples {interval: u32, num: u32, samples: Vec<NodeId>, nodes4: Vec<Node4Info>},
+    /// BEP 44 reply to `get`: the item if the sender has it (with its signature if it's
+    /// mutable), closer nodes, and a token for `put`.
+    Item {token: Vec<u8>, v: Option<Bencode>, signed: Option<Signature>, nodes4: Vec<Node4Info>},
+}
+
+impl Response {
+    /// Drops the least useful item it carries, to make the reply smaller: the farthest node
+    /// first, then the last peer or sample. Returns false if there was nothing left to drop.
+    pub fn shrink(&mut self) -> bool {
+        match *self {
+            Response::Pong => false,
+            Response::FoundNodes {ref mut nodes4} |
+            Response::Item {ref mut nodes4, ..} => nodes4.pop().is_some(),
+            Response::Peers {ref mut values, ref mut nodes4, ..} => {
+                nodes4.pop().is_some() || values.pop().is_some()
+            }
+            Response::Samples {ref mut samples, ref mut nodes4, ..} => {
+                nodes4.pop().is_some() || samples.pop().is_some()
+            }
+        }
+    }
 }
 
 /// Full payload for a `Response`.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct FullResponse {
     pub response: Response,
     pub sender_id: NodeId,
     pub tx_id: TxId,
+    /// BEP 42 `ip`: the querier's address as the responder saw it.
+    pub ip: Option<SocketAddrV4>,
+    /// Entries of `nodes` and `nodes6` past `ListPolicy::max_nodes`, dropped unread when
+    /// this was decoded. Not encoded.
+    pub ignored_nodes: usize,
 }
 
 impl FromBencode for FullResponse {
     type Err = DecodeError;
     fn from_bencode(b: &Bencode) -> DecodeResult<Self> {
+        FullResponse::decode(b, ListPolicy::default())
+    }
+}
+
+impl FullResponse {
+    /// Decodes `b`, reading its node and peer lists under `policy`.
+    pub fn decode(b: &Bencode, policy: ListPolicy) -> DecodeResult<Self> {
         let dict = b.dict()?;
         if dict.lookup("y")?.bytes()? != b"r" {
             return Err(DecodeError::WrongDiscrim)
@@ -327,11 +794,51 @@ impl FromBencode for FullResponse {
 
         // there's no explicit discriminator but we can tell by the args...
         let response: Response;
-        if let Ok(token) = args.lookup("token") {
-            panic!("get_peers not implemented {:?}", token);
+        let mut ignored = 0;
+        if let Ok(samples) = args.lookup("samples") {
+            let samples = samples.bytes()?;
+            if samples.len() % NODE_ID_LEN != 0 {
+                return Err(DecodeError::WrongLength);
+            }
+            let nodes = match args.lookup("nodes") {
+                Ok(nodes) => Node4Info::parse_list(nodes.bytes()?, policy, &mut ignored)?,
+                Err(_) => vec![],
+            };
+            response = Response::Samples {
+                interval: args.lookup("interval")?.u32()?,
+                num: args.lookup("num")?.u32()?,
+                samples: samples.chunks(NODE_ID_LEN).map(|id| NodeId::from_slice(id).unwrap()).collect(),
+                nodes4: nodes,
+            };
+        } else if let Ok(v) = args.lookup("v") {
+            let nodes = match args.lookup("nodes") {
+                Ok(nodes) => Node4Info::parse_list(nodes.bytes()?, policy, &mut ignored)?,
+                Err(_) => vec![],
+            };
+            response = Response::Item {
+                token: args.lookup("token")?.bytes()?.to_vec(),
+                v: Some(v.clone()),
+                signed: Signature::from_args(args)?,
+                nodes4: nodes,
+            };
+        } else if let Ok(token) = args.lookup("token") {
+            // a `get` reply without `v` looks just like this, and means the same: no values
+            let values = match args.lookup("values") {
+                Ok(values) => Peer4Info::parse_values(values.array()?, policy.allow_private)?,
+                Err(_) => vec![],
+            };
+            let nodes = match args.lookup("nodes") {
+                Ok(nodes) => Node4Info::parse_list(nodes.bytes()?, policy, &mut ignored)?,
+                Err(_) => vec![],
+            };
+            response = Response::Peers {token: token.bytes()?.to_vec(), values: values, nodes4: nodes};
         } else if let Ok(nodes) = args.lookup("nodes") {
-            let nodes = Node4Info::parse_list(nodes.bytes()?)?;
+            check_nodes6(args, policy.max_nodes, &mut ignored)?;
+            let nodes = Node4Info::parse_list(nodes.bytes()?, policy, &mut ignored)?;
             response = Response::FoundNodes {nodes4: nodes};
+        } else if check_nodes6(args, policy.max_nodes, &mut ignored)? {
+            // an IPv6-only node; nothing here we can route to
+            response = Response::FoundNodes {nodes4: vec![]};
         } else {
             response = Response::Pong;
         }
@@ -340,16 +847,72 @@ impl FromBencode for FullResponse {
             response: response,
             sender_id: NodeId::from_bencode(args.lookup("id")?)?,
             tx_id: TxId::from_bencode(dict.lookup("t")?)?,
+            ip: parse_ip_key(dict),
+            ignored_nodes: ignored,
         })
     }
 }
 
+impl ToBencode for FullResponse {
+    fn to_bencode(&self) -> Bencode {
+        let mut args = BTreeMap::new();
+        args.insert(Bytes::from_str("id"), self.sender_id.to_bencode());
+        match self.response {
+            Response::Pong => (),
+            Response::FoundNodes {ref nodes4} => {
+                args.insert(Bytes::from_str("nodes"), Node4Info::compact_list(nodes4));
+            }
+            Response::Peers {ref token, ref values, ref nodes4} => {
+                args.insert(Bytes::from_str("token"), ByteString(token.clone()));
+                if !values.is_empty() {
+                    let values = values.iter().map(|peer| {
+                        let mut compact = Vec::with_capacity(6);
+                        peer.to_compact(&mut compact);
+                        ByteString(compact)
+                    }).collect();
+                    args.insert(Bytes::from_str("values"), List(values));
+                }
+                args.insert(Bytes::from_str("nodes"), Node4Info::compact_list(nodes4));
+            }
+            Response::Samples {interval, num, ref samples, ref nodes4} => {
+                let mut compact = Vec::with_capacity(samples.len() * NODE_ID_LEN);
+                for id in samples {
+                    compact.extend_from_slice(&id.0);
+                }
+                args.insert(Bytes::from_str("interval"), interval.to_bencode());
+                args.insert(Bytes::from_str("num"), num.to_bencode());
+                args.insert(Bytes::from_str("samples"), ByteString(compact));
+                args.insert(Bytes::from_str("nodes"), Node4Info::compact_list(nodes4));
+            }
+            Response::Item {ref token, ref v, ref signed, ref nodes4} => {
+                args.insert(Bytes::from_str("token"), ByteString(token.clone()));
+                if let Some(ref v) = *v {
+                    args.insert(Bytes::from_str("v"), v.clone());
+                }
+                if let Some(ref signed) = *signed {
+                    signed.add_args(&mut args);
+                }
+                args.insert(Bytes::from_str("nodes"), Node4Info::compact_list(nodes4));
+            }
+        }
+
+        let mut dict = BTreeMap::new();
+        dict.insert(Bytes::from_str("y"), 'r'.to_bencode());
+        dict.insert(Bytes::from_str("t"), self.tx_id.to_bencode());
+        dict.insert(Bytes::from_str("r"), Dict(args));
+        insert_ip_key(&mut dict, self.ip);
+        Dict(dict)
+    }
+}
+
 /// Describes an error reported by one node to another.
-#[derive(Debug)]
+#[derive(Debug, Eq, PartialEq)]
 pub struct DhtError {
     pub message: String,
     pub code: u32,
     pub tx_id: TxId,
+    /// BEP 42 `ip`: the querier's address as the sender of the error saw it.
+    pub ip: Option<SocketAddrV4>,
 }
 
 impl FromBencode for DhtError {
@@ -371,12 +934,26 @@ impl FromBencode for DhtError {
             message: message,
             code: code,
             tx_id: tx_id,
+            ip: parse_ip_key(dict),
         })
     }
 }
 
+impl ToBencode for DhtError {
+    fn to_bencode(&self) -> Bencode {
+        let args = vec![self.code.to_bencode(), ByteString(self.message.as_bytes().to_vec())];
+
+        let mut dict = BTreeMap::new();
+        dict.insert(Bytes::from_str("y"), 'e'.to_bencode());
+        dict.insert(Bytes::from_str("t"), self.tx_id.to_bencode());
+        dict.insert(Bytes::from_str("e"), List(args));
+        insert_ip_key(&mut dict, self.ip);
+        Dict(dict)
+    }
+}
+
 /// Any message that can be sent and received.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum DhtMessage {
     Query(FullQuery),
     Response(FullResponse),
@@ -397,14 +974,92 @@ impl FromBencode for DhtMessage {
     }
 }
 
+/// Top-level key carrying a private DHT's network identifier.
+const NETWORK_KEY: &'static str = "network";
+
+impl DhtMessage {
+    /// Decodes `b`, which must carry our identifier if we're on a private `network`.
+    ///
+    /// Mainline (`None`) accepts anything, as unknown keys are ignored there anyway. Replies
+    /// have their node and peer lists read under `policy`; see `FullResponse::decode`.
+    pub fn decode(b: &Bencode, network: Option<&[u8]>, policy: ListPolicy) -> DecodeResult<Self> {
+        if let Some(network) = network {
+            if b.dict()?.lookup(NETWORK_KEY)?.bytes()? != network {
+                return Err(DecodeError::WrongNetwork)
+            }
+        }
+        if b.dict()?.lookup("y")?.bytes()? == b"r" {
+            return Ok(DhtMessage::Response(FullResponse::decode(b, policy)?))
+        }
+        DhtMessage::from_bencode(b)
+    }
+}
+
+/// Marks an outgoing message as belonging to the private `network`.
+pub fn tag_network(msg: &mut Bencode, network: &[u8]) {
+    if let Dict(ref mut dict) = *msg {
+        dict.insert(Bytes::from_str(NETWORK_KEY), ByteString(network.to_vec()));
+    }
+}
+
 // ! Helpers
 
+/// Reads a message's BEP 42 `ip` key.
+///
+/// It's optional and purely informative, so a mangled one isn't worth rejecting the message over.
+fn parse_ip_key(dict: &DictMap) -> Option<SocketAddrV4> {
+    dict.lookup("ip").and_then(|ip| ip.bytes()).and_then(parse_compact_v4).ok()
+}
+
+fn insert_ip_key(dict: &mut DictMap, ip: Option<SocketAddrV4>) {
+    if let Some(ip) = ip {
+        let mut compact = Vec::with_capacity(6);
+        Peer4Info(ip).to_compact(&mut compact);
+        dict.insert(Bytes::from_str("ip"), ByteString(compact));
+    }
+}
+
+/// Reads a 6-byte compact address without judging whether it's usable.
+fn parse_compact_v4(b: &[u8]) -> DecodeResult<SocketAddrV4> {
+    if b.len() != 6 {
+        return Err(DecodeError::WrongLength);
+    }
+    let ip = Ipv4Addr::new(b[0], b[1], b[2], b[3]);
+    let port = ((b[4] as u16) << 8) + b[5] as u16;
+    Ok(SocketAddrV4::new(ip, port))
+}
+
+/// Whether `ip` is publicly routable, so worth handing out as a peer or node address.
+///
+/// Like the unstable `Ipv4Addr::is_global`, but also rules out shared (CGNAT) space,
+/// benchmarking and multicast/reserved ranges, none of which a remote node can reach.
+fn is_global_v4(ip: &Ipv4Addr) -> bool {
+    let o = ip.octets();
+    match (o[0], o[1], o[2]) {
+        (0, _, _) |                             // "this network"
+        (10, _, _) |                            // private
+        (127, _, _) |                           // loopback
+        (169, 254, _) |                         // link-local
+        (192, 168, _) |                         // private
+        (192, 0, 0) |                           // IETF protocol assignments
+        (192, 0, 2) |                           // documentation
+        (198, 51, 100) |                        // documentation
+        (203, 0, 113) => false,                 // documentation
+        (100, b, _) if b & 0xc0 == 64 => false, // shared address space (CGNAT)
+        (172, b, _) if b & 0xf0 == 16 => false, // private
+        (198, b, _) if b & 0xfe == 18 => false, // benchmarking
+        (a, _, _) if a >= 224 => false,         // multicast, reserved and broadcast
+        _ => true,
+    }
+}
+
 /// Provides Result-based Bencode unwrapping.
-trait BencodeExt {
+pub trait BencodeExt {
     fn array(&self) -> DecodeResult<&ListVec>;
     fn bytes(&self) -> DecodeResult<&[u8]>;
     fn dict(&self) -> DecodeResult<&DictMap>;
     fn u32(&self) -> DecodeResult<u32>;
+    fn i64(&self) -> DecodeResult<i64>;
 }
 
 impl BencodeExt for Bencode {
@@ -434,10 +1089,16 @@ impl BencodeExt for Bencode {
             _ => Err(DecodeError::WrongType),
         }
     }
+    fn i64(&self) -> DecodeResult<i64> {
+        match self {
+            &Number(n) => Ok(n),
+            _ => Err(DecodeError::WrongType),
+        }
+    }
 }
 
 /// Provides Result-based Bencode::Dict lookups.
-trait DictExt {
+pub trait DictExt {
     fn lookup<'a>(&'a self, &'static str) -> DecodeResult<&'a Bencode>;
 }
 
@@ -447,3 +1108,254 @@ impl DictExt for DictMap {
         self.get(&Bytes::from_str(key)).ok_or(DecodeError::KeyMissing(key))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+    use bencode::{self, FromBencode, ToBencode};
+    use bencode::Bencode::ByteString;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    use super::{DecodeError, DhtError, DhtMessage, FullQuery, FullResponse, ListPolicy, MAX_TX_ID_LEN, Node4Info,
+                NodeId, Peer4Info, Query, Response, Signature, TxId, TxIdAllocator, is_global_v4};
+
+    #[test]
+    fn node_ids_round_trip_through_hex() {
+        let hex = "0123456789abcdef0123456789abcdef01234567";
+        let id = NodeId::from_hex(hex).unwrap();
+        assert_eq!(id.0[0], 0x01);
+        assert_eq!(id.0[19], 0x67);
+        assert_eq!(id.to_hex(), hex);
+        assert_eq!(NodeId::from_hex(&hex.to_uppercase()).unwrap(), id);
+        for _ in 0..10 {
+            let id = NodeId::random();
+            assert_eq!(NodeId::from_hex(&id.to_hex()).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_hex() {
+        match NodeId::from_hex("0123") {
+            Err(DecodeError::WrongLength) => (),
+            other => panic!("expected WrongLength, got {:?}", other),
+        }
+        match NodeId::from_hex("0123456789abcdef0123456789abcdef012345678") {
+            Err(DecodeError::WrongLength) => (),
+            other => panic!("expected WrongLength, got {:?}", other),
+        }
+        match NodeId::from_hex("0123456789abcdef0123456789abcdef0123456g") {
+            Err(DecodeError::InvalidHex) => (),
+            other => panic!("expected InvalidHex, got {:?}", other),
+        }
+        // multibyte characters mustn't be split into digit pairs
+        assert!(NodeId::from_hex("é123456789abcdef0123456789abcdef0123456").is_err());
+    }
+
+    #[test]
+    fn only_global_v4_addresses_are_global() {
+        for ip in &["0.1.2.3", "10.0.0.1", "127.0.0.1", "169.254.1.1", "172.16.0.1", "172.31.255.255",
+                    "192.168.1.1", "100.64.0.1", "100.127.255.255", "192.0.2.1", "198.18.0.1",
+                    "224.0.0.1", "255.255.255.255"] {
+            assert!(!is_global_v4(&ip.parse().unwrap()), "{} isn't global", ip);
+        }
+        for ip in &["1.1.1.1", "8.8.8.8", "172.15.255.255", "172.32.0.1", "100.63.255.255",
+                    "100.128.0.1", "198.20.0.1", "223.255.255.254"] {
+            assert!(is_global_v4(&ip.parse().unwrap()), "{} is global", ip);
+        }
+    }
+
+    #[test]
+    fn parse_refuses_non_global_peers_unless_allowed() {
+        let loopback = [127, 0, 0, 1, 0x1a, 0xe1];
+        match Peer4Info::parse(&loopback) {
+            Err(DecodeError::InvalidAddress(ip)) => assert_eq!(ip, Ipv4Addr::new(127, 0, 0, 1)),
+            other => panic!("expected InvalidAddress, got {:?}", other),
+        }
+        assert_eq!(Peer4Info::parse_scoped(&loopback, true).unwrap().socket_addr(),
+                   "127.0.0.1:6881".parse().unwrap());
+        assert!(Peer4Info::parse(&[8, 8, 8, 8, 0x1a, 0xe1]).is_ok());
+    }
+
+    #[test]
+    fn tx_ids_wrap_and_skip_those_in_use() {
+        let mut tx_ids = TxIdAllocator::new();
+        tx_ids.short = 0xffff;
+        assert_eq!(tx_ids.next(|_| false), TxId::Short([0xff, 0xff]));
+        assert_eq!(tx_ids.next(|_| false), TxId::Short([0, 0]));
+        assert_eq!(tx_ids.next(|id| id == &TxId::Short([0, 1])), TxId::Short([0, 2]));
+        assert_eq!(tx_ids.issued(), 3);
+        assert_eq!(tx_ids.collisions(), 1);
+    }
+
+    #[test]
+    fn tx_ids_get_longer_once_every_short_one_is_in_use() {
+        let mut tx_ids = TxIdAllocator::new();
+        tx_ids.long = 0xffff_ffff;
+        let short_in_use = |id: &TxId| match *id {
+            TxId::Short(_) => true,
+            TxId::Arbitrary(_) => false,
+        };
+        let id = tx_ids.next(&short_in_use);
+        assert_eq!(id.as_slice(), &[0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(tx_ids.next(&short_in_use).as_slice(), &[0, 0, 0, 0]);
+        assert_eq!(tx_ids.collisions(), 2 << 16);
+    }
+
+    #[test]
+    fn tx_ids_from_peers_must_be_one_to_max_bytes() {
+        assert_eq!(TxId::from_bencode(&ByteString(vec![1, 2])).unwrap(), TxId::Short([1, 2]));
+        for len in &[1, 4, MAX_TX_ID_LEN] {
+            assert_eq!(TxId::from_bencode(&ByteString(vec![7; *len])).unwrap().as_slice(), &vec![7; *len][..]);
+        }
+        for len in &[0, MAX_TX_ID_LEN + 1, 1000] {
+            match TxId::from_bencode(&ByteString(vec![7; *len])) {
+                Err(DecodeError::WrongLength) => (),
+                other => panic!("{}-byte tx ID: expected WrongLength, got {:?}", len, other),
+            }
+        }
+    }
+
+    fn bytes<R: Rng>(rng: &mut R, min: usize, max: usize) -> Vec<u8> {
+        let len = rng.gen_range(min, max + 1);
+        rng.gen_iter().take(len).collect()
+    }
+
+    fn tx_id<R: Rng>(rng: &mut R) -> TxId {
+        TxId::from_bencode(&ByteString(bytes(rng, 1, MAX_TX_ID_LEN))).unwrap()
+    }
+
+    /// A reachable address, as `Peer4Info::parse` insists on.
+    fn addr<R: Rng>(rng: &mut R) -> SocketAddrV4 {
+        loop {
+            let ip: Ipv4Addr = Ipv4Addr::from(rng.gen::<u32>());
+            if is_global_v4(&ip) {
+                return SocketAddrV4::new(ip, rng.gen_range(1, 65535))
+            }
+        }
+    }
+
+    fn peers<R: Rng>(rng: &mut R) -> Vec<Peer4Info> {
+        (0..rng.gen_range(0, 8)).map(|_| Peer4Info(addr(rng))).collect()
+    }
+
+    fn nodes<R: Rng>(rng: &mut R) -> Vec<Node4Info> {
+        (0..rng.gen_range(0, 8)).map(|_| Node4Info {id: NodeId(rng.gen()), peer: Peer4Info(addr(rng))}).collect()
+    }
+
+    fn signature<R: Rng>(rng: &mut R) -> Option<Signature> {
+        if rng.gen() {
+            Some(Signature {k: bytes(rng, 32, 32), seq: rng.gen_range(0, 1 << 40), sig: bytes(rng, 64, 64)})
+        } else {
+            None
+        }
+    }
+
+    fn any_query<R: Rng>(rng: &mut R, variant: usize) -> FullQuery {
+        let query = match variant % 8 {
+            0 => Query::Ping,
+            1 => Query::FindNode(NodeId(rng.gen())),
+            2 => Query::GetPeers {info_hash: NodeId(rng.gen()), noseed: rng.gen()},
+            3 => Query::AnnouncePeer {
+                info_hash: NodeId(rng.gen()),
+                port: rng.gen(),
+                implied_port: rng.gen(),
+                token: bytes(rng, 1, 20),
+                seed: rng.gen(),
+            },
+            4 => Query::SampleInfohashes(NodeId(rng.gen())),
+            5 => Query::Get(NodeId(rng.gen())),
+            6 => Query::Put {
+                token: bytes(rng, 1, 20),
+                v: ByteString(bytes(rng, 0, 100)),
+                signed: signature(rng),
+                salt: bytes(rng, 0, 10),
+                cas: if rng.gen() { Some(rng.gen_range(0, 1 << 40)) } else { None },
+            },
+            _ => Query::Unknown("vote".to_string()),
+        };
+        FullQuery {
+            query: query,
+            sender_id: if rng.gen() { Some(NodeId(rng.gen())) } else { None },
+            tx_id: tx_id(rng),
+        }
+    }
+
+    fn any_response<R: Rng>(rng: &mut R, variant: usize) -> FullResponse {
+        let response = match variant % 5 {
+            0 => Response::Pong,
+            1 => Response::FoundNodes {nodes4: nodes(rng)},
+            2 => Response::Peers {token: bytes(rng, 1, 20), values: peers(rng), nodes4: nodes(rng)},
+            3 => Response::Samples {
+                interval: rng.gen(),
+                num: rng.gen(),
+                samples: (0..rng.gen_range(0, 5)).map(|_| NodeId(rng.gen())).collect(),
+                nodes4: nodes(rng),
+            },
+            // one without `v` reads back as `Peers`; see `FullResponse::decode`
+            _ => Response::Item {
+                token: bytes(rng, 1, 20),
+                v: Some(ByteString(bytes(rng, 0, 100))),
+                signed: signature(rng),
+                nodes4: nodes(rng),
+            },
+        };
+        FullResponse {
+            response: response,
+            sender_id: NodeId(rng.gen()),
+            tx_id: tx_id(rng),
+            ip: if rng.gen() { Some(addr(rng)) } else { None },
+            ignored_nodes: 0,
+        }
+    }
+
+    fn any_error<R: Rng>(rng: &mut R) -> DhtError {
+        let len = rng.gen_range(0, 40);
+        DhtError {
+            message: rng.gen_ascii_chars().take(len).collect(),
+            code: rng.gen(),
+            tx_id: tx_id(rng),
+            ip: if rng.gen() { Some(addr(rng)) } else { None },
+        }
+    }
+
+    /// What `msg` decodes to once encoded, as we'd read it off the wire.
+    fn round_trip<T: ToBencode>(msg: &T) -> DhtMessage {
+        let bytes = msg.to_bencode().to_bytes().unwrap();
+        DhtMessage::decode(&bencode::from_buffer(&bytes).unwrap(), None, ListPolicy::default()).unwrap()
+    }
+
+    #[test]
+    fn every_message_type_round_trips() {
+        let mut rng = XorShiftRng::from_seed([157, 1, 2, 3]);
+        for i in 0..400 {
+            let query = any_query(&mut rng, i);
+            assert_eq!(round_trip(&query), DhtMessage::Query(query));
+            let response = any_response(&mut rng, i);
+            assert_eq!(round_trip(&response), DhtMessage::Response(response));
+            let error = any_error(&mut rng);
+            assert_eq!(round_trip(&error), DhtMessage::Error(error));
+        }
+    }
+
+    #[test]
+    fn item_without_v_reads_back_as_peers() {
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 6881));
+        let nodes4 = vec![Node4Info::from_addr(&NodeId([5; 20]), &addr).unwrap()];
+        let item = FullResponse {
+            response: Response::Item {token: vec![1, 2, 3], v: None, signed: None, nodes4: nodes4.clone()},
+            sender_id: NodeId([4; 20]),
+            tx_id: TxId::Short([0, 1]),
+            ip: None,
+            ignored_nodes: 0,
+        };
+        match round_trip(&item) {
+            DhtMessage::Response(FullResponse {response: Response::Peers {token, values, nodes4: got}, ..}) => {
+                assert_eq!(token, vec![1, 2, 3]);
+                assert!(values.is_empty());
+                assert_eq!(got, nodes4);
+            }
+            other => panic!("expected Peers, got {:?}", other),
+        }
+    }
+}
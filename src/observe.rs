@@ -0,0 +1,60 @@
+/// Watching the infohashes other nodes ask us about, for crawlers and indexers.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use messages::NodeId;
+
+/// Which query showed interest in an infohash.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Interest {
+    /// Someone is looking for peers.
+    GetPeers,
+    /// Someone claims to be a peer.
+    Announce,
+}
+
+/// Called with each infohash queried, who asked, and how.
+pub type QueryHook = Box<FnMut(&NodeId, &SocketAddr, Interest)>;
+
+/// Passes inbound `get_peers` and `announce_peer` to a `QueryHook`, at most `max_per_sec`
+/// a second so a flood of queries can't swamp whatever is listening.
+pub struct Observer {
+    hook: QueryHook,
+    max_per_sec: usize,
+    /// Start of the current one-second window, and calls made in it.
+    window: Instant,
+    calls: usize,
+    /// Queries skipped for going over the limit.
+    dropped: u64,
+}
+
+impl Observer {
+    pub fn new(hook: QueryHook, max_per_sec: usize) -> Self {
+        Observer {
+            hook: hook,
+            max_per_sec: max_per_sec,
+            window: Instant::now(),
+            calls: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn observe(&mut self, info_hash: &NodeId, source: &SocketAddr, interest: Interest) {
+        if self.window.elapsed() >= Duration::from_secs(1) {
+            self.window = Instant::now();
+            self.calls = 0;
+        }
+        if self.calls >= self.max_per_sec {
+            self.dropped += 1;
+            return
+        }
+        self.calls += 1;
+        (self.hook)(info_hash, source, interest);
+    }
+
+    /// How many queries went unobserved because they came too fast.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
@@ -0,0 +1,356 @@
+/// Peers that have announced themselves to us, by infohash.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bencode::Bencode;
+use bencode::Bencode::{ByteString, Dict, Number};
+use bencode::util::ByteString as Bytes;
+use rand::{self, Rng};
+
+use messages::{BencodeExt, DecodeError, DecodeResult, DictExt, NodeId, Peer4Info};
+
+/// Announces lapse after this long unless renewed.
+const PEER_TTL_SECS: u64 = 30 * 60;
+
+/// Most peers we'll hand out in one `get_peers` reply.
+const MAX_VALUES: usize = 32;
+
+/// Most infohashes we store peers for; past this, the one announced to least recently goes.
+const MAX_INFOHASHES: usize = 2000;
+
+/// Most peers we store per infohash; past this, the oldest announce goes.
+const MAX_PEERS_PER_INFOHASH: usize = 500;
+
+/// Most infohashes we'll hand out in one `sample_infohashes` reply.
+pub const MAX_SAMPLES: usize = 20;
+
+/// Seconds we ask `sample_infohashes` callers to wait before asking again.
+pub const SAMPLE_INTERVAL_SECS: u32 = 6 * 60 * 60;
+
+/// Version of the `PeerStore::export` format; bump it on any incompatible change.
+const EXPORT_VERSION: i64 = 2;
+
+/// Bytes per saved announce: the compact peer, a 4-byte UNIX time, then 1 if it's a seed.
+/// Version 1 records lack the seed byte.
+const EXPORT_RECORD_LEN: usize = 11;
+
+/// Serves `get_peers` from the `announce_peer`s we've accepted.
+pub struct PeerStore {
+    /// Announced peers, when they last announced and whether they said they're seeding,
+    /// oldest first.
+    peers: HashMap<NodeId, Vec<(Peer4Info, Instant, bool)>>,
+    max_infohashes: usize,
+    max_peers: usize,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        PeerStore::with_limits(MAX_INFOHASHES, MAX_PEERS_PER_INFOHASH)
+    }
+
+    /// A store holding peers for at most `max_infohashes` infohashes, and at most
+    /// `max_peers` for each.
+    pub fn with_limits(max_infohashes: usize, max_peers: usize) -> Self {
+        PeerStore {
+            peers: HashMap::new(),
+            max_infohashes: max_infohashes,
+            max_peers: max_peers,
+        }
+    }
+
+    /// Records (or renews) `peer` as downloading `info_hash`, or as seeding it.
+    ///
+    /// Announcers choose the infohashes and can come from many addresses, so past our limits
+    /// the stalest announces make way.
+    pub fn announce(&mut self, info_hash: &NodeId, peer: Peer4Info, seed: bool) {
+        self.make_room_for(info_hash);
+        let max_peers = self.max_peers;
+        let entries = self.peers.entry(*info_hash).or_insert_with(Vec::new);
+        entries.retain(|&(p, _, _)| p.socket_addr() != peer.socket_addr());
+        entries.push((peer, Instant::now(), seed));
+        if entries.len() > max_peers {
+            let excess = entries.len() - max_peers;
+            entries.drain(..excess);
+        }
+    }
+
+    /// The most recently announced live peers for `info_hash`, leaving out seeds if
+    /// `noseed`.
+    pub fn get(&mut self, info_hash: &NodeId, noseed: bool) -> Vec<Peer4Info> {
+        let ttl = Duration::from_secs(PEER_TTL_SECS);
+        let now_empty = match self.peers.get_mut(info_hash) {
+            Some(entries) => {
+                entries.retain(|&(_, announced, _)| announced.elapsed() < ttl);
+                entries.is_empty()
+            }
+            None => return vec![],
+        };
+        if now_empty {
+            self.peers.remove(info_hash);
+            return vec![]
+        }
+        self.peers[info_hash].iter().rev()
+                             .filter(|&&(_, _, seed)| !(noseed && seed))
+                             .take(MAX_VALUES)
+                             .map(|&(peer, _, _)| peer)
+                             .collect()
+    }
+
+    /// Up to `MAX_SAMPLES` random infohashes with live peers, and how many there are in all.
+    pub fn sample(&mut self) -> (Vec<NodeId>, usize) {
+        self.prune();
+        let mut infohashes: Vec<NodeId> = self.peers.keys().cloned().collect();
+        let total = infohashes.len();
+        rand::thread_rng().shuffle(&mut infohashes);
+        infohashes.truncate(MAX_SAMPLES);
+        (infohashes, total)
+    }
+
+    /// Each infohash with live peers, and how many it has. Expired announces aren't counted.
+    pub fn infohashes(&self) -> Vec<(NodeId, usize)> {
+        let ttl = Duration::from_secs(PEER_TTL_SECS);
+        self.peers.iter()
+            .map(|(info_hash, entries)| {
+                (*info_hash, entries.iter().filter(|&&(_, announced, _)| announced.elapsed() < ttl).count())
+            })
+            .filter(|&(_, live)| live > 0)
+            .collect()
+    }
+
+    /// The live announces, to be loaded again by `import` after a restart.
+    ///
+    /// A bencoded dict: `v` is `EXPORT_VERSION`, and `peers` maps each infohash to a string
+    /// of 11-byte records, each a compact peer, the big-endian UNIX time it last announced,
+    /// and a seed flag byte. Wall-clock times let `import` tell how long we were down.
+    pub fn export(&mut self) -> Bencode {
+        self.prune();
+        let now = unix_now();
+        let mut peers = BTreeMap::new();
+        for (info_hash, entries) in &self.peers {
+            let mut records = Vec::with_capacity(entries.len() * EXPORT_RECORD_LEN);
+            for &(peer, announced, seed) in entries {
+                peer.to_compact(&mut records);
+                let at = now.saturating_sub(announced.elapsed().as_secs()) as u32;
+                records.extend_from_slice(&[(at >> 24) as u8, (at >> 16) as u8, (at >> 8) as u8, at as u8]);
+                records.push(seed as u8);
+            }
+            peers.insert(Bytes::from_slice(&info_hash.0), ByteString(records));
+        }
+        let mut dict = BTreeMap::new();
+        dict.insert(Bytes::from_str("v"), Number(EXPORT_VERSION));
+        dict.insert(Bytes::from_str("peers"), Dict(peers));
+        Dict(dict)
+    }
+
+    /// Loads announces saved by `export`, skipping any that have expired since, and returns
+    /// how many it kept. Announces we already hold are left alone if they're newer.
+    ///
+    /// Version 1 files still load, their peers all taken as non-seeds.
+    pub fn import(&mut self, saved: &Bencode) -> DecodeResult<usize> {
+        let dict = saved.dict()?;
+        let record_len = match dict.lookup("v")?.i64()? {
+            1 => 10,
+            EXPORT_VERSION => EXPORT_RECORD_LEN,
+            _ => return Err(DecodeError::OutOfRange),
+        };
+        let ttl = Duration::from_secs(PEER_TTL_SECS);
+        let now = unix_now();
+        let mut loaded = 0;
+        for (info_hash, records) in dict.lookup("peers")?.dict()? {
+            let info_hash = NodeId::from_slice(info_hash.as_slice())?;
+            let records = records.bytes()?;
+            if records.len() % record_len != 0 {
+                return Err(DecodeError::WrongLength)
+            }
+            for record in records.chunks(record_len) {
+                let peer = Peer4Info::parse(&record[..6])?;
+                let at = record[6..10].iter().fold(0u64, |at, &b| (at << 8) | b as u64);
+                let seed = record.len() > 10 && record[10] != 0;
+                let age = Duration::from_secs(now.saturating_sub(at));
+                if age >= ttl {
+                    continue
+                }
+                let announced = Instant::now() - age;
+                self.make_room_for(&info_hash);
+                let max_peers = self.max_peers;
+                let entries = self.peers.entry(info_hash).or_insert_with(Vec::new);
+                if entries.iter().any(|&(p, when, _)| p == peer && when >= announced) {
+                    continue
+                }
+                entries.retain(|&(p, _, _)| p != peer);
+                // keep oldest first, as `get` expects
+                let i = entries.iter().position(|&(_, when, _)| when > announced).unwrap_or(entries.len());
+                entries.insert(i, (peer, announced, seed));
+                if entries.len() > max_peers {
+                    entries.remove(0);
+                }
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Drops the infohash announced to least recently if `info_hash` would be one too many.
+    fn make_room_for(&mut self, info_hash: &NodeId) {
+        if self.peers.contains_key(info_hash) || self.peers.len() < self.max_infohashes {
+            return
+        }
+        let stalest = self.peers.iter()
+                                .min_by_key(|&(_, entries)| entries.last().map(|&(_, announced, _)| announced))
+                                .map(|(info_hash, _)| *info_hash);
+        if let Some(stalest) = stalest {
+            self.peers.remove(&stalest);
+        }
+    }
+
+    /// Drops expired announces, and infohashes left with none.
+    fn prune(&mut self) {
+        let ttl = Duration::from_secs(PEER_TTL_SECS);
+        let mut emptied = vec![];
+        for (info_hash, entries) in self.peers.iter_mut() {
+            entries.retain(|&(_, announced, _)| announced.elapsed() < ttl);
+            if entries.is_empty() {
+                emptied.push(*info_hash);
+            }
+        }
+        for info_hash in emptied {
+            self.peers.remove(&info_hash);
+        }
+    }
+}
+
+/// Peers our own `get_peers` lookups turned up, kept briefly so asking again soon after
+/// needn't go back to the network.
+pub struct PeerCache {
+    /// What each lookup found, and when it finished.
+    found: HashMap<NodeId, (Vec<Peer4Info>, Instant)>,
+    /// Most infohashes we remember; zero turns the cache off.
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl PeerCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        PeerCache {
+            found: HashMap::new(),
+            capacity: capacity,
+            ttl: ttl,
+        }
+    }
+
+    /// Remembers the peers a lookup for `info_hash` found, replacing any earlier result.
+    ///
+    /// The oldest result makes way once we're at capacity. Empty results aren't kept.
+    pub fn insert(&mut self, info_hash: NodeId, peers: Vec<Peer4Info>) {
+        if self.capacity == 0 || peers.is_empty() {
+            return
+        }
+        if !self.found.contains_key(&info_hash) && self.found.len() >= self.capacity {
+            let oldest = self.found.iter().min_by_key(|&(_, &(_, found))| found).map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.found.remove(&oldest);
+            }
+        }
+        self.found.insert(info_hash, (peers, Instant::now()));
+    }
+
+    /// The peers last found for `info_hash`, unless that was longer ago than the TTL.
+    pub fn get(&mut self, info_hash: &NodeId) -> Option<Vec<Peer4Info>> {
+        match self.found.get(info_hash) {
+            Some(&(ref peers, found)) if found.elapsed() < self.ttl => return Some(peers.clone()),
+            _ => (),
+        }
+        self.found.remove(info_hash);
+        None
+    }
+}
+
+/// Seconds since the UNIX epoch, by the wall clock.
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+
+    use messages::{NodeId, Peer4Info};
+    use super::PeerStore;
+
+    fn peer(port: u16) -> Peer4Info {
+        let addr: SocketAddr = format!("203.0.113.7:{}", port).parse().unwrap();
+        Peer4Info::from_socket_addr(&addr).unwrap()
+    }
+
+    fn info_hash(n: u8) -> NodeId {
+        NodeId([n; 20])
+    }
+
+    #[test]
+    fn announce_renews_rather_than_duplicates() {
+        let mut store = PeerStore::new();
+        store.announce(&info_hash(1), peer(1000), false);
+        store.announce(&info_hash(1), peer(1000), true);
+        assert_eq!(store.get(&info_hash(1), false), vec![peer(1000)]);
+        // now a seed
+        assert_eq!(store.get(&info_hash(1), true), vec![]);
+    }
+
+    #[test]
+    fn oldest_peer_makes_way_past_the_per_infohash_cap() {
+        let mut store = PeerStore::with_limits(10, 2);
+        store.announce(&info_hash(1), peer(1000), false);
+        store.announce(&info_hash(1), peer(1001), false);
+        store.announce(&info_hash(1), peer(1002), false);
+        assert_eq!(store.get(&info_hash(1), false), vec![peer(1002), peer(1001)]);
+    }
+
+    #[test]
+    fn stalest_infohash_makes_way_past_the_total_cap() {
+        let mut store = PeerStore::with_limits(2, 10);
+        store.announce(&info_hash(1), peer(1000), false);
+        thread::sleep(Duration::from_millis(2));
+        store.announce(&info_hash(2), peer(1000), false);
+        thread::sleep(Duration::from_millis(2));
+        // renewing 1 leaves 2 the stalest
+        store.announce(&info_hash(1), peer(1001), false);
+        thread::sleep(Duration::from_millis(2));
+        store.announce(&info_hash(3), peer(1000), false);
+
+        let mut stored: Vec<NodeId> = store.infohashes().into_iter().map(|(info_hash, _)| info_hash).collect();
+        stored.sort();
+        assert_eq!(stored, vec![info_hash(1), info_hash(3)]);
+        assert_eq!(store.get(&info_hash(2), false), vec![]);
+    }
+
+    #[test]
+    fn export_then_import_keeps_peers_and_seed_flags() {
+        let mut store = PeerStore::new();
+        store.announce(&info_hash(1), peer(1000), false);
+        store.announce(&info_hash(1), peer(1001), true);
+        store.announce(&info_hash(2), peer(1002), false);
+        let saved = store.export();
+
+        let mut restored = PeerStore::new();
+        assert_eq!(restored.import(&saved).unwrap(), 3);
+        assert_eq!(restored.get(&info_hash(1), false), vec![peer(1001), peer(1000)]);
+        assert_eq!(restored.get(&info_hash(1), true), vec![peer(1000)]);
+        assert_eq!(restored.get(&info_hash(2), false), vec![peer(1002)]);
+    }
+
+    #[test]
+    fn import_respects_the_per_infohash_cap() {
+        let mut store = PeerStore::new();
+        for port in 1000..1005 {
+            store.announce(&info_hash(1), peer(port), false);
+        }
+        let saved = store.export();
+
+        let mut restored = PeerStore::with_limits(10, 3);
+        restored.import(&saved).unwrap();
+        assert_eq!(restored.get(&info_hash(1), false).len(), 3);
+    }
+}
@@ -0,0 +1,80 @@
+/// Working out whether nodes outside can reach us, from how they see our address.
+
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+/// Reports needed before we'll venture a guess.
+pub const MIN_REPORTS: usize = 3;
+
+/// Only the most recent reports count, so a change in NAT mapping shows up eventually.
+const MAX_REPORTS: usize = 8;
+
+/// Unsolicited queries it takes, inside `INBOUND_WINDOW_SECS`, to show others can reach us.
+const MIN_INBOUND: usize = 3;
+
+/// How recent those queries must be; a NAT mapping that lapsed since shouldn't count.
+const INBOUND_WINDOW_SECS: u64 = 15 * 60;
+
+/// Tallies the `ip` echoes in replies to our queries.
+///
+/// If other nodes see us on the port we're bound to, nothing is rewriting it in between
+/// and we're most likely reachable; if they see another port, a NAT is in the way, and
+/// announces should use `implied_port`.
+pub struct Reachability {
+    bound_port: u16,
+    /// Our address as recently reported, oldest first.
+    reports: VecDeque<SocketAddrV4>,
+    /// When the last few queries from other nodes arrived, oldest first.
+    inbound: VecDeque<Instant>,
+}
+
+impl Reachability {
+    pub fn new(bound_port: u16) -> Self {
+        Reachability {
+            bound_port: bound_port,
+            reports: VecDeque::with_capacity(MAX_REPORTS),
+            inbound: VecDeque::with_capacity(MIN_INBOUND),
+        }
+    }
+
+    /// Records that a node saw our query come from `seen_as`.
+    pub fn report(&mut self, seen_as: SocketAddrV4) {
+        if self.reports.len() == MAX_REPORTS {
+            self.reports.pop_front();
+        }
+        self.reports.push_back(seen_as);
+    }
+
+    /// Records that a node sent us a query of its own.
+    pub fn inbound_query(&mut self) {
+        if self.inbound.len() == MIN_INBOUND {
+            self.inbound.pop_front();
+        }
+        self.inbound.push_back(Instant::now());
+    }
+
+    /// Whether other nodes have been querying us lately.
+    ///
+    /// Replies to our own queries get through most NATs, so pongs alone don't show we're
+    /// reachable; queries nobody asked for do. If this stays false while `is_reachable` says
+    /// true, we're probably behind a NAT that only lets replies in.
+    pub fn inbound_observed(&self) -> bool {
+        let window = Duration::from_secs(INBOUND_WINDOW_SECS);
+        self.inbound.len() == MIN_INBOUND && self.inbound[0].elapsed() < window
+    }
+
+    /// Our address as the latest report had it.
+    pub fn external(&self) -> Option<SocketAddrV4> {
+        self.reports.back().cloned()
+    }
+
+    /// Whether most recent reports have us on our bound port; `None` until there are enough.
+    pub fn is_reachable(&self) -> Option<bool> {
+        if self.reports.len() < MIN_REPORTS {
+            return None
+        }
+        let matching = self.reports.iter().filter(|addr| addr.port() == self.bound_port).count();
+        Some(matching * 2 > self.reports.len())
+    }
+}
@@ -0,0 +1,117 @@
+/// Transactions we finished lately, so a second reply to one can be told from a bogus one.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use messages::TxId;
+
+/// The last few transactions answered, with who answered and when.
+///
+/// Bounded: recording one more past `capacity` forgets the oldest. Transaction IDs get
+/// reused, so an entry is only good until the ID is issued again; `received` checks
+/// `txs` first, and `forget` drops an ID as it's reissued.
+pub struct RecentTxs {
+    capacity: usize,
+    /// Oldest first.
+    order: VecDeque<TxId>,
+    answered: HashMap<TxId, (SocketAddr, Instant)>,
+}
+
+impl RecentTxs {
+    /// Remembers up to `capacity` transactions; zero remembers none.
+    pub fn new(capacity: usize) -> Self {
+        RecentTxs {
+            capacity: capacity,
+            order: VecDeque::new(),
+            answered: HashMap::new(),
+        }
+    }
+
+    /// Notes that `addr` just answered `tx_id`.
+    pub fn record(&mut self, tx_id: TxId, addr: SocketAddr) {
+        if self.capacity == 0 {
+            return
+        }
+        if self.answered.insert(tx_id.clone(), (addr, Instant::now())).is_some() {
+            self.order.retain(|recent| recent != &tx_id);
+        } else if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.answered.remove(&oldest);
+            }
+        }
+        self.order.push_back(tx_id);
+    }
+
+    /// When `addr` answered `tx_id`, if it's one of the transactions we remember.
+    pub fn answered(&self, tx_id: &TxId, addr: &SocketAddr) -> Option<Instant> {
+        match self.answered.get(tx_id) {
+            Some(&(answerer, at)) if &answerer == addr => Some(at),
+            _ => None,
+        }
+    }
+
+    /// Drops `tx_id`, which is about to name a new transaction.
+    pub fn forget(&mut self, tx_id: &TxId) {
+        if self.answered.remove(tx_id).is_some() {
+            self.order.retain(|recent| recent != tx_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use messages::TxId;
+    use super::RecentTxs;
+
+    fn tx(n: u8) -> TxId {
+        TxId::Short([0, n])
+    }
+
+    #[test]
+    fn remembers_who_answered() {
+        let a: SocketAddr = "198.51.100.1:6881".parse().unwrap();
+        let b: SocketAddr = "198.51.100.2:6881".parse().unwrap();
+        let mut recent = RecentTxs::new(4);
+        recent.record(tx(1), a);
+        assert!(recent.answered(&tx(1), &a).is_some());
+        assert!(recent.answered(&tx(1), &b).is_none());
+        assert!(recent.answered(&tx(2), &a).is_none());
+        recent.forget(&tx(1));
+        assert!(recent.answered(&tx(1), &a).is_none());
+    }
+
+    #[test]
+    fn forgets_the_oldest_past_capacity() {
+        let a: SocketAddr = "198.51.100.1:6881".parse().unwrap();
+        let mut recent = RecentTxs::new(3);
+        for n in 1..4 {
+            recent.record(tx(n), a);
+        }
+        // recording one again makes it the newest
+        recent.record(tx(1), a);
+        recent.record(tx(4), a);
+        assert!(recent.answered(&tx(2), &a).is_none());
+        for n in &[1, 3, 4] {
+            assert!(recent.answered(&tx(*n), &a).is_some(), "tx {} forgotten", n);
+        }
+        assert_eq!(recent.order.len(), 3);
+        assert_eq!(recent.answered.len(), 3);
+
+        // a forgotten one frees its slot
+        recent.forget(&tx(3));
+        recent.record(tx(5), a);
+        assert!(recent.answered(&tx(1), &a).is_some());
+        assert_eq!(recent.order.len(), 3);
+    }
+
+    #[test]
+    fn zero_capacity_remembers_nothing() {
+        let a: SocketAddr = "198.51.100.1:6881".parse().unwrap();
+        let mut recent = RecentTxs::new(0);
+        recent.record(tx(1), a);
+        assert!(recent.answered(&tx(1), &a).is_none());
+    }
+}
@@ -0,0 +1,21 @@
+/// Turning bootstrap names into addresses.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// Looks up a `host:port` name, so bootstrap needn't depend on the system resolver.
+///
+/// Plug in another implementation to resolve over DNS-over-HTTPS, say, or to pin names to
+/// fixed addresses where DNS is blocked.
+pub trait Resolver {
+    fn resolve(&self, name: &str) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves through the OS, via `ToSocketAddrs`.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, name: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(name.to_socket_addrs()?.collect())
+    }
+}
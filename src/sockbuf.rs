@@ -0,0 +1,46 @@
+/// Sizing the kernel's buffers for our UDP socket.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+use libc::{self, c_int, c_void, socklen_t};
+
+/// Which of a socket's buffers to size.
+#[derive(Clone, Copy, Debug)]
+pub enum Buffer {
+    Recv,
+    Send,
+}
+
+impl Buffer {
+    fn option(self) -> c_int {
+        match self {
+            Buffer::Recv => libc::SO_RCVBUF,
+            Buffer::Send => libc::SO_SNDBUF,
+        }
+    }
+}
+
+/// Asks the kernel for a `size`-byte buffer. It may round or clamp the request (Linux
+/// doubles it, and caps it at `net.core.rmem_max`/`wmem_max`), so check with `get`.
+pub fn set<S: AsRawFd>(sock: &S, buffer: Buffer, size: usize) -> io::Result<()> {
+    let size = size as c_int;
+    let ret = unsafe {
+        libc::setsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, buffer.option(),
+                         &size as *const c_int as *const c_void,
+                         mem::size_of::<c_int>() as socklen_t)
+    };
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/// The buffer size the kernel is actually using.
+pub fn get<S: AsRawFd>(sock: &S, buffer: Buffer) -> io::Result<usize> {
+    let mut size: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, buffer.option(),
+                         &mut size as *mut c_int as *mut c_void, &mut len)
+    };
+    if ret == 0 { Ok(size as usize) } else { Err(io::Error::last_os_error()) }
+}
@@ -0,0 +1,126 @@
+/// Point-in-time figures about a running node.
+
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use lookup::LookupId;
+use messages::{DecodeError, TxId};
+
+/// A snapshot from `ServerHandler::stats`.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// Depth of the routing table.
+    pub buckets: usize,
+    /// Queries of ours awaiting a reply.
+    pub transactions: usize,
+    /// Transaction IDs issued since startup.
+    pub tx_ids_issued: u64,
+    /// Transaction IDs skipped since startup because they were still outstanding.
+    pub tx_id_collisions: u64,
+    /// Iterative lookups in progress.
+    pub lookups: usize,
+    /// Infohashes we're storing live peers for.
+    pub infohashes: usize,
+    /// Whether nodes outside can reach us, if we know yet.
+    pub reachable: Option<bool>,
+    /// Whether other nodes have been sending us queries lately; see
+    /// `Reachability::inbound_observed`.
+    pub inbound_observed: bool,
+    /// Time until the refresh scheduler next looks for a stale bucket.
+    pub next_refresh: Duration,
+    /// Messages we've failed to decode since startup, by reason.
+    pub decode_errors: DecodeErrorCounts,
+    /// Datagrams too big for our receive buffer, dropped unread since startup.
+    pub truncated_datagrams: u64,
+}
+
+/// The routing table at a glance, from `ServerHandler::summary`.
+///
+/// Displays as one greppable line, `buckets=N good=G questionable=Q total=T txs=X`.
+#[derive(Clone, Copy, Debug)]
+pub struct Summary {
+    /// Depth of the routing table.
+    pub buckets: usize,
+    /// Nodes that have answered us.
+    pub good: usize,
+    /// Nodes we're still pinging to verify.
+    pub questionable: usize,
+    pub total: usize,
+    /// Queries of ours awaiting a reply.
+    pub txs: usize,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "buckets={} good={} questionable={} total={} txs={}",
+               self.buckets, self.good, self.questionable, self.total, self.txs)
+    }
+}
+
+/// One of our queries awaiting a reply, from `ServerHandler::pending_transactions`.
+///
+/// The only query we resend is one refused with error 202, as the same transaction kind
+/// under a new ID.
+#[derive(Clone, Debug)]
+pub struct TxInfo {
+    pub tx_id: TxId,
+    /// Where the query went.
+    pub addr: SocketAddr,
+    /// Its KRPC method, such as `find_node`.
+    pub method: &'static str,
+    /// What we sent it for, such as `lookup` or `bootstrap`.
+    pub purpose: &'static str,
+    /// The lookup it belongs to, if any.
+    pub lookup: Option<LookupId>,
+    /// Times the query was resent after a server error.
+    pub retries: u32,
+    /// Time left until it times out.
+    pub expires_in: Duration,
+}
+
+/// One line per transaction: `TX method to ADDR for PURPOSE [lookup N] retries=R expires_in=MSms`.
+impl Display for TxInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?} {} to {} for {}", self.tx_id, self.method, self.addr, self.purpose)?;
+        if let Some(lookup) = self.lookup {
+            write!(f, " lookup {}", lookup)?;
+        }
+        let ms = self.expires_in.as_secs() * 1000 + (self.expires_in.subsec_nanos() / 1_000_000) as u64;
+        write!(f, " retries={} expires_in={}ms", self.retries, ms)
+    }
+}
+
+/// Running totals of `DecodeError`s, one per variant.
+///
+/// A spike in one of these (say `wrong_length`) usually points at a buggy peer implementation.
+#[derive(Clone, Debug, Default)]
+pub struct DecodeErrorCounts {
+    pub key_missing: u64,
+    pub invalid_address: u64,
+    pub invalid_discrim: u64,
+    pub invalid_hex: u64,
+    pub out_of_range: u64,
+    pub wrong_discrim: u64,
+    pub wrong_length: u64,
+    pub wrong_network: u64,
+    pub wrong_type: u64,
+}
+
+impl DecodeErrorCounts {
+    /// Bumps the counter for `error`'s variant.
+    pub fn record(&mut self, error: &DecodeError) {
+        let counter = match *error {
+            DecodeError::KeyMissing(_) => &mut self.key_missing,
+            DecodeError::InvalidAddress(_) => &mut self.invalid_address,
+            DecodeError::InvalidDiscrim => &mut self.invalid_discrim,
+            DecodeError::InvalidHex => &mut self.invalid_hex,
+            DecodeError::OutOfRange => &mut self.out_of_range,
+            DecodeError::WrongDiscrim => &mut self.wrong_discrim,
+            DecodeError::WrongLength => &mut self.wrong_length,
+            DecodeError::WrongNetwork => &mut self.wrong_network,
+            DecodeError::WrongType => &mut self.wrong_type,
+        };
+        *counter += 1;
+    }
+}
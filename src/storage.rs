@@ -0,0 +1,246 @@
+/// Arbitrary data items stored on behalf of others (BEP 44).
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use bencode::Bencode;
+use crypto::ed25519;
+use sha1::Sha1;
+
+use messages::{NodeId, Signature};
+
+/// Largest bencoded `v` we'll accept.
+pub const MAX_VALUE_LEN: usize = 1000;
+
+/// Largest `salt` we'll accept on a mutable item.
+pub const MAX_SALT_LEN: usize = 64;
+
+/// Items lapse after this long unless put again.
+const ITEM_TTL_SECS: u64 = 2 * 60 * 60;
+
+/// Why a `put` was refused; maps onto BEP 44's error codes.
+#[derive(Debug)]
+pub enum PutError {
+    /// `v` is over `MAX_VALUE_LEN` bytes.
+    TooBig,
+    /// `v` couldn't be re-encoded to hash it.
+    Malformed,
+    /// A mutable item's signature doesn't check out.
+    BadSignature,
+    /// `salt` is over `MAX_SALT_LEN` bytes.
+    SaltTooBig,
+    /// The put named a `cas` sequence number, but we hold a different version.
+    CasMismatch,
+    /// We already hold a newer version of the item.
+    StaleSeq,
+}
+
+impl PutError {
+    pub fn code(&self) -> u32 {
+        match *self {
+            PutError::TooBig => 205,
+            PutError::Malformed => 203,
+            PutError::BadSignature => 206,
+            PutError::SaltTooBig => 207,
+            PutError::CasMismatch => 301,
+            PutError::StaleSeq => 302,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match *self {
+            PutError::TooBig => "message (v field) too big",
+            PutError::Malformed => "invalid v",
+            PutError::BadSignature => "invalid signature",
+            PutError::SaltTooBig => "salt (salt field) too big",
+            PutError::CasMismatch => "the CAS hash mismatched, re-read value and try again",
+            PutError::StaleSeq => "sequence number less than current",
+        }
+    }
+}
+
+/// The key an immutable item is filed under: the SHA-1 of its bencoded form.
+pub fn immutable_target(encoded: &[u8]) -> NodeId {
+    let mut sha = Sha1::new();
+    sha.update(encoded);
+    NodeId(sha.digest().bytes())
+}
+
+/// The key a mutable item is filed under: the SHA-1 of its public key and salt.
+pub fn mutable_target(k: &[u8], salt: &[u8]) -> NodeId {
+    let mut sha = Sha1::new();
+    sha.update(k);
+    sha.update(salt);
+    NodeId(sha.digest().bytes())
+}
+
+/// The bytes a mutable item's signature covers: its salt (if any), `seq` and `v`, spelled
+/// out as they'd appear in a bencoded dict, without the enclosing `d`..`e`.
+pub fn signable(salt: &[u8], seq: i64, encoded_v: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    if !salt.is_empty() {
+        buf.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        buf.extend_from_slice(salt);
+    }
+    buf.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    buf.extend_from_slice(encoded_v);
+    buf
+}
+
+struct Item {
+    v: Bencode,
+    /// Present for mutable items.
+    signed: Option<Signature>,
+    stored: Instant,
+}
+
+/// Serves `get` from the items `put` to us.
+pub struct ItemStore {
+    items: HashMap<NodeId, Item>,
+}
+
+impl ItemStore {
+    pub fn new() -> Self {
+        ItemStore {items: HashMap::new()}
+    }
+
+    /// Stores (or renews) immutable item `v`, returning the key it's now under.
+    pub fn put_immutable(&mut self, v: Bencode) -> Result<NodeId, PutError> {
+        let encoded = v.to_bytes().map_err(|_| PutError::Malformed)?;
+        if encoded.len() > MAX_VALUE_LEN {
+            return Err(PutError::TooBig)
+        }
+        let target = immutable_target(&encoded);
+        self.items.insert(target, Item {v: v, signed: None, stored: Instant::now()});
+        Ok(target)
+    }
+
+    /// Stores mutable item `v` if `signed` vouches for it and it's no older than what we have.
+    ///
+    /// With `cas`, the put only succeeds if our current version has exactly that sequence number.
+    pub fn put_mutable(&mut self, v: Bencode, signed: Signature, salt: &[u8], cas: Option<i64>)
+        -> Result<NodeId, PutError>
+    {
+        let encoded = v.to_bytes().map_err(|_| PutError::Malformed)?;
+        if encoded.len() > MAX_VALUE_LEN {
+            return Err(PutError::TooBig)
+        }
+        if salt.len() > MAX_SALT_LEN {
+            return Err(PutError::SaltTooBig)
+        }
+        if signed.k.len() != 32 || signed.sig.len() != 64 ||
+           !ed25519::verify(&signable(salt, signed.seq, &encoded), &signed.k, &signed.sig) {
+            return Err(PutError::BadSignature)
+        }
+
+        let target = mutable_target(&signed.k, salt);
+        let current = self.live(&target).and_then(|item| item.signed.as_ref()).map(|s| s.seq);
+        if let Some(current) = current {
+            if cas.map_or(false, |cas| cas != current) {
+                return Err(PutError::CasMismatch)
+            }
+            if signed.seq < current {
+                return Err(PutError::StaleSeq)
+            }
+        }
+        self.items.insert(target, Item {v: v, signed: Some(signed), stored: Instant::now()});
+        Ok(target)
+    }
+
+    /// The live item under `target`, if we have one, and its signature if it's mutable.
+    pub fn get(&mut self, target: &NodeId) -> Option<(Bencode, Option<Signature>)> {
+        let found = self.live(target).map(|item| (item.v.clone(), item.signed.clone()));
+        if found.is_none() {
+            self.items.remove(target);
+        }
+        found
+    }
+
+    /// The item under `target`, unless it's missing or expired.
+    fn live(&self, target: &NodeId) -> Option<&Item> {
+        match self.items.get(target) {
+            Some(item) if item.stored.elapsed() < Duration::from_secs(ITEM_TTL_SECS) => Some(item),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bencode::Bencode;
+    use bencode::Bencode::ByteString;
+
+    use messages::{NodeId, Signature};
+    use super::{ItemStore, MAX_VALUE_LEN, PutError, immutable_target, mutable_target, signable};
+
+    // BEP 44's test vectors
+    const K: &'static str = "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548";
+    const SIG: &'static str = "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+                               1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01";
+    const SALTED_SIG: &'static str = "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d\
+                                      df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08";
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap()).collect()
+    }
+
+    fn hello() -> Bencode {
+        ByteString(b"Hello World!".to_vec())
+    }
+
+    #[test]
+    fn matches_bep44_immutable_vector() {
+        assert_eq!(immutable_target(b"12:Hello World!"),
+                   NodeId::from_hex("e5f96f6f38320f0f33959cb4d3d656452117aadb").unwrap());
+        let mut items = ItemStore::new();
+        assert_eq!(items.put_immutable(hello()).unwrap(), immutable_target(b"12:Hello World!"));
+    }
+
+    #[test]
+    fn matches_bep44_mutable_vectors() {
+        let k = bytes(K);
+        assert_eq!(signable(b"", 1, b"12:Hello World!"), b"3:seqi1e1:v12:Hello World!".to_vec());
+        assert_eq!(signable(b"foobar", 1, b"12:Hello World!"), b"4:salt6:foobar3:seqi1e1:v12:Hello World!".to_vec());
+        assert_eq!(mutable_target(&k, b""), NodeId::from_hex("4a533d47ec9c7d95b1ad75f576cffc641853b750").unwrap());
+        assert_eq!(mutable_target(&k, b"foobar"),
+                   NodeId::from_hex("411eba73b6f087ca51a3795d9c8c938d365e32c1").unwrap());
+
+        let mut items = ItemStore::new();
+        let signed = Signature {k: k.clone(), seq: 1, sig: bytes(SIG)};
+        assert_eq!(items.put_mutable(hello(), signed.clone(), b"", None).unwrap(), mutable_target(&k, b""));
+        assert_eq!(items.get(&mutable_target(&k, b"")), Some((hello(), Some(signed))));
+        let salted = Signature {k: k.clone(), seq: 1, sig: bytes(SALTED_SIG)};
+        assert_eq!(items.put_mutable(hello(), salted, b"foobar", None).unwrap(), mutable_target(&k, b"foobar"));
+
+        // each signature covers its own salt, and only that
+        match items.put_mutable(hello(), Signature {k: k.clone(), seq: 1, sig: bytes(SIG)}, b"foobar", None) {
+            Err(PutError::BadSignature) => (),
+            other => panic!("expected BadSignature, got {:?}", other),
+        }
+        let mut tampered = bytes(SIG);
+        tampered[0] ^= 1;
+        match items.put_mutable(hello(), Signature {k: k, seq: 1, sig: tampered}, b"", None) {
+            Err(PutError::BadSignature) => (),
+            other => panic!("expected BadSignature, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stores_immutable_items_up_to_the_limit() {
+        let mut items = ItemStore::new();
+        // "995:" and 995 bytes: exactly MAX_VALUE_LEN encoded
+        let v = ByteString(vec![b'x'; MAX_VALUE_LEN - 5]);
+        let encoded = v.to_bytes().unwrap();
+        assert_eq!(encoded.len(), MAX_VALUE_LEN);
+
+        let target = items.put_immutable(v.clone()).unwrap();
+        assert_eq!(target, immutable_target(&encoded));
+        assert_eq!(items.get(&target), Some((v, None)));
+
+        match items.put_immutable(ByteString(vec![b'x'; MAX_VALUE_LEN - 4])) {
+            Err(PutError::TooBig) => (),
+            other => panic!("expected TooBig, got {:?}", other),
+        }
+        assert_eq!(items.get(&immutable_target(b"3:abc")), None);
+    }
+}
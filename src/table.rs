@@ -1,9 +1,12 @@
 /// Subdividable data structure that holds known nodes.
 
+use std::cmp;
 use std::fmt::{self, Debug, Formatter};
 use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use messages::{NODE_ID_LEN, NodeId};
+use messages::{NODE_ID_LEN, Node4Info, NodeId, Peer4Info};
 
 /// XOR-based distance metric for `NodeId`s.
 #[derive(Copy, Clone, Eq, Ord, PartialEq, PartialOrd)]
@@ -25,21 +28,28 @@ impl Distance {
                 continue
             }
             // zeros == i*8 + number of zero bits in `bits`
-            let mut mask = 0xf0;
+            let mut mask = 0x80;
             for n_extra_zeros in 0..8 {
                 if (mask & bits) != 0 {
                     return i * 8 + n_extra_zeros;
                 }
                 mask >>= 1;
             }
-            debug_assert_eq!(mask, 1);
+            unreachable!();
         }
         debug_assert_eq!(self.0, [0u8; NODE_ID_LEN]);
         return NODE_ID_LEN * 8;
     }
 }
 
-const MAX_BUCKETS: usize = NODE_ID_LEN * 8 + 1;
+/// Deepest a table can get: one bucket per bit of shared prefix, plus one for our own ID.
+pub const MAX_BUCKETS: usize = NODE_ID_LEN * 8 + 1;
+
+/// A bucket untouched for this long should be refreshed with a lookup in its range.
+pub const REFRESH_AFTER_SECS: u64 = 15 * 60;
+
+/// A node still `Pinging` after this long never answered; its slot is free for a newcomer.
+pub const PINGING_EXPIRY_SECS: u64 = 60;
 
 /// Stores known nodes, bucketing them based on their "distance" from us.
 pub struct Table {
@@ -47,6 +57,75 @@ pub struct Table {
     buckets: Vec<Bucket>,
     /// Our ID; used for the distance metric.
     id: NodeId,
+    /// Called with the new bucket count whenever a spill deepens the table.
+    on_spill: Option<Box<FnMut(usize)>>,
+    /// Whether a full home bucket makes room for newcomers by evicting its worst node.
+    evict_in_home: bool,
+    /// Which node counts as worst.
+    eviction: Eviction,
+    /// Whether nodes whose IDs break BEP 42 are evicted before any that keep to it.
+    prefer_bep42: bool,
+    /// When the deepest bucket splits.
+    split: Split,
+    /// Buckets past which the deepest stops splitting; at most `MAX_BUCKETS`.
+    max_buckets: usize,
+    /// Unanswered queries in a row that make a node bad, and so evictable however recently
+    /// it last answered.
+    bad_after: u32,
+}
+
+/// Which node a full bucket gives up when it must make room.
+///
+/// Unverified nodes go first once their ping has had `PINGING_EXPIRY_SECS` to be answered;
+/// until then they keep their slots. Otherwise only nodes that have gone quiet for
+/// `REFRESH_AFTER_SECS` or gone bad (see `Table::set_bad_after`) are candidates, and this
+/// picks among them; ties go to the one silent longest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Eviction {
+    /// The one silent longest.
+    Oldest,
+    /// The one with the most unanswered queries since it last answered.
+    LeastReliable,
+    /// The one slowest to answer last time; never having answered counts as slowest.
+    SlowestRtt,
+}
+
+impl Eviction {
+    /// Parses the `--eviction` spelling.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "oldest" => Some(Eviction::Oldest),
+            "least-reliable" => Some(Eviction::LeastReliable),
+            "slowest-rtt" => Some(Eviction::SlowestRtt),
+            _ => None,
+        }
+    }
+}
+
+/// When the deepest bucket splits to make the table one bucket deeper.
+///
+/// Only the deepest bucket ever splits, since it's the only one covering our own ID; the
+/// choice is when. Either way a node goes in the bucket `bucket_index_for` names.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Split {
+    /// As soon as a node turns up that would go in the deeper bucket, full or not, so each
+    /// closer arrival deepens the table by one bucket, until `Table::set_max_buckets` stops
+    /// it. The table ends up about as deep as the closest nodes we've met.
+    Eager,
+    /// Only when the deepest bucket is full and a node for the deeper half turns up, as the
+    /// Kademlia paper has it. Shallower tables, with closer nodes sharing the deepest bucket.
+    WhenFull,
+}
+
+impl Split {
+    /// Parses the `--split` spelling.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "eager" => Some(Split::Eager),
+            "when-full" => Some(Split::WhenFull),
+            _ => None,
+        }
+    }
 }
 
 impl Debug for Table {
@@ -63,16 +142,31 @@ impl Debug for Table {
 }
 
 /// Number of slots per bucket.
-const K: usize = 8;
+pub const K: usize = 8;
+
+/// One bucket at a glance, from `Table::bucket_summaries`.
+#[derive(Clone, Copy, Debug)]
+pub struct BucketSummary {
+    /// Nodes that have answered us.
+    pub good: usize,
+    /// Nodes we're still pinging to verify.
+    pub questionable: usize,
+    /// Free slots; `K` less the other two.
+    pub empty: usize,
+    /// Time since a slot was handed out or the bucket was refreshed.
+    pub since_changed: Duration,
+}
 
 struct Bucket {
     /// Most recently "good" nodes first.
     slots: [Slot; K],
+    /// Last time a slot was handed out or the bucket was refreshed.
+    last_changed: Instant,
 }
 
 impl Bucket {
     fn new() -> Self {
-        Bucket {slots: [Slot::Empty; K]}
+        Bucket {slots: [Slot::Empty; K], last_changed: Instant::now()}
     }
 
     /// Finds the given ID, or assigns an empty slot.
@@ -80,7 +174,7 @@ impl Bucket {
         for (i, slot) in self.slots.iter().enumerate() {
             let found = match *slot {
                 Slot::Empty => true,
-                Slot::Node(ref slot_id, _) => id == slot_id,
+                Slot::Node(ref entry) => id == &entry.info.id,
             };
             if found {
                 return Some(i)
@@ -88,6 +182,94 @@ impl Bucket {
         }
         None
     }
+
+    /// Where the node with this ID sits, if it's here.
+    fn find(&self, id: &NodeId) -> Option<usize> {
+        self.slots.iter().position(|slot| match *slot {
+            Slot::Node(ref entry) => &entry.info.id == id,
+            Slot::Empty => false,
+        })
+    }
+
+    /// Checks that nodes fill the bucket from the front, which `locate` and `spill` rely on,
+    /// and that no ID appears twice.
+    ///
+    /// Reports what's wrong rather than panicking, so the caller picks how loudly to fail.
+    #[cfg(test)]
+    fn check_invariants(&self) -> Result<(), String> {
+        let mut seen_empty = false;
+        for (i, slot) in self.slots.iter().enumerate() {
+            match *slot {
+                Slot::Empty => seen_empty = true,
+                Slot::Node(_) if seen_empty => return Err(format!("node in slot {} after a gap", i)),
+                Slot::Node(ref entry) => {
+                    if self.find(&entry.info.id) != Some(i) {
+                        return Err(format!("{:?} in more than one slot", entry.info.id))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A node we've been pinging too long to still expect an answer, if any.
+    fn expired_pinging(&self) -> Option<usize> {
+        let expiry = Duration::from_secs(PINGING_EXPIRY_SECS);
+        self.slots.iter().position(|slot| match *slot {
+            Slot::Node(ref entry) => entry.state == NodeState::Pinging && entry.last_seen.elapsed() >= expiry,
+            Slot::Empty => false,
+        })
+    }
+
+    /// The node we'd miss least, if any is questionable enough: one whose ping has expired,
+    /// or else the good node `eviction` judges worst.
+    ///
+    /// A node still awaiting its ping's answer is never picked before `PINGING_EXPIRY_SECS`,
+    /// or each newcomer would evict the one before it. With `prefer_bep42`, a good node whose
+    /// ID breaks BEP 42 is always worse than one whose doesn't.
+    fn worst(&self, eviction: Eviction, prefer_bep42: bool, bad_after: u32) -> Option<usize> {
+        if let Some(i) = self.expired_pinging() {
+            return Some(i)
+        }
+        let questionable = Duration::from_secs(REFRESH_AFTER_SECS);
+        let mut worst: Option<(usize, &Entry)> = None;
+        for (i, slot) in self.slots.iter().enumerate() {
+            if let Slot::Node(ref entry) = *slot {
+                if entry.state == NodeState::Pinging {
+                    continue
+                }
+                if entry.last_seen.elapsed() < questionable && !entry.is_bad(bad_after) {
+                    continue
+                }
+                let worse = match worst {
+                    None => true,
+                    Some((_, other)) if prefer_bep42 && entry.is_bep42() != other.is_bep42() => {
+                        !entry.is_bep42()
+                    }
+                    Some((_, other)) => {
+                        let by_policy = match eviction {
+                            Eviction::Oldest => cmp::Ordering::Equal,
+                            Eviction::LeastReliable => entry.failures.cmp(&other.failures),
+                            Eviction::SlowestRtt => match (entry.rtt, other.rtt) {
+                                (Some(rtt), Some(other_rtt)) => rtt.cmp(&other_rtt),
+                                (None, Some(_)) => cmp::Ordering::Greater,
+                                (Some(_), None) => cmp::Ordering::Less,
+                                (None, None) => cmp::Ordering::Equal,
+                            },
+                        };
+                        match by_policy {
+                            cmp::Ordering::Equal => entry.last_seen < other.last_seen,
+                            ordering => ordering == cmp::Ordering::Greater,
+                        }
+                    }
+                };
+                if worse {
+                    worst = Some((i, entry));
+                }
+            }
+        }
+        worst.map(|(i, _)| i)
+    }
 }
 
 impl Debug for Bucket {
@@ -103,7 +285,7 @@ impl Debug for Bucket {
 #[derive(Clone, Copy, Debug)]
 pub enum Slot {
     Empty,
-    Node(NodeId, NodeState),
+    Node(Entry),
 }
 
 impl Slot {
@@ -115,52 +297,429 @@ impl Slot {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum NodeState {
     Pinging,
     Good,
 }
 
+/// What we know about one node in the table.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub info: Node4Info,
+    pub state: NodeState,
+    /// When we last heard from the node (or first learned of it).
+    pub last_seen: Instant,
+    /// Queries it has left unanswered since it last answered one.
+    pub failures: u32,
+    /// How long it took to answer our last query it answered.
+    pub rtt: Option<Duration>,
+}
+
+impl Entry {
+    pub fn new(info: Node4Info, state: NodeState) -> Self {
+        Entry {
+            info: info,
+            state: state,
+            last_seen: Instant::now(),
+            failures: 0,
+            rtt: None,
+        }
+    }
+
+    /// Whether it has left `bad_after` or more queries in a row unanswered.
+    pub fn is_bad(&self, bad_after: u32) -> bool {
+        self.failures >= bad_after
+    }
+
+    /// Whether the node's ID is one BEP 42 allows at its address.
+    fn is_bep42(&self) -> bool {
+        match self.info.peer.socket_addr() {
+            SocketAddr::V4(addr) => self.info.id.is_valid_for(addr.ip()),
+            SocketAddr::V6(_) => true,
+        }
+    }
+}
+
 impl Table {
     pub fn new(id: NodeId) -> Self {
         Table {
             buckets: vec![Bucket::new()],
             id: id,
+            on_spill: None,
+            evict_in_home: true,
+            eviction: Eviction::LeastReliable,
+            prefer_bep42: false,
+            split: Split::Eager,
+            max_buckets: MAX_BUCKETS,
+            bad_after: 1,
         }
     }
 
+    /// Sets whether a full home bucket (the one covering our own ID) evicts its worst node
+    /// for a newcomer, rather than turning it away like any other full bucket. On by default:
+    /// our closest neighbours are the contacts most worth keeping fresh.
+    pub fn set_home_eviction(&mut self, evict: bool) {
+        self.evict_in_home = evict;
+    }
+
+    /// Sets how a full home bucket picks the node to evict; `LeastReliable` by default.
+    pub fn set_eviction(&mut self, eviction: Eviction) {
+        self.eviction = eviction;
+    }
+
+    /// Has eviction pick nodes whose IDs BEP 42 wouldn't allow at their address before any
+    /// others, whatever `Eviction` says; such IDs are the ones an attacker could have chosen.
+    /// Off by default, as many clients still pick their IDs at random.
+    pub fn set_prefer_bep42(&mut self, prefer: bool) {
+        self.prefer_bep42 = prefer;
+    }
+
+    /// Sets when the deepest bucket splits; `Eager` by default.
+    pub fn set_split(&mut self, split: Split) {
+        self.split = split;
+    }
+
+    /// Caps how deep the table grows, clamped to between 1 and `MAX_BUCKETS`; the default is
+    /// `MAX_BUCKETS`. Once the table is this deep, nodes closer than that share the deepest
+    /// bucket, saving memory at some cost to how well we know our neighbourhood.
+    ///
+    /// Takes effect as the table grows; buckets already split stay.
+    pub fn set_max_buckets(&mut self, max_buckets: usize) {
+        self.max_buckets = cmp::max(1, cmp::min(max_buckets, MAX_BUCKETS));
+    }
+
+    /// Sets how many queries in a row a node must leave unanswered to go bad, making it
+    /// evictable even if it answered recently; 1 by default, and never less. An answer to
+    /// any query resets the count.
+    pub fn set_bad_after(&mut self, failures: u32) {
+        self.bad_after = cmp::max(1, failures);
+    }
+
+    /// Registers `hook` to hear about each new bucket; more buckets means finer keyspace coverage.
+    pub fn set_spill_hook(&mut self, hook: Box<FnMut(usize)>) {
+        self.on_spill = Some(hook);
+    }
+
     pub fn our_id(&self) -> &NodeId {
         &self.id
     }
 
+    pub fn bucket_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// How many of our nodes are `Good`.
+    pub fn good_count(&self) -> usize {
+        self.buckets.iter()
+            .flat_map(|bucket| bucket.slots.iter())
+            .filter(|slot| match **slot {
+                Slot::Node(ref entry) => entry.state == NodeState::Good,
+                Slot::Empty => false,
+            })
+            .count()
+    }
+
+    /// Our good nodes' IDs and addresses, closest buckets last, for another node to start
+    /// from; see `--save-contacts`.
+    pub fn export_contacts(&self) -> Vec<(NodeId, SocketAddr)> {
+        self.buckets.iter()
+            .flat_map(|bucket| bucket.slots.iter())
+            .filter_map(|slot| match *slot {
+                Slot::Node(ref entry) if entry.state == NodeState::Good => {
+                    Some((entry.info.id, entry.info.peer.socket_addr()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// How many nodes we hold, good or still being verified.
+    pub fn node_count(&self) -> usize {
+        self.buckets.iter()
+            .flat_map(|bucket| bucket.slots.iter())
+            .filter(|slot| !slot.is_empty())
+            .count()
+    }
+
+    /// How many buckets hold at least one node.
+    pub fn occupied_buckets(&self) -> usize {
+        self.buckets.iter().filter(|bucket| !bucket.slots[0].is_empty()).count()
+    }
+
+    /// How many nodes the home bucket, the deepest one covering our own ID, holds.
+    pub fn home_bucket_len(&self) -> usize {
+        self.buckets.last().map_or(0, |bucket| bucket.slots.iter().filter(|slot| !slot.is_empty()).count())
+    }
+
+    /// Every bucket's occupancy and age, farthest from our ID first; the same order as the
+    /// `Debug` output, but for a program to read.
+    pub fn bucket_summaries(&self) -> Vec<BucketSummary> {
+        self.buckets.iter().map(|bucket| {
+            let mut summary = BucketSummary {
+                good: 0,
+                questionable: 0,
+                empty: 0,
+                since_changed: bucket.last_changed.elapsed(),
+            };
+            for slot in &bucket.slots {
+                match *slot {
+                    Slot::Node(ref entry) if entry.state == NodeState::Good => summary.good += 1,
+                    Slot::Node(_) => summary.questionable += 1,
+                    Slot::Empty => summary.empty += 1,
+                }
+            }
+            summary
+        }).collect()
+    }
+
+    /// The bucket that has gone longest without changing, if any is at least `max_age` old.
+    pub fn stalest_bucket(&self, max_age: Duration) -> Option<usize> {
+        let mut stalest: Option<(usize, Instant)> = None;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            if bucket.last_changed.elapsed() < max_age {
+                continue
+            }
+            match stalest {
+                Some((_, changed)) if changed <= bucket.last_changed => (),
+                _ => stalest = Some((i, bucket.last_changed)),
+            }
+        }
+        stalest.map(|(i, _)| i)
+    }
+
+    /// Marks bucket `index` fresh, e.g. once a refresh lookup has been launched for it.
+    pub fn touch_bucket(&mut self, index: usize) {
+        self.buckets[index].last_changed = Instant::now();
+    }
+
+    /// A random ID belonging in bucket `index`, i.e. sharing exactly `index` leading bits with us.
+    pub fn random_id_in_bucket(&self, index: usize) -> NodeId {
+        debug_assert!(index < self.buckets.len());
+        if index >= NODE_ID_LEN * 8 {
+            return self.id
+        }
+        let mut id = NodeId::random();
+        let shared_bytes = index / 8;
+        id.0[..shared_bytes].copy_from_slice(&self.id.0[..shared_bytes]);
+
+        let extra_bits = index % 8;
+        let keep: u8 = if extra_bits == 0 { 0 } else { 0xff << (8 - extra_bits) };
+        let flip: u8 = 0x80 >> extra_bits;
+        let ours = self.id.0[shared_bytes];
+        let random = id.0[shared_bytes];
+        id.0[shared_bytes] = (ours & keep) | (!ours & flip) | (random & !keep & !flip);
+        id
+    }
+
+    /// Up to `count` of our `Good` nodes, closest to `target` first.
+    ///
+    /// Empty until some node has answered us, as before bootstrap completes.
+    pub fn find_closest(&self, target: &NodeId, count: usize) -> Vec<Node4Info> {
+        let mut nodes = vec![];
+        for bucket in &self.buckets {
+            for slot in &bucket.slots {
+                if let Slot::Node(entry) = *slot {
+                    if entry.state == NodeState::Good {
+                        nodes.push((Distance::between(target, &entry.info.id), entry.info));
+                    }
+                }
+            }
+        }
+        // XOR distances only tie for the same ID, e.g. a node that changed address
+        nodes.sort_by(|a, b| (a.0, a.1.peer).cmp(&(b.0, b.1.peer)));
+        nodes.into_iter().take(count).map(|(_, node)| node).collect()
+    }
+
+    /// Up to `count` of our `Good` nodes closest to our own ID: our neighbours in the keyspace.
+    pub fn neighborhood(&self, count: usize) -> Vec<Node4Info> {
+        self.find_closest(&self.id, count)
+    }
+
+    /// Which bucket `node_id` belongs in as the table stands now.
+    ///
+    /// That's the number of leading bits it shares with our ID, except that the deepest
+    /// bucket also holds everything closer than its depth until it spills.
+    pub fn bucket_index_for(&self, node_id: &NodeId) -> usize {
+        let common_bits = Distance::between(&self.id, node_id).count_zeros();
+        cmp::min(common_bits, self.buckets.len() - 1)
+    }
+
+    /// Whether `node_id`'s bucket has a free slot right now, without spilling.
+    pub fn has_room_for(&self, node_id: &NodeId) -> bool {
+        self.buckets[self.bucket_index_for(node_id)].slots.iter().any(Slot::is_empty)
+    }
+
+    /// Looks up a known node without modifying anything, unlike `allocate`.
+    ///
+    /// Returns its address, its state, and how long ago we last heard from it.
+    pub fn get(&self, node_id: &NodeId) -> Option<(Peer4Info, NodeState, Duration)> {
+        let bucket = &self.buckets[self.bucket_index_for(node_id)];
+        for slot in &bucket.slots {
+            if let Slot::Node(ref entry) = *slot {
+                if &entry.info.id == node_id {
+                    return Some((entry.info.peer, entry.state, entry.last_seen.elapsed()))
+                }
+            }
+        }
+        None
+    }
+
+    /// Checks each bucket's invariants, and that every node is in the bucket its distance
+    /// from us calls for. Tests run this after changing the table.
+    #[cfg(test)]
+    pub fn check_invariants(&self) -> Result<(), String> {
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            bucket.check_invariants().map_err(|e| format!("bucket {}: {}", i, e))?;
+            for slot in &bucket.slots {
+                if let Slot::Node(ref entry) = *slot {
+                    if self.bucket_index_for(&entry.info.id) != i {
+                        return Err(format!("bucket {}: {:?} belongs in bucket {}", i, entry.info.id,
+                                           self.bucket_index_for(&entry.info.id)))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The entry for a known node, to update in place; unlike `allocate`, never makes room.
+    pub fn entry_mut(&mut self, node_id: &NodeId) -> Option<&mut Entry> {
+        let index = self.bucket_index_for(node_id);
+        let bucket = &mut self.buckets[index];
+        match bucket.find(node_id) {
+            Some(i) => match bucket.slots[i] {
+                Slot::Node(ref mut entry) => Some(entry),
+                Slot::Empty => None,
+            },
+            None => None,
+        }
+    }
+
     /// Finds and returns an appropriate `Slot` for `node_id`.
     ///
     /// If it already existed, returns the existing entry.
-    /// May spill a new bucket as needed.
+    /// May spill a new bucket as needed, or evict a node from a full home bucket. Any full
+    /// bucket gives up a node that has sat unverified past `PINGING_EXPIRY_SECS`, so one
+    /// filled with dead candidates doesn't turn everyone away for good.
     pub fn allocate<'a>(&'a mut self, node_id: &NodeId) -> Option<&'a mut Slot> {
         let distance = Distance::between(&self.id, &node_id);
         let common_bits = distance.count_zeros() as usize;
         let n = self.buckets.len();
 
         if common_bits < n {
-            if let Some(i) = self.buckets[common_bits].locate(node_id) {
+            let mut found = self.buckets[common_bits].locate(node_id);
+            if found.is_none() {
+                found = self.evict(common_bits);
+            }
+            if let Some(i) = found {
+                self.buckets[common_bits].last_changed = Instant::now();
                 return Some(&mut self.buckets[common_bits].slots[i])
             }
         }
 
-        if common_bits >= n && n < MAX_BUCKETS {
+        // a node closer than the table is deep may already be filed in the deepest bucket;
+        // spilling past it would file it a second time
+        if common_bits >= n {
+            let mut found = match self.split {
+                Split::Eager if n < self.max_buckets => self.buckets[n - 1].find(node_id),
+                _ => self.buckets[n - 1].locate(node_id),
+            };
+            // a table as deep as it may grow can't spill, so its deepest bucket makes room
+            // like the home bucket it is
+            if found.is_none() && n == self.max_buckets {
+                found = self.evict(n - 1);
+            }
+            if let Some(i) = found {
+                self.buckets[n - 1].last_changed = Instant::now();
+                return Some(&mut self.buckets[n - 1].slots[i])
+            }
+        }
+
+        if common_bits >= n && n < self.max_buckets {
             self.spill()
         } else {
             None
         }
     }
 
+    /// Empties a slot in full bucket `index` if any node there can go: the worst, as
+    /// `Bucket::worst` judges it, in the home bucket with home eviction on, or else one whose
+    /// ping has expired.
+    fn evict(&mut self, index: usize) -> Option<usize> {
+        let home = index == self.buckets.len() - 1 && self.evict_in_home;
+        let bucket = &mut self.buckets[index];
+        let found = if home {
+            bucket.worst(self.eviction, self.prefer_bep42, self.bad_after)
+        } else {
+            bucket.expired_pinging()
+        };
+        if let Some(i) = found {
+            bucket.slots[i] = Slot::Empty;
+        }
+        found
+    }
+
+    /// Forgets `node_id`, returning what we knew of it.
+    ///
+    /// Later slots move up to close the gap, and emptied buckets at the deep end go too.
+    pub fn remove(&mut self, node_id: &NodeId) -> Option<Entry> {
+        let index = self.bucket_index_for(node_id);
+        let removed = {
+            let bucket = &mut self.buckets[index];
+            let i = match bucket.find(node_id) {
+                Some(i) => i,
+                None => return None,
+            };
+            let removed = match bucket.slots[i] {
+                Slot::Node(entry) => entry,
+                Slot::Empty => unreachable!(),
+            };
+            for j in i..K - 1 {
+                bucket.slots[j] = bucket.slots[j + 1];
+            }
+            bucket.slots[K - 1] = Slot::Empty;
+            removed
+        };
+        self.compact_buckets();
+        Some(removed)
+    }
+
+    /// Drops empty buckets from the deep end, keeping at least one, so the bucket count
+    /// reflects how deep the table really is.
+    ///
+    /// Safe for `allocate`: once the deepest bucket is empty, nothing is closer to us than
+    /// the one before it, which can take over as the deepest without moving anything.
+    fn compact_buckets(&mut self) {
+        while self.buckets.len() > 1 && self.buckets.last().map_or(false, |b| b.slots[0].is_empty()) {
+            self.buckets.pop();
+        }
+    }
+
+    /// Files every node in `nodes` that fits, marking newcomers `Pinging`.
+    ///
+    /// Returns each node that found a slot, paired with whether it's new and needs a ping.
+    /// Nodes we have no room for are left out.
+    pub fn allocate_many(&mut self, nodes: &[Node4Info]) -> Vec<(Node4Info, bool)> {
+        let mut filed = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            if let Some(slot) = self.allocate(&node.id) {
+                let is_new = slot.is_empty();
+                if is_new {
+                    *slot = Slot::Node(Entry::new(*node, NodeState::Pinging));
+                }
+                filed.push((*node, is_new));
+            }
+        }
+        filed
+    }
+
     /// Push a new bucket, and spill entries from the previous bucket into it as appropriate.
     ///
     /// Returns the next open slot in the new bucket.
     fn spill(&mut self) -> Option<&mut Slot> {
         assert!(!self.buckets.is_empty());
-        assert!(self.buckets.len() < MAX_BUCKETS);
+        assert!(self.buckets.len() < self.max_buckets);
 
         let bit_index = self.buckets.len() - 1;
         let our_bit = self.id.bit(bit_index);
@@ -184,8 +743,8 @@ impl Table {
                             gap = Some(src)
                         }
                     }
-                    // unnecessary copy of `id` here?
-                    Slot::Node(id, _) if our_bit == id.bit(bit_index) => {
+                    // unnecessary copy of `entry` here?
+                    Slot::Node(entry) if our_bit == entry.info.id.bit(bit_index) => {
                         // spill it!
                         dest_bucket.slots[dest_slot] = *src_slot;
                         dest_slot += 1;
@@ -195,7 +754,7 @@ impl Table {
                             gap = Some(src)
                         }
                     }
-                    Slot::Node(_, _) => {
+                    Slot::Node(_) => {
                         // this slot will stay behind in the old bucket
                         if let Some(g) = gap {
                             // move this node up to fill the gap
@@ -217,6 +776,9 @@ impl Table {
         // now that we've spilled into our new bucket, push it
         let bucket_index = self.buckets.len();
         self.buckets.push(dest_bucket);
+        if let Some(ref mut hook) = self.on_spill {
+            hook(bucket_index + 1);
+        }
         if dest_slot < K {
             Some(&mut self.buckets.get_mut(bucket_index).unwrap().slots[dest_slot])
         } else {
@@ -224,3 +786,551 @@ impl Table {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use messages::{Node4Info, NodeId};
+    use super::{Distance, Entry, Eviction, K, NodeState, PINGING_EXPIRY_SECS, REFRESH_AFTER_SECS, Slot, Table};
+
+    /// Our ID in these tests.
+    const US: NodeId = NodeId([0; 20]);
+
+    /// The `n`th node sharing exactly `depth` leading bits with `US`.
+    fn node(depth: usize, n: u8) -> Node4Info {
+        let mut id = [0u8; 20];
+        id[depth / 8] |= 0x80 >> (depth % 8);
+        id[19] |= n;
+        let addr: SocketAddr = format!("203.0.113.{}:{}", n, 6881 + depth).parse().unwrap();
+        Node4Info::from_addr(&NodeId(id), &addr).unwrap()
+    }
+
+    fn nodes(depth: usize, count: u8) -> Vec<Node4Info> {
+        (1..count + 1).map(|n| node(depth, n)).collect()
+    }
+
+    /// `secs` before now, unless the monotonic clock hasn't run that long, as it may not have
+    /// just after boot.
+    fn ago(secs: u64) -> Option<Instant> {
+        Instant::now().checked_sub(Duration::from_secs(secs))
+    }
+
+    fn mark_good(table: &mut Table, nodes: &[Node4Info]) {
+        for node in nodes {
+            table.entry_mut(&node.id).unwrap().state = NodeState::Good;
+        }
+    }
+
+    #[test]
+    fn node_helper_gives_the_depth_asked_for() {
+        for depth in 0..150 {
+            assert_eq!(Distance::between(&US, &node(depth, 1).id).count_zeros(), depth);
+        }
+        assert_eq!(Distance::between(&US, &US).count_zeros(), 160);
+    }
+
+    #[test]
+    fn far_nodes_fill_the_first_bucket() {
+        let mut table = Table::new(US);
+        let far = nodes(0, K as u8);
+        let filed = table.allocate_many(&far);
+        assert_eq!(filed.len(), K);
+        assert!(filed.iter().all(|&(_, is_new)| is_new));
+        assert_eq!(table.bucket_count(), 1);
+        assert_eq!(table.node_count(), K);
+        assert_eq!(table.check_invariants(), Ok(()));
+
+        // filing them again finds the same slots
+        assert!(table.allocate_many(&far).iter().all(|&(_, is_new)| !is_new));
+        assert_eq!(table.node_count(), K);
+    }
+
+    #[test]
+    fn full_home_bucket_keeps_nodes_still_being_pinged() {
+        let mut table = Table::new(US);
+        table.set_max_buckets(1);
+        let far = nodes(0, K as u8);
+        table.allocate_many(&far);
+        // each newcomer would otherwise evict the one filed just before it
+        assert!(table.allocate_many(&[node(0, 100)]).is_empty());
+        assert!(table.allocate_many(&[node(0, 101)]).is_empty());
+        for node in &far {
+            assert!(table.get(&node.id).is_some());
+        }
+    }
+
+    #[test]
+    fn full_home_bucket_evicts_a_node_whose_ping_expired() {
+        let mut table = Table::new(US);
+        table.set_max_buckets(1);
+        let far = nodes(0, K as u8);
+        table.allocate_many(&far);
+        let expired = match ago(PINGING_EXPIRY_SECS) {
+            Some(expired) => expired,
+            // too soon after boot to backdate anything
+            None => return,
+        };
+        table.entry_mut(&far[3].id).unwrap().last_seen = expired;
+        let newcomer = node(0, 100);
+        assert_eq!(table.allocate_many(&[newcomer]), vec![(newcomer, true)]);
+        assert_eq!(table.node_count(), K);
+        assert!(table.get(&far[3].id).is_none());
+        assert_eq!(table.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn full_home_bucket_evicts_a_bad_node() {
+        let mut table = Table::new(US);
+        table.set_max_buckets(1);
+        table.set_bad_after(3);
+        let far = nodes(0, K as u8);
+        table.allocate_many(&far);
+        mark_good(&mut table, &far);
+        table.entry_mut(&far[5].id).unwrap().failures = 2;
+        assert!(table.allocate_many(&[node(0, 100)]).is_empty());
+
+        table.entry_mut(&far[5].id).unwrap().failures = 3;
+        let newcomer = node(0, 100);
+        assert_eq!(table.allocate_many(&[newcomer]), vec![(newcomer, true)]);
+        assert!(table.get(&far[5].id).is_none());
+        assert_eq!(table.good_count(), K - 1);
+    }
+
+    #[test]
+    fn full_home_bucket_of_fresh_good_nodes_turns_newcomers_away() {
+        let mut table = Table::new(US);
+        table.set_max_buckets(1);
+        let far = nodes(0, K as u8);
+        table.allocate_many(&far);
+        mark_good(&mut table, &far);
+        assert!(table.allocate_many(&[node(0, 100)]).is_empty());
+        assert_eq!(table.good_count(), K);
+    }
+
+    /// A full home bucket of good nodes, three of them gone quiet: the longest silent, the
+    /// one with the most failures, and the slowest, in that order. Everyone else is fresh.
+    fn bucket_with_three_candidates(eviction: Eviction) -> Option<(Table, [Node4Info; 3])> {
+        let mut table = Table::new(US);
+        table.set_max_buckets(1);
+        table.set_eviction(eviction);
+        table.set_bad_after(10);
+        let far = nodes(0, K as u8);
+        table.allocate_many(&far);
+        mark_good(&mut table, &far);
+        for node in &far {
+            table.entry_mut(&node.id).unwrap().rtt = Some(Duration::from_millis(50));
+        }
+        let candidates = [far[1], far[3], far[5]];
+        let crafted = [(300, 0, 10), (200, 4, 20), (100, 1, 500)];
+        for (node, &(secs, failures, rtt)) in candidates.iter().zip(&crafted) {
+            let last_seen = match ago(REFRESH_AFTER_SECS + secs) {
+                Some(last_seen) => last_seen,
+                // too soon after boot to backdate anything
+                None => return None,
+            };
+            let entry = table.entry_mut(&node.id).unwrap();
+            entry.last_seen = last_seen;
+            entry.failures = failures;
+            entry.rtt = Some(Duration::from_millis(rtt));
+        }
+        Some((table, candidates))
+    }
+
+    /// Files a newcomer, returning which of `candidates` made way for it.
+    fn evicted(table: &mut Table, candidates: &[Node4Info]) -> Vec<usize> {
+        let newcomer = node(0, 100);
+        assert_eq!(table.allocate_many(&[newcomer]), vec![(newcomer, true)]);
+        assert_eq!(table.node_count(), K);
+        (0..candidates.len()).filter(|&i| table.get(&candidates[i].id).is_none()).collect()
+    }
+
+    #[test]
+    fn oldest_eviction_drops_the_node_silent_longest() {
+        if let Some((mut table, candidates)) = bucket_with_three_candidates(Eviction::Oldest) {
+            assert_eq!(evicted(&mut table, &candidates), vec![0]);
+        }
+    }
+
+    #[test]
+    fn least_reliable_eviction_drops_the_node_with_most_failures() {
+        if let Some((mut table, candidates)) = bucket_with_three_candidates(Eviction::LeastReliable) {
+            assert_eq!(evicted(&mut table, &candidates), vec![1]);
+        }
+    }
+
+    #[test]
+    fn slowest_rtt_eviction_drops_the_slowest_node() {
+        if let Some((mut table, candidates)) = bucket_with_three_candidates(Eviction::SlowestRtt) {
+            assert_eq!(evicted(&mut table, &candidates), vec![2]);
+        }
+    }
+
+    #[test]
+    fn prefer_bep42_evicts_a_non_compliant_node_first() {
+        // BEP 42's own example: rand 1 at 124.31.75.21
+        let ip = Ipv4Addr::new(124, 31, 75, 21);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, 6881));
+        let compliant = NodeId::from_hex("5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401").unwrap();
+        let spoofed = NodeId::from_hex("80bfbff10c5d6a4ec8a88e4c6ab4c28b95eee401").unwrap();
+        assert!(compliant.is_valid_for(&ip));
+        assert!(!spoofed.is_valid_for(&ip));
+        let compliant = Node4Info::from_addr(&compliant, &addr).unwrap();
+        let spoofed = Node4Info::from_addr(&spoofed, &addr).unwrap();
+
+        let mut table = Table::new(US);
+        table.set_max_buckets(1);
+        table.set_eviction(Eviction::Oldest);
+        table.set_prefer_bep42(true);
+        let mut full = nodes(0, K as u8 - 2);
+        full.push(compliant);
+        full.push(spoofed);
+        table.allocate_many(&full);
+        mark_good(&mut table, &full);
+        // both quiet, the compliant one longest, so only the preference puts the other first
+        match (ago(REFRESH_AFTER_SECS + 200), ago(REFRESH_AFTER_SECS + 100)) {
+            (Some(older), Some(newer)) => {
+                table.entry_mut(&compliant.id).unwrap().last_seen = older;
+                table.entry_mut(&spoofed.id).unwrap().last_seen = newer;
+            }
+            // too soon after boot to backdate anything
+            _ => return,
+        }
+
+        let newcomer = node(0, 100);
+        assert_eq!(table.allocate_many(&[newcomer]), vec![(newcomer, true)]);
+        assert!(table.get(&spoofed.id).is_none());
+        assert!(table.get(&compliant.id).is_some());
+
+        let newcomer = node(0, 101);
+        assert_eq!(table.allocate_many(&[newcomer]), vec![(newcomer, true)]);
+        assert!(table.get(&compliant.id).is_none());
+        assert_eq!(table.node_count(), K);
+    }
+
+    #[test]
+    fn without_home_eviction_a_full_bucket_turns_newcomers_away() {
+        let mut table = Table::new(US);
+        table.set_max_buckets(1);
+        table.set_home_eviction(false);
+        table.allocate_many(&nodes(0, K as u8));
+        assert!(table.allocate_many(&[node(0, 100)]).is_empty());
+    }
+
+    #[test]
+    fn full_far_bucket_of_stale_pings_takes_a_newcomer() {
+        let mut table = Table::new(US);
+        let far = nodes(0, K as u8);
+        table.allocate_many(&far);
+        table.allocate_many(&[node(3, 1)]);
+        assert_eq!(table.bucket_count(), 2);
+        // no longer the home bucket, and its pings are still fresh
+        assert!(table.allocate_many(&[node(0, 100)]).is_empty());
+
+        let expired = match ago(PINGING_EXPIRY_SECS) {
+            Some(expired) => expired,
+            // too soon after boot to backdate anything
+            None => return,
+        };
+        for node in &far {
+            table.entry_mut(&node.id).unwrap().last_seen = expired;
+        }
+        let newcomer = node(0, 100);
+        assert_eq!(table.allocate_many(&[newcomer]), vec![(newcomer, true)]);
+        assert_eq!(table.bucket_index_for(&newcomer.id), 0);
+        assert_eq!(table.node_count(), K + 1);
+        assert_eq!(table.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn allocate_many_files_nodes_as_allocating_each_would() {
+        // enough at each depth to fill and spill buckets, with some nodes repeated
+        let mut all = Vec::new();
+        for depth in &[0, 4, 1, 9, 0, 2, 30, 4] {
+            all.extend(nodes(*depth, 6));
+        }
+        all.extend(nodes(2, 3));
+
+        let mut batched = Table::new(US);
+        let filed = batched.allocate_many(&all);
+
+        let mut one_by_one = Table::new(US);
+        let mut expected = Vec::new();
+        for node in &all {
+            if let Some(slot) = one_by_one.allocate(&node.id) {
+                let is_new = slot.is_empty();
+                if is_new {
+                    *slot = Slot::Node(Entry::new(*node, NodeState::Pinging));
+                }
+                expected.push((*node, is_new));
+            }
+        }
+
+        assert_eq!(filed, expected);
+        assert_eq!(batched.bucket_count(), one_by_one.bucket_count());
+        assert_eq!(batched.node_count(), one_by_one.node_count());
+        for node in &all {
+            assert_eq!(batched.bucket_index_for(&node.id), one_by_one.bucket_index_for(&node.id));
+            assert_eq!(batched.get(&node.id).is_some(), one_by_one.get(&node.id).is_some());
+        }
+        assert_eq!(batched.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn closer_node_spills_a_new_bucket() {
+        let spills = Rc::new(Cell::new(0));
+        let mut table = Table::new(US);
+        {
+            let spills = spills.clone();
+            table.set_spill_hook(Box::new(move |buckets: usize| spills.set(buckets)));
+        }
+        table.allocate_many(&nodes(0, 4));
+        table.allocate_many(&[node(3, 1)]);
+        assert_eq!(table.bucket_count(), 2);
+        assert_eq!(spills.get(), 2);
+        assert_eq!(table.bucket_index_for(&node(3, 1).id), 1);
+        assert_eq!(table.check_invariants(), Ok(()));
+
+        // the next closer node spills again, taking the depth 3 node along
+        table.allocate_many(&[node(5, 1)]);
+        assert_eq!(table.bucket_count(), 3);
+        assert_eq!(table.bucket_index_for(&node(3, 1).id), 2);
+        assert_eq!(table.node_count(), 6);
+        assert_eq!(table.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn refiling_a_node_in_the_deepest_bucket_doesnt_spill() {
+        let mut table = Table::new(US);
+        table.allocate_many(&[node(0, 1), node(9, 1)]);
+        let buckets = table.bucket_count();
+        assert_eq!(table.allocate_many(&[node(9, 1)]), vec![(node(9, 1), false)]);
+        assert_eq!(table.bucket_count(), buckets);
+        assert_eq!(table.node_count(), 2);
+        assert_eq!(table.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn spill_keeps_both_buckets_packed() {
+        let mut table = Table::new(US);
+        // interleave far and close nodes so the spill leaves gaps to close up
+        let mixed: Vec<Node4Info> = (1..5).flat_map(|n| vec![node(0, n), node(4, n)]).collect();
+        table.set_split(super::Split::WhenFull);
+        table.allocate_many(&mixed);
+        assert_eq!(table.bucket_count(), 1);
+        table.allocate_many(&[node(6, 1)]);
+        assert_eq!(table.bucket_count(), 2);
+        assert_eq!(table.node_count(), 9);
+        assert_eq!(table.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn remove_closes_the_gap() {
+        let mut table = Table::new(US);
+        let far = nodes(0, 3);
+        table.allocate_many(&far);
+        assert_eq!(table.remove(&far[1].id).map(|entry| entry.info), Some(far[1]));
+        assert!(table.get(&far[1].id).is_none());
+        assert!(table.get(&far[2].id).is_some());
+        assert_eq!(table.node_count(), 2);
+        assert_eq!(table.check_invariants(), Ok(()));
+        assert!(table.remove(&far[1].id).is_none());
+    }
+
+    #[test]
+    fn removing_the_closest_nodes_compacts_the_table() {
+        let mut table = Table::new(US);
+        table.allocate_many(&[node(0, 1), node(3, 1), node(5, 1)]);
+        assert_eq!(table.bucket_count(), 3);
+        table.remove(&node(3, 1).id);
+        assert_eq!(table.bucket_count(), 3);
+        table.remove(&node(5, 1).id);
+        // the emptied bucket before it goes too, but never the first
+        assert_eq!(table.bucket_count(), 1);
+        assert_eq!(table.check_invariants(), Ok(()));
+        table.remove(&node(0, 1).id);
+        assert_eq!(table.bucket_count(), 1);
+        assert_eq!(table.node_count(), 0);
+    }
+
+    #[test]
+    fn allocate_and_spill_work_after_compaction() {
+        let mut table = Table::new(US);
+        table.allocate_many(&[node(0, 1), node(3, 1), node(5, 1)]);
+        table.remove(&node(3, 1).id);
+        table.remove(&node(5, 1).id);
+
+        assert_eq!(table.allocate_many(&[node(4, 1)]), vec![(node(4, 1), true)]);
+        assert_eq!(table.bucket_count(), 2);
+        assert_eq!(table.allocate_many(&[node(0, 2), node(6, 1)]).len(), 2);
+        assert_eq!(table.bucket_count(), 3);
+        assert_eq!(table.node_count(), 4);
+        assert_eq!(table.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn table_stops_splitting_at_max_buckets() {
+        let mut table = Table::new(US);
+        table.set_max_buckets(2);
+        table.allocate_many(&[node(0, 1)]);
+        // closer nodes than the second bucket share it rather than splitting further
+        let close: Vec<Node4Info> = (1..K as u8 + 1).map(|n| node(n as usize, n)).collect();
+        assert_eq!(table.allocate_many(&close).len(), K);
+        assert_eq!(table.bucket_count(), 2);
+        assert_eq!(table.check_invariants(), Ok(()));
+        mark_good(&mut table, &close);
+
+        // and once it's full of good nodes, newcomers are turned away
+        assert!(table.allocate_many(&[node(20, 1)]).is_empty());
+        assert_eq!(table.bucket_count(), 2);
+        assert_eq!(table.node_count(), K + 1);
+    }
+
+    #[test]
+    fn capped_deepest_bucket_makes_room_once_pings_expire() {
+        let mut table = Table::new(US);
+        table.set_max_buckets(2);
+        table.allocate_many(&[node(0, 1)]);
+        let close: Vec<Node4Info> = (1..K as u8 + 1).map(|n| node(n as usize, n)).collect();
+        table.allocate_many(&close);
+        assert_eq!(table.bucket_count(), 2);
+        // still being pinged, so nobody goes yet
+        assert!(table.allocate_many(&[node(20, 1)]).is_empty());
+
+        let expired = match ago(PINGING_EXPIRY_SECS) {
+            Some(expired) => expired,
+            // too soon after boot to backdate anything
+            None => return,
+        };
+        for node in &close {
+            table.entry_mut(&node.id).unwrap().last_seen = expired;
+        }
+        let newcomer = node(20, 1);
+        assert_eq!(table.allocate_many(&[newcomer]), vec![(newcomer, true)]);
+        assert_eq!(table.bucket_count(), 2);
+        assert_eq!(table.node_count(), K + 1);
+        assert_eq!(table.check_invariants(), Ok(()));
+
+        // the same goes without home eviction
+        table.set_home_eviction(false);
+        let newcomer = node(21, 1);
+        assert_eq!(table.allocate_many(&[newcomer]), vec![(newcomer, true)]);
+        assert_eq!(table.node_count(), K + 1);
+    }
+
+    #[test]
+    fn max_buckets_is_clamped() {
+        let mut table = Table::new(US);
+        table.set_max_buckets(0);
+        table.allocate_many(&[node(0, 1), node(5, 1)]);
+        assert_eq!(table.bucket_count(), 1);
+        assert_eq!(table.node_count(), 2);
+        assert_eq!(table.check_invariants(), Ok(()));
+
+        let mut table = Table::new(US);
+        table.set_max_buckets(1000);
+        let deep: Vec<Node4Info> = (0..150).map(|depth| node(depth, 1)).collect();
+        table.allocate_many(&deep);
+        assert_eq!(table.bucket_count(), 150);
+        assert_eq!(table.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn limited_table_keeps_splitting_when_full_up_to_the_limit() {
+        let mut table = Table::new(US);
+        table.set_split(super::Split::WhenFull);
+        table.set_max_buckets(2);
+        let close: Vec<Node4Info> = (1..K as u8 + 1).map(|n| node(3, n)).collect();
+        table.allocate_many(&close);
+        assert_eq!(table.bucket_count(), 1);
+        table.allocate_many(&[node(3, 100)]);
+        assert_eq!(table.bucket_count(), 2);
+        table.allocate_many(&[node(7, 1)]);
+        assert_eq!(table.bucket_count(), 2);
+        assert_eq!(table.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn find_closest_only_returns_good_nodes_closest_first() {
+        let mut table = Table::new(US);
+        let found = vec![node(0, 1), node(2, 1), node(4, 1)];
+        table.allocate_many(&found);
+        assert!(table.find_closest(&US, K).is_empty());
+        mark_good(&mut table, &found[..2]);
+        assert_eq!(table.find_closest(&US, K), vec![node(2, 1), node(0, 1)]);
+        assert_eq!(table.find_closest(&US, 1), vec![node(2, 1)]);
+    }
+
+    #[test]
+    fn neighborhood_is_our_closest_good_nodes_closest_first() {
+        let mut table = Table::new(US);
+        let found = vec![node(0, 1), node(6, 1), node(2, 1), node(9, 1), node(4, 1)];
+        table.allocate_many(&found);
+        mark_good(&mut table, &found);
+        table.allocate_many(&[node(12, 1)]);
+        // the depth 12 node is closest, but still being pinged
+        assert_eq!(table.neighborhood(3), vec![node(9, 1), node(6, 1), node(4, 1)]);
+        assert_eq!(table.neighborhood(K), vec![node(9, 1), node(6, 1), node(4, 1), node(2, 1), node(0, 1)]);
+    }
+
+    #[test]
+    fn get_reports_a_known_nodes_address_and_state() {
+        let mut table = Table::new(US);
+        let found = node(3, 1);
+        assert!(table.get(&found.id).is_none());
+        table.allocate_many(&[found]);
+        let known = |table: &Table| table.get(&found.id).map(|(peer, state, _)| (peer, state));
+        assert_eq!(known(&table), Some((found.peer, NodeState::Pinging)));
+        mark_good(&mut table, &[found]);
+        assert_eq!(known(&table), Some((found.peer, NodeState::Good)));
+        assert!(table.get(&found.id).unwrap().2 < Duration::from_secs(60));
+        assert!(table.get(&node(3, 2).id).is_none());
+    }
+
+    #[test]
+    fn bucket_summaries_count_every_slot() {
+        let mut table = Table::new(US);
+        let far = nodes(0, 3);
+        table.allocate_many(&far);
+        table.allocate_many(&[node(2, 1)]);
+        mark_good(&mut table, &far[..1]);
+        let summaries = table.bucket_summaries();
+        assert_eq!(summaries.len(), table.bucket_count());
+        assert_eq!((summaries[0].good, summaries[0].questionable, summaries[0].empty), (1, 2, K - 3));
+        assert_eq!((summaries[1].good, summaries[1].questionable, summaries[1].empty), (0, 1, K - 1));
+    }
+
+    #[test]
+    fn random_ids_land_in_the_bucket_asked_for() {
+        let mut table = Table::new(US);
+        table.allocate_many(&[node(0, 1), node(1, 1), node(2, 1), node(3, 1)]);
+        for index in 0..table.bucket_count() {
+            let id = table.random_id_in_bucket(index);
+            assert_eq!(Distance::between(&US, &id).count_zeros(), index);
+        }
+    }
+
+    #[test]
+    fn stale_buckets_come_up_one_at_a_time() {
+        let mut table = Table::new(US);
+        table.allocate_many(&[node(0, 1), node(1, 1), node(2, 1)]);
+        assert_eq!(table.bucket_count(), 3);
+        let max_age = Duration::from_millis(50);
+        assert_eq!(table.stalest_bucket(max_age), None);
+        thread::sleep(Duration::from_millis(60));
+
+        // each refresh tick takes the stalest and marks it fresh, so all three take three ticks
+        let mut refreshed = vec![];
+        while let Some(index) = table.stalest_bucket(max_age) {
+            assert!(!refreshed.contains(&index));
+            table.touch_bucket(index);
+            refreshed.push(index);
+        }
+        refreshed.sort();
+        assert_eq!(refreshed, vec![0, 1, 2]);
+    }
+}
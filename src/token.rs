@@ -0,0 +1,117 @@
+/// Announce tokens, which prove that an announcer really owns its source IP.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// How often the secret changes. Tokens stay valid for one to two periods.
+const ROTATE_SECS: u64 = 5 * 60;
+
+/// Issues tokens in `get_peers` replies and checks them on `announce_peer`.
+///
+/// A token is a keyed hash of the querier's IP. Validation recomputes it over the IP the
+/// announce actually came from, so a token handed to one address is useless from another.
+pub struct TokenManager {
+    secret: RandomState,
+    previous: RandomState,
+    rotated: Instant,
+}
+
+impl TokenManager {
+    pub fn new() -> Self {
+        TokenManager {
+            secret: RandomState::new(),
+            previous: RandomState::new(),
+            rotated: Instant::now(),
+        }
+    }
+
+    /// Mints a token for `querier`, which must be the datagram's source address.
+    ///
+    /// Only the IP is covered; NATs may well change the port between queries.
+    pub fn issue(&mut self, querier: &SocketAddr) -> Vec<u8> {
+        self.rotate_if_due();
+        mint(&self.secret, &querier.ip())
+    }
+
+    /// Checks a token presented by `announcer`, the datagram's source address.
+    pub fn validate(&mut self, token: &[u8], announcer: &SocketAddr) -> bool {
+        self.rotate_if_due();
+        let ip = announcer.ip();
+        token == &mint(&self.secret, &ip)[..] || token == &mint(&self.previous, &ip)[..]
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() >= Duration::from_secs(ROTATE_SECS) {
+            self.rotate();
+        }
+    }
+
+    /// Retires the current secret to `previous`, invalidating tokens from the one before.
+    fn rotate(&mut self) {
+        self.previous = ::std::mem::replace(&mut self.secret, RandomState::new());
+        self.rotated = Instant::now();
+    }
+}
+
+fn mint(secret: &RandomState, ip: &IpAddr) -> Vec<u8> {
+    let mut hasher = secret.build_hasher();
+    ip.hash(&mut hasher);
+    let hash = hasher.finish();
+    (0..8).map(|i| (hash >> (56 - i * 8)) as u8).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::TokenManager;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn token_is_good_from_the_address_it_was_issued_to() {
+        let mut tokens = TokenManager::new();
+        let token = tokens.issue(&addr("198.51.100.1:6881"));
+        assert!(tokens.validate(&token, &addr("198.51.100.1:6881")));
+    }
+
+    #[test]
+    fn token_survives_a_port_change() {
+        let mut tokens = TokenManager::new();
+        let token = tokens.issue(&addr("198.51.100.1:6881"));
+        assert!(tokens.validate(&token, &addr("198.51.100.1:40000")));
+    }
+
+    #[test]
+    fn token_issued_to_one_address_is_refused_from_another() {
+        let mut tokens = TokenManager::new();
+        let token = tokens.issue(&addr("198.51.100.1:6881"));
+        assert!(!tokens.validate(&token, &addr("198.51.100.2:6881")));
+        let other = tokens.issue(&addr("198.51.100.2:6881"));
+        assert!(token != other);
+    }
+
+    #[test]
+    fn token_outlives_one_rotation_but_not_two() {
+        let mut tokens = TokenManager::new();
+        let querier = addr("198.51.100.1:6881");
+        let token = tokens.issue(&querier);
+
+        tokens.rotate();
+        assert!(tokens.validate(&token, &querier));
+        tokens.rotate();
+        assert!(!tokens.validate(&token, &querier));
+    }
+
+    #[test]
+    fn garbage_is_refused() {
+        let mut tokens = TokenManager::new();
+        tokens.issue(&addr("198.51.100.1:6881"));
+        assert!(!tokens.validate(b"", &addr("198.51.100.1:6881")));
+        assert!(!tokens.validate(b"12345678", &addr("198.51.100.1:6881")));
+    }
+}